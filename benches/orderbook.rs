@@ -0,0 +1,152 @@
+// throughput of the two hot paths of every update: parsing an exchange message and
+// merging it into the shared book. run with `cargo bench`
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use rust_challenge::{parse_order_book_update, OrderBook, PriceLevel, DEFAULT_DEPTH};
+
+// book summary streams served per update in the fanout benchmark
+const SUBSCRIBERS: u64 = 100;
+
+// 20 level books as the exchanges send them
+const BITSTAMP_ORDER_BOOK: &str = include_str!("data/bitstamp_order_book.json");
+const BINANCE_DEPTH: &str = include_str!("data/binance_depth.json");
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_order_book_update");
+    group.throughput(Throughput::Elements(1));
+    for (exchange, message) in [("bitstamp", BITSTAMP_ORDER_BOOK), ("binance", BINANCE_DEPTH)] {
+        group.bench_function(exchange, |b| {
+            b.iter(|| parse_order_book_update(black_box(message), exchange).expect("valid fixture"))
+        });
+    }
+    group.finish();
+}
+
+fn merge(c: &mut Criterion) {
+    let bitstamp = parse_order_book_update(BITSTAMP_ORDER_BOOK, "bitstamp").expect("valid fixture");
+    let binance = parse_order_book_update(BINANCE_DEPTH, "binance").expect("valid fixture");
+    let (bitstamp_bids, bitstamp_asks) = bitstamp.into_levels();
+    let (binance_bids, binance_asks) = binance.into_levels();
+
+    let mut group = c.benchmark_group("merge_and_sort");
+    group.throughput(Throughput::Elements(1));
+    // each iteration is one exchange replacing its levels in a book that holds both
+    group.bench_function("replace_exchange_levels", |b| {
+        let mut book = OrderBook::new(DEFAULT_DEPTH).expect("valid depth");
+        book.merge_and_sort(binance_bids.clone(), binance_asks.clone());
+        b.iter(|| book.replace_exchange_levels("bitstamp", black_box(bitstamp_bids.clone()), black_box(bitstamp_asks.clone())))
+    });
+    group.bench_function("into_empty_book", |b| {
+        b.iter(|| {
+            let mut book = OrderBook::new(DEFAULT_DEPTH).expect("valid depth");
+            book.merge_and_sort(black_box(binance_bids.clone()), black_box(binance_asks.clone()));
+            book.merge_and_sort(black_box(bitstamp_bids.clone()), black_box(bitstamp_asks.clone()));
+            book
+        })
+    });
+    group.finish();
+}
+
+// the book as it was before the BTreeMap sides, both sides in one Vec each that is sorted again
+// after every update, kept here to compare the two
+struct SortedBook {
+    bids: Vec<PriceLevel>,
+    asks: Vec<PriceLevel>,
+    max_depth: usize,
+}
+
+impl SortedBook {
+    fn new(max_depth: usize) -> Self {
+        Self { bids: Vec::new(), asks: Vec::new(), max_depth }
+    }
+
+    fn replace_exchange_levels(&mut self, exchange: &str, new_bids: Vec<PriceLevel>, new_asks: Vec<PriceLevel>) {
+        self.bids.retain(|level| level.exchange != exchange);
+        self.asks.retain(|level| level.exchange != exchange);
+        self.merge_and_sort(new_bids, new_asks);
+    }
+
+    // applies the levels, a zero amount removing its price, then sorts both sides again
+    fn merge_and_sort(&mut self, new_bids: Vec<PriceLevel>, new_asks: Vec<PriceLevel>) {
+        fn apply(side: &mut Vec<PriceLevel>, levels: Vec<PriceLevel>) {
+            for level in levels {
+                side.retain(|existing| existing.exchange != level.exchange || existing.price != level.price);
+                if !level.amount.is_zero() {
+                    side.push(level);
+                }
+            }
+        }
+        apply(&mut self.bids, new_bids);
+        apply(&mut self.asks, new_asks);
+        self.bids.sort_unstable_by(|a, b| b.price.cmp(&a.price).then(b.amount.cmp(&a.amount)));
+        self.asks.sort_unstable_by(|a, b| a.price.cmp(&b.price).then(b.amount.cmp(&a.amount)));
+        self.bids.truncate(self.max_depth);
+        self.asks.truncate(self.max_depth);
+    }
+}
+
+fn book_structure(c: &mut Criterion) {
+    let bitstamp = parse_order_book_update(BITSTAMP_ORDER_BOOK, "bitstamp").expect("valid fixture");
+    let binance = parse_order_book_update(BINANCE_DEPTH, "binance").expect("valid fixture");
+    let (bitstamp_bids, bitstamp_asks) = bitstamp.into_levels();
+    let (binance_bids, binance_asks) = binance.into_levels();
+    // a diff moving the amount of one level, the most common update of a busy feed
+    let mut changed = binance_bids[0].clone();
+    changed.amount += changed.amount;
+    let one_level = vec![changed];
+
+    let mut group = c.benchmark_group("book_structure");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("btreemap/replace_exchange_levels", |b| {
+        let mut book = OrderBook::new(DEFAULT_DEPTH).expect("valid depth");
+        book.replace_exchange_levels("binance", binance_bids.clone(), binance_asks.clone());
+        b.iter(|| book.replace_exchange_levels("bitstamp", black_box(bitstamp_bids.clone()), black_box(bitstamp_asks.clone())))
+    });
+    group.bench_function("sorted_vec/replace_exchange_levels", |b| {
+        let mut book = SortedBook::new(DEFAULT_DEPTH);
+        book.replace_exchange_levels("binance", binance_bids.clone(), binance_asks.clone());
+        b.iter(|| book.replace_exchange_levels("bitstamp", black_box(bitstamp_bids.clone()), black_box(bitstamp_asks.clone())))
+    });
+    group.bench_function("btreemap/one_level", |b| {
+        let mut book = OrderBook::new(DEFAULT_DEPTH).expect("valid depth");
+        book.merge_and_sort(binance_bids.clone(), binance_asks.clone());
+        book.merge_and_sort(bitstamp_bids.clone(), bitstamp_asks.clone());
+        b.iter(|| book.merge_and_sort(black_box(one_level.clone()), Vec::new()))
+    });
+    group.bench_function("sorted_vec/one_level", |b| {
+        let mut book = SortedBook::new(DEFAULT_DEPTH);
+        book.merge_and_sort(binance_bids.clone(), binance_asks.clone());
+        book.merge_and_sort(bitstamp_bids.clone(), bitstamp_asks.clone());
+        b.iter(|| book.merge_and_sort(black_box(one_level.clone()), Vec::new()))
+    });
+    group.finish();
+}
+
+fn fanout(c: &mut Criterion) {
+    let bitstamp = parse_order_book_update(BITSTAMP_ORDER_BOOK, "bitstamp").expect("valid fixture");
+    let binance = parse_order_book_update(BINANCE_DEPTH, "binance").expect("valid fixture");
+    let (bitstamp_bids, bitstamp_asks) = bitstamp.into_levels();
+    let (binance_bids, binance_asks) = binance.into_levels();
+    let mut book = OrderBook::new(DEFAULT_DEPTH).expect("valid depth");
+    book.merge_and_sort(binance_bids, binance_asks);
+    book.merge_and_sort(bitstamp_bids, bitstamp_asks);
+
+    let mut group = c.benchmark_group("summary_fanout");
+    group.throughput(Throughput::Elements(SUBSCRIBERS));
+    // each subscriber building its own summary from the book, as before summaries were shared
+    group.bench_function("summary_per_subscriber", |b| {
+        b.iter(|| (0..SUBSCRIBERS).map(|_| black_box(&book).to_summary()).collect::<Vec<_>>())
+    });
+    // one summary per update, handed to every subscriber behind an Arc
+    group.bench_function("shared_summary", |b| {
+        b.iter(|| {
+            let summary = Arc::new(black_box(&book).to_summary());
+            (0..SUBSCRIBERS).map(|_| Arc::clone(&summary)).collect::<Vec<_>>()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse, merge, book_structure, fanout);
+criterion_main!(benches);
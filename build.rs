@@ -1,5 +1,11 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // descriptor set served by gRPC reflection
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR")?);
     tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("orderbook_descriptor.bin"))
+        // summaries are also sent as JSON over WebSocket
+        .type_attribute("orderbook.Summary", "#[derive(serde::Serialize)]")
+        .type_attribute("orderbook.Level", "#[derive(serde::Serialize)]")
         .compile(&["proto/orderbook.proto"], &["proto/"])
         .unwrap();
     Ok(())
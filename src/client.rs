@@ -1,27 +1,200 @@
-use crate::orderbook::orderbook_aggregator_client::OrderbookAggregatorClient;
+use rust_challenge::apply_summary_delta;
+use rust_challenge::orderbook::orderbook_aggregator_client::OrderbookAggregatorClient;
+use tonic::codec::CompressionEncoding;
 use tonic::transport::Channel;
-use orderbook::Empty;
-
-mod orderbook {
-    tonic::include_proto!("orderbook"); 
-}
+use rust_challenge::orderbook::{BookSummaryRequest, HealthRequest, HealthResponse, Level, Opportunity, OpportunityStreamRequest, Summary, Trade, TradeSide, TradeStreamRequest};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let channel = Channel::from_static("http://[::1]:50051")
+    // stop after this many summaries when given, otherwise stream forever
+    let count = match arg_value("--count")? {
+        Some(value) => Some(value.parse::<usize>()?),
+        None => None,
+    };
+    // the book to stream, may be left empty when the server only serves one symbol
+    let symbol = arg_value("--symbol")?
+        .or_else(|| std::env::var("SYMBOL").ok())
+        .unwrap_or_default();
+    // levels per side to ask for, 0 takes everything the server keeps
+    let depth = match arg_value("--depth")? {
+        Some(value) => value.parse::<u32>()?,
+        None => 0,
+    };
+    // whether the server sends the summaries it kept before the live ones
+    let replay_history = std::env::args().any(|arg| arg == "--history");
+
+    // connect to the same address the server listens on
+    let addr = std::env::var("GRPC_ADDR").unwrap_or_else(|_| "[::1]:50051".to_string());
+    let channel = Channel::from_shared(format!("http://{}", addr))?
         .connect()
         .await?;
 
-    // Create a client.
-    let mut client = OrderbookAggregatorClient::new(channel);
+    // Create a client, taking gzip responses from servers started with --grpc-compression gzip
+    let mut client = OrderbookAggregatorClient::new(channel).accept_compressed(CompressionEncoding::Gzip);
+
+    // print the feed status instead of streaming when asked to
+    if std::env::args().any(|arg| arg == "--health") {
+        let health = client.health(HealthRequest {}).await?.into_inner();
+        print_health(&health);
+        return Ok(());
+    }
+
+    // print each exchange's own levels instead of the merged book when asked to
+    if std::env::args().any(|arg| arg == "--per-exchange") {
+        let response = client.per_exchange_books(BookSummaryRequest { symbol, depth, replay_history }).await?.into_inner();
+        for book in &response.books {
+            println!("{}", book.exchange);
+            print_levels(&book.bids, &book.asks);
+        }
+        return Ok(());
+    }
+
+    // stream trades instead of the book when asked to
+    if std::env::args().any(|arg| arg == "--trades") {
+        let mut stream = client.trade_stream(TradeStreamRequest { symbol }).await?.into_inner();
+        let mut received = 0;
+        while let Some(trade) = stream.message().await? {
+            print_trade(&trade);
+            received += 1;
+            if Some(received) == count {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    // stream arbitrage opportunities instead of the book when asked to
+    if std::env::args().any(|arg| arg == "--opportunities") {
+        let mut stream = client.opportunity_stream(OpportunityStreamRequest { symbol }).await?.into_inner();
+        let mut received = 0;
+        while let Some(opportunity) = stream.message().await? {
+            print_opportunity(&opportunity);
+            received += 1;
+            if Some(received) == count {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    // stream deltas and rebuild each summary from them when asked to
+    if std::env::args().any(|arg| arg == "--delta") {
+        let mut stream = client.book_summary_delta(BookSummaryRequest { symbol, depth, replay_history }).await?.into_inner();
+        let mut summary = Summary::default();
+        let mut received = 0;
+        while let Some(delta) = stream.message().await? {
+            println!("{} ({} bid and {} ask changes)", if delta.snapshot { "snapshot" } else { "delta" }, delta.bids.len(), delta.asks.len());
+            apply_summary_delta(&mut summary, &delta);
+            print_summary(&summary);
+            received += 1;
+            if Some(received) == count {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
     // Create a request.
-    let request = tonic::Request::new(Empty {});
+    let request = tonic::Request::new(BookSummaryRequest { symbol, depth, replay_history });
     // Call the `book_summary` method.
     let response = client.book_summary(request).await?;
-    // Print the response.
-    println!("RESPONSE={:?}", response);
+    // Print every summary as it arrives.
+    let mut stream = response.into_inner();
+    let mut received = 0;
+    while let Some(summary) = stream.message().await? {
+        print_summary(&summary);
+        received += 1;
+        if Some(received) == count {
+            break;
+        }
+    }
 
-    
     Ok(())
 
-}
\ No newline at end of file
+}
+
+// returns the value following `name` on the command line, e.g. `--count 5`
+fn arg_value(name: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == name {
+            let value = args.next().ok_or(format!("{} needs a value", name))?;
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
+// prints the spread followed by the top bids and asks
+fn print_summary(summary: &Summary) {
+    if summary.has_spread {
+        println!("spread: {} ({:.2} bps){}", summary.spread, summary.spread_bps, if summary.crossed { " (crossed)" } else { "" });
+    } else {
+        println!("spread: none, a side of the book is empty");
+    }
+    println!("mid: {} micro: {} spread ema: {} imbalance: {:.3}", summary.mid_price, summary.micro_price, summary.spread_ema, summary.imbalance);
+    println!("generated at: {}", summary.generated_at_ms);
+    if !summary.stale_exchanges.is_empty() {
+        println!("stale: {}", summary.stale_exchanges.join(", "));
+    }
+    if !summary.reconnects.is_empty() {
+        let mut reconnects: Vec<_> = summary.reconnects.iter().map(|(exchange, count)| format!("{} {}", exchange, count)).collect();
+        reconnects.sort();
+        println!("reconnects: {}", reconnects.join(", "));
+    }
+    println!("vwap: bids {} asks {}", summary.bid_vwap, summary.ask_vwap);
+    println!("liquidity: bids {} asks {}", summary.bid_total_amount, summary.ask_total_amount);
+    print_levels(&summary.bids, &summary.asks);
+}
+
+// prints the bids then the asks, one line per level
+fn print_levels(bids: &[Level], asks: &[Level]) {
+    for (side, levels) in [("bid", bids), ("ask", asks)] {
+        for level in levels {
+            println!("  {} {:<10} {:>18} @ {:<18} {}", side, level.exchange, level.amount, level.price, level.timestamp_ms);
+        }
+    }
+}
+
+// prints one line per exchange feed
+fn print_health(health: &HealthResponse) {
+    for feed in &health.feeds {
+        println!(
+            "{:<10} {:<10} {:<12} messages {:>8} levels {:>3} reconnects {:>3} last update {}",
+            feed.exchange,
+            feed.symbol,
+            if feed.failed { "failed" } else if feed.connected { "connected" } else { "disconnected" },
+            feed.message_count,
+            feed.merged_levels,
+            feed.reconnects,
+            feed.last_update_ms,
+        );
+    }
+}
+
+// prints one line per trade
+fn print_trade(trade: &Trade) {
+    let side = match trade.side() {
+        TradeSide::Buy => "buy",
+        TradeSide::Sell => "sell",
+    };
+    println!("{} {:<10} {:<4} {:>18} @ {}", trade.timestamp_ms, trade.exchange, side, trade.amount, trade.price);
+}
+
+// prints one line per opportunity
+fn print_opportunity(opportunity: &Opportunity) {
+    println!(
+        "{} buy {:<10} @ {} sell {:<10} @ {} profit {} (gross {}) x {}, {} down the book for {} ({} per unit)",
+        opportunity.timestamp_ms,
+        opportunity.buy_exchange,
+        opportunity.buy_price,
+        opportunity.sell_exchange,
+        opportunity.sell_price,
+        opportunity.profit_per_unit,
+        opportunity.gross_profit_per_unit,
+        opportunity.amount,
+        opportunity.executable_amount,
+        opportunity.executable_profit,
+        opportunity.executable_profit_per_unit,
+    );
+}
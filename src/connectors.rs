@@ -0,0 +1,1338 @@
+// per-exchange subscription and message handling behind the ExchangeConnector trait
+
+use std::env;
+use std::collections::BTreeMap;
+use std::cmp::Reverse;
+use std::time::Duration;
+use tracing::{debug, warn};
+use rust_decimal::Decimal;
+use serde_json::json;
+use async_trait::async_trait;
+
+// order book core
+use crate::{orderbook, parse_decimal, parse_levels, parse_order_book_update, AmountMode, ArbError, OrderBook, PriceLevel};
+
+// gRPC crates
+use orderbook::{Trade, TradeSide};
+
+// used to parse orderbook update
+use serde_json::Value;
+
+// log targets of the exchange specific events, so one exchange can be traced on its own with e.g.
+// RUST_LOG=arb::binance=debug
+pub(crate) const BINANCE_LOG: &str = "arb::binance";
+pub(crate) const BITSTAMP_LOG: &str = "arb::bitstamp";
+pub(crate) const COINBASE_LOG: &str = "arb::coinbase";
+pub(crate) const KRAKEN_LOG: &str = "arb::kraken";
+
+// logs an event of exchange's connection under the exchange's target, e.g. exchange_log!(info, "binance", ..)
+// under arb::binance. tracing fixes targets at compile time, hence one call per exchange
+macro_rules! exchange_log {
+    ($level:ident, $exchange:expr, $($arg:tt)+) => {{
+        let exchange: &str = &$exchange;
+        match exchange {
+            "binance" => tracing::$level!(target: $crate::connectors::BINANCE_LOG, $($arg)+),
+            "bitstamp" => tracing::$level!(target: $crate::connectors::BITSTAMP_LOG, $($arg)+),
+            "coinbase" => tracing::$level!(target: $crate::connectors::COINBASE_LOG, $($arg)+),
+            "kraken" => tracing::$level!(target: $crate::connectors::KRAKEN_LOG, $($arg)+),
+            _ => tracing::$level!($($arg)+),
+        }
+    }};
+}
+pub(crate) use exchange_log;
+
+// exchange event time of a message in milliseconds since the epoch, if the exchange sends one
+pub fn extract_event_time(v: &Value, exchange: &str) -> Option<i64> {
+    match exchange {
+        // Binance sends the event time in milliseconds as `E`, inside `data` on combined streams
+        "binance" => v.get("data").unwrap_or(v).get("E")?.as_i64(),
+        // Bitstamp sends microseconds as a string in `data.microtimestamp`
+        "bitstamp" => v.get("data")?.get("microtimestamp")?.as_str()?.parse::<i64>().ok().map(|micros| micros / 1000),
+        _ => None,
+    }
+}
+
+// a trade carried by an exchange message, None for anything else. the symbol is left for the caller to fill in
+pub fn parse_trade(v: &Value, exchange: &str) -> Option<Trade> {
+    match exchange {
+        "binance" => {
+            let event = v.get("data").unwrap_or(v);
+            if event.get("e")?.as_str()? != "aggTrade" {
+                return None;
+            }
+            // `m` is set when the buyer was the maker, so the taker sold
+            let side = if event.get("m")?.as_bool()? { TradeSide::Sell } else { TradeSide::Buy };
+            Some(Trade {
+                exchange: exchange.to_string(),
+                symbol: String::new(),
+                price: event.get("p")?.as_str()?.parse().ok()?,
+                amount: event.get("q")?.as_str()?.parse().ok()?,
+                side: side as i32,
+                timestamp_ms: event.get("T")?.as_i64()?,
+            })
+        }
+        _ => None,
+    }
+}
+
+// how long a connection may stay silent before it is considered dead
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// the exchange endpoint unless overridden, e.g. to point a feed at a local mock server
+fn endpoint_from_env(var: &str, default: &str) -> String {
+    env::var(var).unwrap_or_else(|_| default.to_string())
+}
+
+// settings shared by the connectors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectorOptions {
+    // levels per side each connector reports
+    pub depth: usize,
+    // stream Bitstamp's diff channel on top of a REST snapshot instead of its full snapshots
+    pub bitstamp_diff: bool,
+}
+
+// a fresh connector for every supported exchange
+pub fn exchange_connectors(options: ConnectorOptions) -> Vec<Box<dyn ExchangeConnector>> {
+    let depth = options.depth;
+    vec![
+        Box::new(BinanceConnector::new(depth)),
+        Box::new(BitstampConnector::new(depth, options.bitstamp_diff)),
+        Box::new(CoinbaseConnector::new(depth)),
+        Box::new(KrakenConnector::new(depth)),
+    ]
+}
+
+// exchange specific parts of a websocket order book feed, the connect/read loop is shared
+#[async_trait]
+pub trait ExchangeConnector: Send {
+    fn name(&self) -> &str;
+    fn ws_url(&self, symbol: &str) -> String;
+    // base of the REST API serving order book snapshots, None for exchanges without one
+    fn rest_url(&self) -> Option<String> {
+        None
+    }
+    fn subscribe_message(&self, symbol: &str) -> String;
+    // returns None for messages that don't carry order book data (acks, trades, ...)
+    fn parse_update(&mut self, text: &str) -> Result<Option<OrderBook>, ArbError>;
+    // called before every connection attempt, so local state built on a dropped connection
+    // isn't carried over to the next one
+    fn reset(&mut self) {}
+    // called after every message so connectors with sequenced local state can fetch
+    // a fresh snapshot out of band, returns the resulting book if one was applied
+    async fn resync(&mut self, _symbol: &str) -> Result<Option<OrderBook>, ArbError> {
+        Ok(None)
+    }
+    // called before a recording is played back. a recording holds no REST snapshots and replays
+    // must not reach the exchange, so connectors anchoring on one start from the first recorded diff
+    fn start_replay(&mut self) {}
+    // silence after which the connection is dropped and reopened
+    fn idle_timeout(&self) -> Duration {
+        DEFAULT_IDLE_TIMEOUT
+    }
+    // how long to wait for the exchange to confirm the subscription, None for exchanges that don't
+    fn subscription_timeout(&self) -> Option<Duration> {
+        None
+    }
+    // whether a message received while waiting answers our subscription to symbol
+    fn subscription_reply(&self, _symbol: &str, _v: &Value) -> SubscriptionReply {
+        SubscriptionReply::Unrelated
+    }
+    // whether the local book matches a checksum sent by the exchange, computed with the
+    // exchange's own digest. exchanges that don't send checksums accept anything
+    fn verify_checksum(&self, _expected: u32) -> bool {
+        true
+    }
+    // how fast new connections may be opened, shared by every feed of the exchange
+    fn connect_rate_limit(&self) -> RateLimit {
+        DEFAULT_CONNECT_RATE_LIMIT
+    }
+    // how fast messages may be sent on one connection, subscriptions and pongs alike
+    fn message_rate_limit(&self) -> RateLimit {
+        DEFAULT_MESSAGE_RATE_LIMIT
+    }
+    // what the amounts of parsed levels measure, converted to the base asset before merging
+    fn amount_mode(&self) -> AmountMode {
+        AmountMode::Base
+    }
+}
+
+// a token bucket: burst tokens up front, then one more every interval. a zero interval never limits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub interval: Duration,
+}
+
+pub const DEFAULT_CONNECT_RATE_LIMIT: RateLimit = RateLimit { burst: 5, interval: Duration::from_secs(1) };
+pub const DEFAULT_MESSAGE_RATE_LIMIT: RateLimit = RateLimit { burst: 10, interval: Duration::from_millis(100) };
+
+// the exchange's answer to a subscribe message
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubscriptionReply {
+    Confirmed,
+    Rejected(String),
+    Unrelated,
+}
+
+// how long exchanges that confirm subscriptions get to do so
+pub const SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+// price -> amount maps for exchanges that stream deltas on top of a snapshot
+#[derive(Debug, Default)]
+pub struct LocalBook {
+    bids: BTreeMap<Reverse<Decimal>, Decimal>,
+    asks: BTreeMap<Decimal, Decimal>,
+}
+
+impl LocalBook {
+    pub fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+    }
+
+    // a zero amount removes the price level
+    pub fn apply(&mut self, side: Side, price: Decimal, amount: Decimal) {
+        match (side, amount.is_zero()) {
+            (Side::Bid, true) => { self.bids.remove(&Reverse(price)); }
+            (Side::Bid, false) => { self.bids.insert(Reverse(price), amount); }
+            (Side::Ask, true) => { self.asks.remove(&price); }
+            (Side::Ask, false) => { self.asks.insert(price, amount); }
+        }
+    }
+
+    pub fn apply_levels<'a>(&mut self, side: Side, levels: impl IntoIterator<Item = &'a PriceLevel>) {
+        for level in levels {
+            self.apply(side, level.price, level.amount);
+        }
+    }
+
+    // best first
+    pub fn bids(&self) -> impl Iterator<Item = (Decimal, Decimal)> + '_ {
+        self.bids.iter().map(|(price, amount)| (price.0, *amount))
+    }
+
+    // best first
+    pub fn asks(&self) -> impl Iterator<Item = (Decimal, Decimal)> + '_ {
+        self.asks.iter().map(|(price, amount)| (*price, *amount))
+    }
+
+    // drops every level past depth on both sides
+    pub fn truncate(&mut self, depth: usize) {
+        if let Some(price) = self.bids.keys().nth(depth).copied() {
+            self.bids.split_off(&price);
+        }
+        if let Some(price) = self.asks.keys().nth(depth).copied() {
+            self.asks.split_off(&price);
+        }
+    }
+
+    pub fn top(&self, exchange: &str, depth: usize) -> OrderBook {
+        let level = |price: Decimal, amount: Decimal| PriceLevel {
+            exchange: exchange.to_string(),
+            price,
+            amount,
+        };
+        let bids = self.bids.iter().take(depth).map(|(price, amount)| level(price.0, *amount)).collect();
+        let asks = self.asks.iter().take(depth).map(|(price, amount)| level(*price, *amount)).collect();
+        OrderBook::from_levels(bids, asks)
+    }
+}
+
+// a Binance @depth diff event covering update ids U (first) to u (final)
+#[derive(Debug, Clone)]
+pub struct BinanceDepthDiff {
+    pub first_update_id: u64,
+    pub final_update_id: u64,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiffOutcome {
+    Applied,
+    // waiting for the REST snapshot
+    Buffered,
+    // already covered by the snapshot
+    Stale,
+    // update ids are not contiguous, the book must be re-synced
+    Gap { expected: u64, got: u64 },
+}
+
+// Binance diff stream state: diffs are buffered until a REST snapshot anchors the book,
+// then only applied while update ids stay contiguous
+#[derive(Debug, Default)]
+pub struct BinanceBookState {
+    last_update_id: Option<u64>,
+    buffer: Vec<BinanceDepthDiff>,
+    book: LocalBook,
+}
+
+impl BinanceBookState {
+    pub fn needs_snapshot(&self) -> bool {
+        self.last_update_id.is_none()
+    }
+
+    pub fn apply_snapshot(&mut self, last_update_id: u64, snapshot: OrderBook) -> Result<(), ArbError> {
+        self.book.clear();
+        self.book.apply_levels(Side::Bid, snapshot.bids().iter());
+        self.book.apply_levels(Side::Ask, snapshot.asks().iter());
+        self.last_update_id = Some(last_update_id);
+
+        // replay what arrived while the snapshot was being fetched
+        for diff in std::mem::take(&mut self.buffer) {
+            if let DiffOutcome::Gap { expected, got } = self.apply_diff(diff) {
+                return Err(ArbError::SequenceGap { exchange: "binance".to_string(), expected, got });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply_diff(&mut self, diff: BinanceDepthDiff) -> DiffOutcome {
+        let last_update_id = match self.last_update_id {
+            Some(id) => id,
+            None => {
+                self.buffer.push(diff);
+                return DiffOutcome::Buffered;
+            }
+        };
+
+        if diff.final_update_id <= last_update_id {
+            return DiffOutcome::Stale;
+        }
+        if diff.first_update_id > last_update_id + 1 {
+            // drop the book and keep the diff for after the next snapshot
+            let outcome = DiffOutcome::Gap { expected: last_update_id + 1, got: diff.first_update_id };
+            self.last_update_id = None;
+            self.buffer.clear();
+            self.buffer.push(diff);
+            return outcome;
+        }
+
+        self.book.apply_levels(Side::Bid, &diff.bids);
+        self.book.apply_levels(Side::Ask, &diff.asks);
+        self.last_update_id = Some(diff.final_update_id);
+        DiffOutcome::Applied
+    }
+
+    // anchors the book on the buffered diffs instead of a snapshot, keeping the levels it has. the
+    // book then only holds the levels the diffs touched
+    pub fn anchor_on_diffs(&mut self) {
+        let Some(first) = self.buffer.first() else {
+            return;
+        };
+        self.last_update_id = Some(first.first_update_id.saturating_sub(1));
+        for diff in std::mem::take(&mut self.buffer) {
+            self.apply_diff(diff);
+        }
+    }
+
+    pub fn top(&self, depth: usize) -> OrderBook {
+        self.book.top("binance", depth)
+    }
+}
+
+// id of our SUBSCRIBE request, echoed back in Binance's reply
+const BINANCE_SUBSCRIBE_ID: u64 = 1;
+
+pub struct BinanceConnector {
+    depth: usize,
+    state: BinanceBookState,
+    // playing a recording, which anchors on its diffs rather than fetching snapshots
+    replay: bool,
+}
+
+impl BinanceConnector {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            state: BinanceBookState::default(),
+            replay: false,
+        }
+    }
+}
+
+#[async_trait]
+impl ExchangeConnector for BinanceConnector {
+    fn name(&self) -> &str {
+        "binance"
+    }
+
+    fn ws_url(&self, _symbol: &str) -> String {
+        endpoint_from_env("BINANCE_WS_URL", "wss://stream.binance.com:9443/ws")
+    }
+
+    fn rest_url(&self) -> Option<String> {
+        Some(binance_rest_url())
+    }
+
+    fn subscribe_message(&self, symbol: &str) -> String {
+        format!(
+            r#"{{
+                "method": "SUBSCRIBE",
+                "params": [
+                    "{}@aggTrade",
+                    "{}@depth"
+                ],
+                "id": {}
+            }}"#,
+            symbol, symbol, BINANCE_SUBSCRIBE_ID
+        )
+    }
+
+    fn subscription_timeout(&self) -> Option<Duration> {
+        Some(SUBSCRIPTION_TIMEOUT)
+    }
+
+    // 300 new connections per 5 minutes from one IP
+    fn connect_rate_limit(&self) -> RateLimit {
+        RateLimit { burst: 10, interval: Duration::from_secs(1) }
+    }
+
+    // 5 messages per second on a connection, pongs included, before it is dropped
+    fn message_rate_limit(&self) -> RateLimit {
+        RateLimit { burst: 5, interval: Duration::from_millis(200) }
+    }
+
+    // {"result":null,"id":1} on success, {"error":{"code":..,"msg":".."},"id":1} otherwise
+    fn subscription_reply(&self, _symbol: &str, v: &Value) -> SubscriptionReply {
+        if v["id"].as_u64() != Some(BINANCE_SUBSCRIBE_ID) {
+            return SubscriptionReply::Unrelated;
+        }
+        match v.get("error") {
+            Some(error) => SubscriptionReply::Rejected(error["msg"].as_str().map_or_else(|| error.to_string(), str::to_string)),
+            None => SubscriptionReply::Confirmed,
+        }
+    }
+
+    fn parse_update(&mut self, text: &str) -> Result<Option<OrderBook>, ArbError> {
+        let diff = match parse_binance_message(text)? {
+            BinanceMessage::Depth(diff) => diff,
+            // trades share the connection but don't touch the book
+            BinanceMessage::Trade => return Ok(None),
+            BinanceMessage::Other => return Ok(None),
+        };
+        match self.state.apply_diff(diff) {
+            DiffOutcome::Applied => Ok(Some(self.state.top(self.depth))),
+            DiffOutcome::Gap { expected, got } if self.replay => {
+                warn!(target: BINANCE_LOG, "{} in the recording, carrying on from the next diff", ArbError::SequenceGap { exchange: self.name().to_string(), expected, got });
+                self.state.anchor_on_diffs();
+                Ok(Some(self.state.top(self.depth)))
+            }
+            DiffOutcome::Gap { expected, got } => {
+                let gap = ArbError::SequenceGap { exchange: self.name().to_string(), expected, got };
+                warn!(target: BINANCE_LOG, "{}, re-syncing from snapshot", gap);
+                Ok(None)
+            }
+            DiffOutcome::Buffered if self.replay => {
+                self.state.anchor_on_diffs();
+                Ok(Some(self.state.top(self.depth)))
+            }
+            DiffOutcome::Buffered | DiffOutcome::Stale => Ok(None),
+        }
+    }
+
+    fn start_replay(&mut self) {
+        self.replay = true;
+    }
+
+    async fn resync(&mut self, symbol: &str) -> Result<Option<OrderBook>, ArbError> {
+        if self.replay || !self.state.needs_snapshot() {
+            return Ok(None);
+        }
+        let (last_update_id, snapshot) = fetch_binance_snapshot(symbol).await?;
+        debug!(target: BINANCE_LOG, symbol, last_update_id, "fetched depth snapshot");
+        // a snapshot older than the buffered diffs fails the connection so it starts over
+        self.state.apply_snapshot(last_update_id, snapshot)?;
+        Ok(Some(self.state.top(self.depth)))
+    }
+}
+
+// the current book of symbol, as the exchange spells it, from the exchange's REST API, cut to depth levels
+// per side. only Binance and Bitstamp publish one here
+pub async fn fetch_rest_snapshot(exchange: &str, symbol: &str, depth: usize) -> anyhow::Result<OrderBook> {
+    let mut book = match exchange {
+        "binance" => fetch_binance_snapshot(symbol).await?.1,
+        "bitstamp" => {
+            let snapshot = fetch_bitstamp_snapshot(symbol).await?;
+            OrderBook::from_levels(snapshot.bids, snapshot.asks)
+        }
+        _ => return Err(anyhow::anyhow!("{} has no REST snapshot", exchange)),
+    };
+    book.truncate(depth);
+    Ok(book)
+}
+
+fn binance_rest_url() -> String {
+    endpoint_from_env("BINANCE_REST_URL", "https://api.binance.com")
+}
+
+// fetches the REST depth snapshot anchoring the Binance diff stream
+async fn fetch_binance_snapshot(symbol: &str) -> Result<(u64, OrderBook), ArbError> {
+    let url = format!("{}/api/v3/depth?symbol={}&limit=1000", binance_rest_url(), symbol.to_uppercase());
+    let snapshot_error = |e: reqwest::Error| ArbError::Connect { exchange: "binance".to_string(), reason: format!("depth snapshot request failed: {}", e) };
+    let body = reqwest::get(&url).await
+        .and_then(|response| response.error_for_status())
+        .map_err(snapshot_error)?
+        .text().await
+        .map_err(snapshot_error)?;
+    parse_binance_snapshot(&body)
+}
+
+// parses a REST depth snapshot, returning its lastUpdateId with the levels
+fn parse_binance_snapshot(message: &str) -> Result<(u64, OrderBook), ArbError> {
+    let v: Value = serde_json::from_str(message)?;
+    let last_update_id = v["lastUpdateId"]
+        .as_u64()
+        .ok_or(ArbError::Parse("lastUpdateId is not a number".to_string()))?;
+    Ok((last_update_id, parse_order_book_update(message, "binance")?))
+}
+
+// one message on the Binance connection, which carries every subscribed stream
+#[derive(Debug)]
+pub enum BinanceMessage {
+    Depth(BinanceDepthDiff),
+    Trade,
+    // subscription acks and anything else
+    Other,
+}
+
+// routes a Binance message on its event type, unwrapping the combined stream format
+// ({"stream": "ethbtc@depth", "data": {...}}) when present
+fn parse_binance_message(message: &str) -> Result<BinanceMessage, ArbError> {
+    let v: Value = serde_json::from_str(message)?;
+    let event = match (v.get("stream"), v.get("data")) {
+        (Some(_), Some(data)) => data,
+        _ => &v,
+    };
+    match event.get("e").and_then(|e| e.as_str()) {
+        Some("depthUpdate") => Ok(BinanceMessage::Depth(parse_binance_depth_diff(event)?)),
+        Some("aggTrade") => Ok(BinanceMessage::Trade),
+        _ => Ok(BinanceMessage::Other),
+    }
+}
+
+// parses a Binance @depth diff event
+fn parse_binance_depth_diff(v: &Value) -> Result<BinanceDepthDiff, ArbError> {
+    Ok(BinanceDepthDiff {
+        first_update_id: v["U"].as_u64().ok_or(ArbError::Parse("U is not a number".to_string()))?,
+        final_update_id: v["u"].as_u64().ok_or(ArbError::Parse("u is not a number".to_string()))?,
+        bids: parse_levels(&v["b"], "binance", "bid")?,
+        asks: parse_levels(&v["a"], "binance", "ask")?,
+    })
+}
+
+// Bitstamp's diff channel carries changes only, so they are applied on top of a REST snapshot.
+// there are no sequence numbers, diffs are matched to the snapshot by their microtimestamp
+#[derive(Debug, Default)]
+pub struct BitstampDiffState {
+    // microtimestamp of the snapshot the book was built from, None until it is fetched
+    snapshot_at: Option<i64>,
+    // diffs received while the snapshot was being fetched
+    buffer: Vec<BitstampDiff>,
+    book: LocalBook,
+}
+
+// one message of the diff channel, a zero amount removes the price
+#[derive(Debug, Clone)]
+pub struct BitstampDiff {
+    pub microtimestamp: i64,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+impl BitstampDiffState {
+    pub fn needs_snapshot(&self) -> bool {
+        self.snapshot_at.is_none()
+    }
+
+    pub fn apply_snapshot(&mut self, snapshot: BitstampDiff) {
+        self.book.clear();
+        self.book.apply_levels(Side::Bid, &snapshot.bids);
+        self.book.apply_levels(Side::Ask, &snapshot.asks);
+        self.snapshot_at = Some(snapshot.microtimestamp);
+        for diff in std::mem::take(&mut self.buffer) {
+            self.apply_diff(diff);
+        }
+    }
+
+    // returns whether the diff changed the book, diffs older than the snapshot are already in it
+    pub fn apply_diff(&mut self, diff: BitstampDiff) -> bool {
+        match self.snapshot_at {
+            None => {
+                self.buffer.push(diff);
+                false
+            }
+            Some(snapshot_at) if diff.microtimestamp <= snapshot_at => false,
+            Some(_) => {
+                self.book.apply_levels(Side::Bid, &diff.bids);
+                self.book.apply_levels(Side::Ask, &diff.asks);
+                true
+            }
+        }
+    }
+
+    // anchors the book on the buffered diffs instead of a snapshot, the book then only holds the
+    // levels the diffs touched
+    pub fn anchor_on_diffs(&mut self) {
+        let Some(first) = self.buffer.first() else {
+            return;
+        };
+        self.snapshot_at = Some(first.microtimestamp - 1);
+        for diff in std::mem::take(&mut self.buffer) {
+            self.apply_diff(diff);
+        }
+    }
+}
+
+pub struct BitstampConnector {
+    depth: usize,
+    // Some when streaming the diff channel rather than full snapshots
+    diff: Option<BitstampDiffState>,
+    // playing a recording, which anchors on its diffs rather than fetching snapshots
+    replay: bool,
+}
+
+impl BitstampConnector {
+    pub fn new(depth: usize, diff: bool) -> Self {
+        Self {
+            depth,
+            diff: diff.then(BitstampDiffState::default),
+            replay: false,
+        }
+    }
+
+    fn channel(&self, symbol: &str) -> String {
+        match self.diff {
+            Some(_) => format!("diff_order_book_{}", symbol),
+            None => format!("order_book_{}", symbol),
+        }
+    }
+}
+
+// parses the data of a diff message or of a REST order book, both carry microtimestamp, bids and asks
+fn parse_bitstamp_diff(data: &Value) -> Result<BitstampDiff, ArbError> {
+    let microtimestamp = data["microtimestamp"]
+        .as_str()
+        .and_then(|micros| micros.parse::<i64>().ok())
+        .ok_or(ArbError::Parse("microtimestamp is not a number".to_string()))?;
+    Ok(BitstampDiff {
+        microtimestamp,
+        bids: parse_levels(&data["bids"], "bitstamp", "bid")?,
+        asks: parse_levels(&data["asks"], "bitstamp", "ask")?,
+    })
+}
+
+fn bitstamp_rest_url() -> String {
+    endpoint_from_env("BITSTAMP_REST_URL", "https://www.bitstamp.net")
+}
+
+// fetches the REST order book the Bitstamp diff channel applies to
+async fn fetch_bitstamp_snapshot(symbol: &str) -> Result<BitstampDiff, ArbError> {
+    let url = format!("{}/api/v2/order_book/{}/", bitstamp_rest_url(), symbol);
+    let snapshot_error = |e: reqwest::Error| ArbError::Connect { exchange: "bitstamp".to_string(), reason: format!("order book request failed: {}", e) };
+    let body = reqwest::get(&url).await
+        .and_then(|response| response.error_for_status())
+        .map_err(snapshot_error)?
+        .text().await
+        .map_err(snapshot_error)?;
+    parse_bitstamp_diff(&serde_json::from_str(&body)?)
+}
+
+#[async_trait]
+impl ExchangeConnector for BitstampConnector {
+    fn name(&self) -> &str {
+        "bitstamp"
+    }
+
+    fn ws_url(&self, _symbol: &str) -> String {
+        endpoint_from_env("BITSTAMP_WS_URL", "wss://ws.bitstamp.net")
+    }
+
+    fn rest_url(&self) -> Option<String> {
+        Some(bitstamp_rest_url())
+    }
+
+    fn subscribe_message(&self, symbol: &str) -> String {
+        json!({
+            "event": "bts:subscribe",
+            "data": {
+                "channel": self.channel(symbol)
+            }
+        }).to_string()
+    }
+
+    fn reset(&mut self) {
+        if let Some(diff) = &mut self.diff {
+            *diff = BitstampDiffState::default();
+        }
+    }
+
+    fn parse_update(&mut self, text: &str) -> Result<Option<OrderBook>, ArbError> {
+        // Check the event type to ensure it is an order book update
+        let v: Value = serde_json::from_str(text)?;
+        match v.get("event").and_then(|e| e.as_str()) {
+            Some("data") => match &mut self.diff {
+                Some(state) => {
+                    let mut changed = state.apply_diff(parse_bitstamp_diff(&v["data"])?);
+                    if self.replay && state.needs_snapshot() {
+                        state.anchor_on_diffs();
+                        changed = true;
+                    }
+                    Ok(changed.then(|| state.book.top("bitstamp", self.depth)))
+                }
+                None => parse_order_book_update(text, self.name()).map(Some),
+            },
+            // sent before maintenance or to rebalance load, the connection is about to go away
+            Some("bts:request_reconnect") => {
+                debug!(target: BITSTAMP_LOG, "reconnect requested");
+                Err(ArbError::ReconnectRequested { exchange: self.name().to_string() })
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn subscription_timeout(&self) -> Option<Duration> {
+        Some(SUBSCRIPTION_TIMEOUT)
+    }
+
+    fn subscription_reply(&self, symbol: &str, v: &Value) -> SubscriptionReply {
+        match v["event"].as_str() {
+            Some("bts:subscription_succeeded") if v["channel"].as_str() == Some(&self.channel(symbol)) => SubscriptionReply::Confirmed,
+            Some("bts:error") => SubscriptionReply::Rejected(v["data"]["message"].as_str().unwrap_or("unknown error").to_string()),
+            _ => SubscriptionReply::Unrelated,
+        }
+    }
+
+    fn start_replay(&mut self) {
+        self.replay = true;
+    }
+
+    async fn resync(&mut self, symbol: &str) -> Result<Option<OrderBook>, ArbError> {
+        let Some(state) = &mut self.diff else {
+            return Ok(None);
+        };
+        if self.replay || !state.needs_snapshot() {
+            return Ok(None);
+        }
+        let snapshot = fetch_bitstamp_snapshot(symbol).await?;
+        debug!(target: BITSTAMP_LOG, symbol, microtimestamp = snapshot.microtimestamp, "fetched order book snapshot");
+        state.apply_snapshot(snapshot);
+        Ok(Some(state.book.top("bitstamp", self.depth)))
+    }
+}
+
+// Coinbase sends one snapshot followed by deltas, so the book is maintained locally
+pub struct CoinbaseConnector {
+    depth: usize,
+    book: LocalBook,
+}
+
+impl CoinbaseConnector {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            book: LocalBook::default(),
+        }
+    }
+
+    fn apply_snapshot(&mut self, v: &Value) -> Result<(), ArbError> {
+        self.book.clear();
+        for (side, key) in [("buy", "bids"), ("sell", "asks")] {
+            let levels = v[key]
+                .as_array()
+                .ok_or(ArbError::Parse(format!("{} is not an array", key)))?;
+            for level in levels {
+                self.apply_change(side, &level[0], &level[1])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_change(&mut self, side: &str, price: &Value, amount: &Value) -> Result<(), ArbError> {
+        let side = match side {
+            "buy" => Side::Bid,
+            "sell" => Side::Ask,
+            _ => return Err(ArbError::Parse(format!("unknown side {}", side))),
+        };
+        self.book.apply(side, parse_decimal(price, "price")?, parse_decimal(amount, "amount")?);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ExchangeConnector for CoinbaseConnector {
+    fn name(&self) -> &str {
+        "coinbase"
+    }
+
+    fn ws_url(&self, _symbol: &str) -> String {
+        endpoint_from_env("COINBASE_WS_URL", "wss://ws-feed.exchange.coinbase.com")
+    }
+
+    fn subscribe_message(&self, symbol: &str) -> String {
+        json!({
+            "type": "subscribe",
+            "product_ids": [symbol],
+            "channels": ["level2"]
+        }).to_string()
+    }
+
+    fn parse_update(&mut self, text: &str) -> Result<Option<OrderBook>, ArbError> {
+        let v: Value = serde_json::from_str(text)?;
+        match v.get("type").and_then(|t| t.as_str()) {
+            Some("snapshot") => {
+                self.apply_snapshot(&v)?;
+                debug!(target: COINBASE_LOG, product = v["product_id"].as_str().unwrap_or_default(), "applied level2 snapshot");
+            }
+            Some("l2update") => {
+                let changes = v["changes"]
+                    .as_array()
+                    .ok_or(ArbError::Parse("changes is not an array".to_string()))?;
+                for change in changes {
+                    let side = change[0]
+                        .as_str()
+                        .ok_or(ArbError::Parse("change side is not a string".to_string()))?;
+                    self.apply_change(side, &change[1], &change[2])?;
+                }
+            }
+            _ => return Ok(None),
+        }
+        Ok(Some(self.book.top(self.name(), self.depth)))
+    }
+}
+
+// book depths Kraken accepts in a subscription
+const KRAKEN_DEPTHS: [usize; 5] = [10, 25, 100, 500, 1000];
+
+// levels per side covered by Kraken's checksum
+const KRAKEN_CHECKSUM_DEPTH: usize = 10;
+
+// Kraken's CRC32 over the top 10 asks then the top 10 bids, each price and volume written as
+// sent with the decimal point and leading zeros removed
+pub fn kraken_checksum(book: &LocalBook) -> u32 {
+    let digits = |value: Decimal| value.to_string().replace('.', "").trim_start_matches('0').to_string();
+    let mut hasher = crc32fast::Hasher::new();
+    for (price, amount) in book.asks().take(KRAKEN_CHECKSUM_DEPTH).chain(book.bids().take(KRAKEN_CHECKSUM_DEPTH)) {
+        hasher.update(digits(price).as_bytes());
+        hasher.update(digits(amount).as_bytes());
+    }
+    hasher.finalize()
+}
+
+// Kraken sends a snapshot of the subscribed depth followed by deltas, and expects the
+// client to drop the levels that fall out of that depth
+pub struct KrakenConnector {
+    depth: usize,
+    subscribed_depth: usize,
+    book: LocalBook,
+}
+
+impl KrakenConnector {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            subscribed_depth: KRAKEN_DEPTHS.into_iter().find(|&subscribed| subscribed >= depth).unwrap_or(1000),
+            book: LocalBook::default(),
+        }
+    }
+
+    // [price, volume, timestamp] entries, updates may carry a trailing "r" for republished levels
+    fn apply_levels(&mut self, side: Side, levels: &Value) -> Result<(), ArbError> {
+        let side_name = if side == Side::Bid { "bid" } else { "ask" };
+        self.book.apply_levels(side, &parse_levels(levels, "kraken", side_name)?);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ExchangeConnector for KrakenConnector {
+    fn name(&self) -> &str {
+        "kraken"
+    }
+
+    fn ws_url(&self, _symbol: &str) -> String {
+        endpoint_from_env("KRAKEN_WS_URL", "wss://ws.kraken.com")
+    }
+
+    fn subscribe_message(&self, symbol: &str) -> String {
+        json!({
+            "event": "subscribe",
+            "pair": [symbol],
+            "subscription": {
+                "name": "book",
+                "depth": self.subscribed_depth
+            }
+        }).to_string()
+    }
+
+    fn subscription_timeout(&self) -> Option<Duration> {
+        Some(SUBSCRIPTION_TIMEOUT)
+    }
+
+    // {"event":"subscriptionStatus","status":"subscribed","pair":"ETH/XBT",..}, with an errorMessage when rejected
+    fn subscription_reply(&self, symbol: &str, v: &Value) -> SubscriptionReply {
+        if v["event"].as_str() != Some("subscriptionStatus") || v["pair"].as_str() != Some(symbol) {
+            return SubscriptionReply::Unrelated;
+        }
+        match v["status"].as_str() {
+            Some("subscribed") => SubscriptionReply::Confirmed,
+            _ => SubscriptionReply::Rejected(v["errorMessage"].as_str().unwrap_or("unknown error").to_string()),
+        }
+    }
+
+    // book messages are arrays: [channelID, {..}, ({..},) "book-10", "ETH/XBT"], the objects
+    // holding "as"/"bs" on the snapshot and "a"/"b" plus a checksum "c" on updates
+    fn parse_update(&mut self, text: &str) -> Result<Option<OrderBook>, ArbError> {
+        let v: Value = serde_json::from_str(text)?;
+        // heartbeats and status events are objects
+        let message = match v.as_array() {
+            Some(message) if message.len() >= 4 => message,
+            _ => return Ok(None),
+        };
+        let channel = message[message.len() - 2].as_str().unwrap_or_default();
+        if !channel.starts_with("book") {
+            return Ok(None);
+        }
+        for payload in &message[1..message.len() - 2] {
+            if payload.get("as").is_some() || payload.get("bs").is_some() {
+                debug!(target: KRAKEN_LOG, channel, "applied book snapshot");
+                self.book.clear();
+                self.apply_levels(Side::Ask, &payload["as"])?;
+                self.apply_levels(Side::Bid, &payload["bs"])?;
+                continue;
+            }
+            if let Some(asks) = payload.get("a") {
+                self.apply_levels(Side::Ask, asks)?;
+            }
+            if let Some(bids) = payload.get("b") {
+                self.apply_levels(Side::Bid, bids)?;
+            }
+        }
+        self.book.truncate(self.subscribed_depth);
+
+        // the checksum rides on the last update object and covers the book once it is applied
+        let checksum = message[1..message.len() - 2].iter().find_map(|payload| payload["c"].as_str());
+        if let Some(checksum) = checksum {
+            let expected = checksum
+                .parse::<u32>()
+                .map_err(|_| ArbError::Parse("checksum is not a number".to_string()))?;
+            if !self.verify_checksum(expected) {
+                debug!(target: KRAKEN_LOG, expected, local = kraken_checksum(&self.book), "checksum mismatch");
+                return Err(ArbError::ChecksumMismatch { exchange: self.name().to_string(), expected });
+            }
+        }
+        Ok(Some(self.book.top(self.name(), self.depth)))
+    }
+
+    fn verify_checksum(&self, expected: u32) -> bool {
+        kraken_checksum(&self.book) == expected
+    }
+}
+
+// an exchange without a network that hands out scripted books, one per message it is given whatever
+// the message says. for exercising the merge and the feed loop deterministically, e.g. replaying
+// `replay_source()` through a Feed
+#[cfg(any(test, feature = "testing"))]
+pub struct MockConnector {
+    name: String,
+    updates: std::collections::VecDeque<OrderBook>,
+    amount_mode: AmountMode,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl MockConnector {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_string(), updates: std::collections::VecDeque::new(), amount_mode: AmountMode::Base }
+    }
+
+    // treats the scripted amounts as amount_mode, e.g. quote notional like a contract exchange
+    pub fn with_amount_mode(mut self, amount_mode: AmountMode) -> Self {
+        self.amount_mode = amount_mode;
+        self
+    }
+
+    // the books handed out in order, each level should carry this connector's name as its exchange
+    pub fn with_updates(mut self, updates: Vec<OrderBook>) -> Self {
+        self.updates.extend(updates);
+        self
+    }
+
+    // one frame for every scripted update, played back without delays
+    pub fn replay_source(&self, symbol: &str) -> crate::recording::ReplaySource {
+        let frames: Vec<_> = (0..self.updates.len()).map(|_| crate::recording::RecordedFrame::new(&self.name, symbol, "{}")).collect();
+        crate::recording::ReplaySource::new(&frames, &self.name, symbol, 0.0)
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+#[async_trait]
+impl ExchangeConnector for MockConnector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn ws_url(&self, _symbol: &str) -> String {
+        format!("ws://{}.invalid", self.name)
+    }
+
+    fn subscribe_message(&self, _symbol: &str) -> String {
+        "{}".to_string()
+    }
+
+    // the next scripted book, None once the script ran out
+    fn parse_update(&mut self, _text: &str) -> Result<Option<OrderBook>, ArbError> {
+        Ok(self.updates.pop_front())
+    }
+
+    fn amount_mode(&self) -> AmountMode {
+        self.amount_mode
+    }
+}
+
+// how each exchange spells a pair, None where the pair is not listed
+struct SymbolListing {
+    canonical: &'static str,
+    binance: Option<&'static str>,
+    bitstamp: Option<&'static str>,
+    coinbase: Option<&'static str>,
+    kraken: Option<&'static str>,
+}
+
+// Binance has no USD books so USD pairs stream its USDT books, Kraken calls bitcoin XBT
+const SYMBOL_TABLE: &[SymbolListing] = &[
+    SymbolListing { canonical: "BTC-USD", binance: Some("btcusdt"), bitstamp: Some("btcusd"), coinbase: Some("BTC-USD"), kraken: Some("XBT/USD") },
+    SymbolListing { canonical: "ETH-USD", binance: Some("ethusdt"), bitstamp: Some("ethusd"), coinbase: Some("ETH-USD"), kraken: Some("ETH/USD") },
+    SymbolListing { canonical: "LTC-USD", binance: Some("ltcusdt"), bitstamp: Some("ltcusd"), coinbase: Some("LTC-USD"), kraken: Some("LTC/USD") },
+    SymbolListing { canonical: "XRP-USD", binance: Some("xrpusdt"), bitstamp: Some("xrpusd"), coinbase: Some("XRP-USD"), kraken: Some("XRP/USD") },
+    SymbolListing { canonical: "BCH-USD", binance: Some("bchusdt"), bitstamp: Some("bchusd"), coinbase: Some("BCH-USD"), kraken: Some("BCH/USD") },
+    SymbolListing { canonical: "LINK-USD", binance: Some("linkusdt"), bitstamp: Some("linkusd"), coinbase: Some("LINK-USD"), kraken: Some("LINK/USD") },
+    SymbolListing { canonical: "BTC-USDT", binance: Some("btcusdt"), bitstamp: Some("btcusdt"), coinbase: Some("BTC-USDT"), kraken: Some("XBT/USDT") },
+    SymbolListing { canonical: "ETH-USDT", binance: Some("ethusdt"), bitstamp: Some("ethusdt"), coinbase: Some("ETH-USDT"), kraken: Some("ETH/USDT") },
+    SymbolListing { canonical: "BTC-EUR", binance: Some("btceur"), bitstamp: Some("btceur"), coinbase: Some("BTC-EUR"), kraken: Some("XBT/EUR") },
+    SymbolListing { canonical: "ETH-EUR", binance: Some("etheur"), bitstamp: Some("etheur"), coinbase: Some("ETH-EUR"), kraken: Some("ETH/EUR") },
+    SymbolListing { canonical: "ETH-BTC", binance: Some("ethbtc"), bitstamp: Some("ethbtc"), coinbase: Some("ETH-BTC"), kraken: Some("ETH/XBT") },
+    SymbolListing { canonical: "LTC-BTC", binance: Some("ltcbtc"), bitstamp: Some("ltcbtc"), coinbase: Some("LTC-BTC"), kraken: Some("LTC/XBT") },
+    SymbolListing { canonical: "BCH-BTC", binance: Some("bchbtc"), bitstamp: Some("bchbtc"), coinbase: Some("BCH-BTC"), kraken: Some("BCH/XBT") },
+    SymbolListing { canonical: "XRP-BTC", binance: Some("xrpbtc"), bitstamp: Some("xrpbtc"), coinbase: None, kraken: Some("XRP/XBT") },
+    SymbolListing { canonical: "LINK-BTC", binance: Some("linkbtc"), bitstamp: Some("linkbtc"), coinbase: None, kraken: Some("LINK/XBT") },
+];
+
+// maps "ethbtc", "eth-btc" or "ETH/BTC" to the canonical "ETH-BTC"
+pub fn canonical_symbol(symbol: &str) -> String {
+    const QUOTES: [&str; 7] = ["usdt", "usdc", "usd", "eur", "gbp", "btc", "eth"];
+
+    let symbol = symbol.trim().to_lowercase().replace(['/', '_'], "-");
+    if symbol.contains('-') {
+        return symbol.to_uppercase();
+    }
+    match QUOTES.iter().find(|quote| symbol.len() > quote.len() && symbol.ends_with(*quote)) {
+        Some(quote) => format!("{}-{}", &symbol[..symbol.len() - quote.len()], quote).to_uppercase(),
+        None => symbol.to_uppercase(),
+    }
+}
+
+// whether exchange lists symbol in another quote currency than the canonical pair's, like Binance
+// streaming BTC-USD as btcusdt. Kraken's XBT counts as BTC
+pub fn quoted_in_other_currency(symbol: &str, exchange: &str) -> bool {
+    let Ok(exchange_symbol) = normalize_symbol(symbol, exchange) else {
+        return false;
+    };
+    let quote = |symbol: &str| canonical_symbol(symbol).rsplit('-').next().map(|quote| quote.replace("XBT", "BTC"));
+    quote(&exchange_symbol) != quote(symbol)
+}
+
+// the symbol in the format `exchange` expects in its stream urls and subscriptions
+pub fn normalize_symbol(symbol: &str, exchange: &str) -> Result<String, ArbError> {
+    let canonical = canonical_symbol(symbol);
+    let unsupported = || ArbError::UnsupportedSymbol { exchange: exchange.to_string(), symbol: canonical.clone() };
+    let listing = SYMBOL_TABLE
+        .iter()
+        .find(|listing| listing.canonical == canonical)
+        .ok_or_else(unsupported)?;
+    let exchange_symbol = match exchange {
+        "binance" => listing.binance,
+        "bitstamp" => listing.bitstamp,
+        "coinbase" => listing.coinbase,
+        "kraken" => listing.kraken,
+        _ => None,
+    };
+    exchange_symbol.map(str::to_string).ok_or_else(unsupported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(exchange: &str, price: &str, amount: &str) -> PriceLevel {
+        PriceLevel { exchange: exchange.to_string(), price: price.parse().unwrap(), amount: amount.parse().unwrap() }
+    }
+
+    fn binance_diff(first_update_id: u64, final_update_id: u64, bids: &[(&str, &str)], asks: &[(&str, &str)]) -> BinanceDepthDiff {
+        let levels = |levels: &[(&str, &str)]| levels.iter().map(|(price, amount)| level("binance", price, amount)).collect();
+        BinanceDepthDiff { first_update_id, final_update_id, bids: levels(bids), asks: levels(asks) }
+    }
+
+    fn binance_snapshot() -> OrderBook {
+        OrderBook::from_levels(vec![level("binance", "100", "1")], vec![level("binance", "101", "1")])
+    }
+
+    #[test]
+    fn binance_diffs_in_order_are_applied() {
+        let mut state = BinanceBookState::default();
+        // buffered until the snapshot, the first one is already covered by it
+        assert_eq!(state.apply_diff(binance_diff(8, 10, &[("99", "5")], &[])), DiffOutcome::Buffered);
+        assert_eq!(state.apply_diff(binance_diff(11, 12, &[("100", "2")], &[])), DiffOutcome::Buffered);
+        state.apply_snapshot(10, binance_snapshot()).unwrap();
+        assert!(!state.needs_snapshot());
+        assert_eq!(state.apply_diff(binance_diff(13, 13, &[], &[("101", "0"), ("102", "3")])), DiffOutcome::Applied);
+
+        let (bids, asks) = state.top(10).into_levels();
+        assert_eq!(bids, vec![level("binance", "100", "2")]);
+        assert_eq!(asks, vec![level("binance", "102", "3")]);
+    }
+
+    #[test]
+    fn binance_gap_drops_the_book_until_the_next_snapshot() {
+        let mut state = BinanceBookState::default();
+        state.apply_snapshot(10, binance_snapshot()).unwrap();
+        assert_eq!(state.apply_diff(binance_diff(15, 16, &[("98", "1")], &[])), DiffOutcome::Gap { expected: 11, got: 15 });
+        assert!(state.needs_snapshot());
+
+        // the gapped diff is kept and applied on top of the next snapshot
+        state.apply_snapshot(14, binance_snapshot()).unwrap();
+        let (bids, _) = state.top(10).into_levels();
+        assert_eq!(bids, vec![level("binance", "100", "1"), level("binance", "98", "1")]);
+        assert_eq!(state.apply_diff(binance_diff(17, 17, &[], &[])), DiffOutcome::Applied);
+    }
+
+    #[test]
+    fn binance_out_of_order_diffs_are_dropped() {
+        let mut state = BinanceBookState::default();
+        state.apply_snapshot(10, binance_snapshot()).unwrap();
+        assert_eq!(state.apply_diff(binance_diff(11, 14, &[("100", "4")], &[])), DiffOutcome::Applied);
+        // arrives late, everything in it is already in the book
+        assert_eq!(state.apply_diff(binance_diff(11, 12, &[("100", "9")], &[])), DiffOutcome::Stale);
+        let (bids, _) = state.top(10).into_levels();
+        assert_eq!(bids, vec![level("binance", "100", "4")]);
+    }
+
+    #[test]
+    fn binance_snapshot_older_than_the_buffer_fails() {
+        let mut state = BinanceBookState::default();
+        state.apply_diff(binance_diff(20, 21, &[], &[]));
+        assert!(matches!(state.apply_snapshot(10, binance_snapshot()), Err(ArbError::SequenceGap { expected: 11, got: 20, .. })));
+    }
+
+    #[test]
+    fn event_times_of_both_exchanges() {
+        let binance: Value = serde_json::from_str(r#"{"e":"depthUpdate","E":1700000000123,"U":1,"u":2,"b":[],"a":[]}"#).unwrap();
+        assert_eq!(extract_event_time(&binance, "binance"), Some(1_700_000_000_123));
+        let combined: Value = serde_json::from_str(r#"{"stream":"ethbtc@depth","data":{"E":1700000000456}}"#).unwrap();
+        assert_eq!(extract_event_time(&combined, "binance"), Some(1_700_000_000_456));
+        let bitstamp: Value = serde_json::from_str(r#"{"event":"data","data":{"microtimestamp":"1700000000789012"}}"#).unwrap();
+        assert_eq!(extract_event_time(&bitstamp, "bitstamp"), Some(1_700_000_000_789));
+        assert_eq!(extract_event_time(&bitstamp, "binance"), None);
+        assert_eq!(extract_event_time(&binance, "kraken"), None);
+    }
+
+    #[test]
+    fn symbols_in_each_exchange_format() {
+        for symbol in ["BTC-USD", "btcusd", "btc/usd", "BTC_USD"] {
+            assert_eq!(normalize_symbol(symbol, "binance").unwrap(), "btcusdt");
+            assert_eq!(normalize_symbol(symbol, "bitstamp").unwrap(), "btcusd");
+            assert_eq!(normalize_symbol(symbol, "coinbase").unwrap(), "BTC-USD");
+            assert_eq!(normalize_symbol(symbol, "kraken").unwrap(), "XBT/USD");
+        }
+        assert_eq!(normalize_symbol("ethbtc", "binance").unwrap(), "ethbtc");
+        assert_eq!(normalize_symbol("ethbtc", "kraken").unwrap(), "ETH/XBT");
+        assert_eq!(normalize_symbol("ETH-EUR", "bitstamp").unwrap(), "etheur");
+    }
+
+    #[test]
+    fn unlisted_pairs_are_unsupported() {
+        assert!(matches!(
+            normalize_symbol("doge-usd", "binance"),
+            Err(ArbError::UnsupportedSymbol { exchange, symbol }) if exchange == "binance" && symbol == "DOGE-USD"
+        ));
+        // listed, just not on every exchange
+        assert!(normalize_symbol("XRP-BTC", "coinbase").is_err());
+        assert!(normalize_symbol("ETH-BTC", "ftx").is_err());
+    }
+
+    #[test]
+    fn bitstamp_reconnect_request_is_an_error() {
+        let mut connector = BitstampConnector::new(10, false);
+        let request = r#"{"event":"bts:request_reconnect","channel":"","data":""}"#;
+        assert!(matches!(connector.parse_update(request), Err(ArbError::ReconnectRequested { exchange }) if exchange == "bitstamp"));
+    }
+
+    #[test]
+    fn bitstamp_subscription_replies() {
+        let connector = BitstampConnector::new(10, false);
+        let reply = |text: &str| connector.subscription_reply("ethbtc", &serde_json::from_str(text).unwrap());
+        assert_eq!(reply(r#"{"event":"bts:subscription_succeeded","channel":"order_book_ethbtc","data":{}}"#), SubscriptionReply::Confirmed);
+        assert_eq!(
+            reply(r#"{"event":"bts:error","channel":"","data":{"code":null,"message":"Bad subscription string."}}"#),
+            SubscriptionReply::Rejected("Bad subscription string.".to_string())
+        );
+        // another channel's confirmation, or data, says nothing about ours
+        assert_eq!(reply(r#"{"event":"bts:subscription_succeeded","channel":"order_book_btcusd","data":{}}"#), SubscriptionReply::Unrelated);
+        assert_eq!(reply(r#"{"event":"data","channel":"order_book_ethbtc","data":{}}"#), SubscriptionReply::Unrelated);
+        let diff = BitstampConnector::new(10, true);
+        assert_eq!(
+            diff.subscription_reply("ethbtc", &serde_json::from_str(r#"{"event":"bts:subscription_succeeded","channel":"diff_order_book_ethbtc"}"#).unwrap()),
+            SubscriptionReply::Confirmed
+        );
+    }
+
+    #[test]
+    fn binance_subscription_replies() {
+        let connector = BinanceConnector::new(10);
+        let reply = |text: &str| connector.subscription_reply("ethbtc", &serde_json::from_str(text).unwrap());
+        assert_eq!(reply(r#"{"result":null,"id":1}"#), SubscriptionReply::Confirmed);
+        assert_eq!(
+            reply(r#"{"error":{"code":2,"msg":"Invalid request: unknown stream"},"id":1}"#),
+            SubscriptionReply::Rejected("Invalid request: unknown stream".to_string())
+        );
+        // answers to other requests and stream events are not ours
+        assert_eq!(reply(r#"{"result":null,"id":7}"#), SubscriptionReply::Unrelated);
+        assert_eq!(reply(r#"{"e":"depthUpdate","U":1,"u":2,"b":[],"a":[]}"#), SubscriptionReply::Unrelated);
+    }
+
+    const KRAKEN_SNAPSHOT: &str = r#"[336,{"as":[["0.05005","1.50000000","1700000000.100000"],["0.05010","2.00000000","1700000000.200000"]],"bs":[["0.05000","3.00000000","1700000000.300000"],["0.04995","0.50000000","1700000000.400000"]]},"book-10","ETH/XBT"]"#;
+
+    #[test]
+    fn kraken_snapshot_and_update_are_parsed() {
+        let mut connector = KrakenConnector::new(10);
+        let book = connector.parse_update(KRAKEN_SNAPSHOT).unwrap().unwrap();
+        let (bids, asks) = book.into_levels();
+        assert_eq!(bids, vec![level("kraken", "0.05000", "3"), level("kraken", "0.04995", "0.5")]);
+        assert_eq!(asks, vec![level("kraken", "0.05005", "1.5"), level("kraken", "0.05010", "2")]);
+
+        // asks and bids in separate objects, the checksum on the last one
+        let update = r#"[336,{"a":[["0.05005","0.00000000","1700000001.100000"],["0.05008","1.00000000","1700000001.200000"]]},{"b":[["0.05002","0.25000000","1700000001.300000","r"]],"c":"1114284533"},"book-10","ETH/XBT"]"#;
+        let (bids, asks) = connector.parse_update(update).unwrap().unwrap().into_levels();
+        assert_eq!(bids, vec![level("kraken", "0.05002", "0.25"), level("kraken", "0.05000", "3"), level("kraken", "0.04995", "0.5")]);
+        assert_eq!(asks, vec![level("kraken", "0.05008", "1"), level("kraken", "0.05010", "2")]);
+
+        // heartbeats and status events carry no book
+        assert!(connector.parse_update(r#"{"event":"heartbeat"}"#).unwrap().is_none());
+    }
+
+    #[test]
+    fn kraken_checksum_of_a_known_book() {
+        let mut connector = KrakenConnector::new(10);
+        connector.parse_update(KRAKEN_SNAPSHOT).unwrap();
+        // crc32 of "5005150000000" "5010200000000" "5000300000000" "499550000000"
+        assert_eq!(kraken_checksum(&connector.book), 4011387653);
+        assert!(connector.verify_checksum(4011387653));
+        assert!(!connector.verify_checksum(4011387654));
+
+        let update = r#"[336,{"a":[["0.05010","2.50000000","1700000001.100000"]],"c":"4011387653"},"book-10","ETH/XBT"]"#;
+        assert!(matches!(
+            connector.parse_update(update),
+            Err(ArbError::ChecksumMismatch { expected: 4011387653, ref exchange }) if exchange == "kraken"
+        ));
+    }
+
+    fn bitstamp_diff_message(microtimestamp: i64, bids: &str, asks: &str) -> String {
+        format!(
+            r#"{{"data":{{"timestamp":"1700000000","microtimestamp":"{}","bids":{},"asks":{}}},"channel":"diff_order_book_ethbtc","event":"data"}}"#,
+            microtimestamp, bids, asks
+        )
+    }
+
+    #[test]
+    fn bitstamp_diff_sequence_is_applied_on_the_snapshot() {
+        let mut connector = BitstampConnector::new(10, true);
+        assert!(connector.subscribe_message("ethbtc").contains("diff_order_book_ethbtc"));
+
+        // buffered while the snapshot is fetched, the first one is older than the snapshot
+        assert!(connector.parse_update(&bitstamp_diff_message(1_000, r#"[["0.0499","9"]]"#, "[]")).unwrap().is_none());
+        assert!(connector.parse_update(&bitstamp_diff_message(3_000, r#"[["0.0500","2"]]"#, "[]")).unwrap().is_none());
+        let snapshot: Value = serde_json::from_str(
+            r#"{"timestamp":"1700000000","microtimestamp":"2000","bids":[["0.0500","1"],["0.0499","1"]],"asks":[["0.0501","1"],["0.0502","1"]]}"#,
+        )
+        .unwrap();
+        connector.diff.as_mut().unwrap().apply_snapshot(parse_bitstamp_diff(&snapshot).unwrap());
+
+        // a zero amount removes the price
+        let book = connector.parse_update(&bitstamp_diff_message(4_000, r#"[["0.0499","0.00000000"]]"#, r#"[["0.0501","0.5"]]"#)).unwrap().unwrap();
+        let (bids, asks) = book.into_levels();
+        assert_eq!(bids, vec![level("bitstamp", "0.0500", "2")]);
+        assert_eq!(asks, vec![level("bitstamp", "0.0501", "0.5"), level("bitstamp", "0.0502", "1")]);
+
+        // a reconnect starts over from a new snapshot
+        connector.reset();
+        assert!(connector.diff.as_ref().unwrap().needs_snapshot());
+        assert!(connector.parse_update(&bitstamp_diff_message(5_000, "[]", r#"[["0.0503","1"]]"#)).unwrap().is_none());
+    }
+
+    #[test]
+    fn coinbase_snapshot_and_update_are_parsed() {
+        let mut connector = CoinbaseConnector::new(10);
+        let snapshot = r#"{"type":"snapshot","product_id":"ETH-BTC","bids":[["0.05000","3.0"],["0.04990","1.5"]],"asks":[["0.05010","2.0"],["0.05020","4.0"]]}"#;
+        let (bids, asks) = connector.parse_update(snapshot).unwrap().unwrap().into_levels();
+        assert_eq!(bids, vec![level("coinbase", "0.05000", "3"), level("coinbase", "0.04990", "1.5")]);
+        assert_eq!(asks, vec![level("coinbase", "0.05010", "2"), level("coinbase", "0.05020", "4")]);
+
+        // a zero size removes the price
+        let update = r#"{"type":"l2update","product_id":"ETH-BTC","time":"2023-11-14T22:13:20.000000Z","changes":[["buy","0.05005","0.5"],["sell","0.05010","0.00000000"],["buy","0.04990","0"]]}"#;
+        let (bids, asks) = connector.parse_update(update).unwrap().unwrap().into_levels();
+        assert_eq!(bids, vec![level("coinbase", "0.05005", "0.5"), level("coinbase", "0.05000", "3")]);
+        assert_eq!(asks, vec![level("coinbase", "0.05020", "4")]);
+
+        let unknown_side = r#"{"type":"l2update","product_id":"ETH-BTC","changes":[["hold","0.05","1"]]}"#;
+        assert!(matches!(connector.parse_update(unknown_side), Err(ArbError::Parse(reason)) if reason.contains("hold")));
+        // subscription confirmations and heartbeats carry no book
+        assert!(connector.parse_update(r#"{"type":"subscriptions","channels":[]}"#).unwrap().is_none());
+    }
+
+    #[test]
+    fn binance_messages_are_routed_raw_and_combined() {
+        let depth = r#"{"e":"depthUpdate","E":1700000000000,"s":"ETHBTC","U":5,"u":7,"b":[["0.0610","2"]],"a":[["0.0619","0"]]}"#;
+        let trade = r#"{"e":"aggTrade","E":1700000000000,"s":"ETHBTC","a":1,"p":"0.0612","q":"0.5","T":1700000000000,"m":false}"#;
+        for (depth, trade) in [
+            (depth.to_string(), trade.to_string()),
+            (format!(r#"{{"stream":"ethbtc@depth@100ms","data":{}}}"#, depth), format!(r#"{{"stream":"ethbtc@aggTrade","data":{}}}"#, trade)),
+        ] {
+            match parse_binance_message(&depth).unwrap() {
+                BinanceMessage::Depth(diff) => {
+                    assert_eq!((diff.first_update_id, diff.final_update_id), (5, 7));
+                    assert_eq!(diff.bids, vec![level("binance", "0.0610", "2")]);
+                    assert_eq!(diff.asks, vec![level("binance", "0.0619", "0")]);
+                }
+                other => panic!("{} parsed as {:?}", depth, other),
+            }
+            assert!(matches!(parse_binance_message(&trade).unwrap(), BinanceMessage::Trade), "{}", trade);
+        }
+        assert!(matches!(parse_binance_message(r#"{"result":null,"id":1}"#).unwrap(), BinanceMessage::Other));
+    }
+
+    #[test]
+    fn binance_agg_trades_are_parsed() {
+        let trade = |maker: bool| json!({"e":"aggTrade","E":1700000000001u64,"s":"ETHBTC","a":1,"p":"0.0612","q":"0.5","T":1700000000000u64,"m":maker});
+        let parsed = parse_trade(&trade(false), "binance").unwrap();
+        assert_eq!(parsed.price, 0.0612);
+        assert_eq!(parsed.amount, 0.5);
+        assert_eq!(parsed.timestamp_ms, 1_700_000_000_000);
+        assert_eq!(parsed.exchange, "binance");
+        // the taker bought unless the buyer was the maker
+        assert_eq!(parsed.side, TradeSide::Buy as i32);
+        assert_eq!(parse_trade(&trade(true), "binance").unwrap().side, TradeSide::Sell as i32);
+        // the combined stream format too
+        let combined = json!({"stream":"ethbtc@aggTrade","data":trade(true)});
+        assert_eq!(parse_trade(&combined, "binance").unwrap().side, TradeSide::Sell as i32);
+
+        let depth = json!({"e":"depthUpdate","E":1700000000000u64,"U":5,"u":7,"b":[],"a":[]});
+        assert!(parse_trade(&depth, "binance").is_none());
+        assert!(parse_trade(&trade(false), "bitstamp").is_none());
+    }
+}
@@ -0,0 +1,1504 @@
+// one task per exchange and symbol: connect, subscribe, and merge every update into the shared book
+
+// WebSocket crates
+use tokio_tungstenite::tungstenite::protocol::Message as TMessage;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tungstenite::Message;
+
+use futures::stream::Stream;
+use futures::StreamExt;
+use futures::{Sink, SinkExt};
+
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::{broadcast, watch, Mutex};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, info_span, warn, Instrument};
+use rand::Rng;
+use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
+
+// order book core
+use crate::{level_counts_by_exchange, orderbook, AmountMode, ArbError, Fees, OrderBook};
+use crate::connectors::{exchange_log, extract_event_time, fetch_rest_snapshot, parse_trade, ExchangeConnector, RateLimit, SubscriptionReply};
+use crate::metrics::METRICS;
+use crate::recording::{RecordedFrame, Recorder, ReplaySource};
+use crate::tls::TlsConnector;
+
+// gRPC crates
+use orderbook::{Summary, FeedHealth, Opportunity, Trade};
+
+// used to parse orderbook update
+use serde_json::Value;
+
+// average of the last `capacity` samples
+#[derive(Debug)]
+pub struct RollingAverage {
+    samples: VecDeque<i64>,
+    capacity: usize,
+    sum: i64,
+}
+
+impl RollingAverage {
+    pub fn new(capacity: usize) -> Self {
+        Self { samples: VecDeque::with_capacity(capacity), capacity, sum: 0 }
+    }
+
+    pub fn push(&mut self, sample: i64) -> f64 {
+        if self.samples.len() == self.capacity {
+            self.sum -= self.samples.pop_front().unwrap_or_default();
+        }
+        self.samples.push_back(sample);
+        self.sum += sample;
+        self.sum as f64 / self.samples.len() as f64
+    }
+}
+
+// the read half of an exchange connection, boxed so TLS, plaintext and replayed feeds all go
+// through the same read loop
+pub type MessageStream = Pin<Box<dyn Stream<Item = Result<TMessage, tungstenite::Error>> + Send>>;
+
+// the write half, for the subscription and the pongs
+pub type MessageSink = Pin<Box<dyn Sink<TMessage, Error = tungstenite::Error> + Send>>;
+
+// publishes the latest summary of one book, watch::Sender is not Clone so the feeds share it
+pub type SummarySender = Arc<watch::Sender<Arc<Summary>>>;
+
+// trades buffered for slow trade stream subscribers before they start skipping
+pub const TRADE_CHANNEL_CAPACITY: usize = 1024;
+
+// opportunities buffered for slow opportunity stream subscribers before they start skipping
+pub const OPPORTUNITY_CHANNEL_CAPACITY: usize = 256;
+
+// where detected arbitrage opportunities go, and the smallest profit per unit after fees worth reporting
+#[derive(Debug, Clone)]
+pub struct Opportunities {
+    pub sender: broadcast::Sender<Opportunity>,
+    pub min_profit: Decimal,
+    pub fees: Fees,
+}
+
+// current wall clock time in milliseconds since the epoch
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+// largest websocket message accepted from an exchange unless configured otherwise
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 << 20;
+
+// how the feeds open their websocket connections
+#[derive(Clone)]
+pub struct WebSocketOptions {
+    pub tls: TlsConnector,
+    // messages and frames above this many bytes fail the connection, which is then reopened
+    pub max_message_size: usize,
+}
+
+// backoff parameters used when reconnecting to an exchange
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+    // consecutive failed attempts after which a feed gives up, None retries forever
+    pub max_retries: Option<u32>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+            max_retries: None,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    // next delay in the exponential sequence, capped at max
+    pub fn next_delay(&self, current: Duration) -> Duration {
+        current.mul_f64(self.multiplier).min(self.max)
+    }
+
+    // adds up to 50% random jitter so both exchanges don't reconnect in lockstep
+    pub fn with_jitter(&self, delay: Duration) -> Duration {
+        let jitter = rand::thread_rng().gen_range(0.0..=0.5);
+        delay.mul_f64(1.0 + jitter).min(self.max)
+    }
+}
+
+// spaces out connection attempts or outbound messages to a RateLimit. each caller reserves the
+// next token and waits for it, so callers sharing a bucket are served in order
+#[derive(Debug)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    // tokens left as of the instant, negative while callers are waiting on reserved tokens
+    state: std::sync::Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        Self { limit, state: std::sync::Mutex::new((f64::from(limit.burst), Instant::now())) }
+    }
+
+    // takes a token, sleeping until one is available rather than failing
+    pub async fn acquire(&self) {
+        if self.limit.interval.is_zero() {
+            return;
+        }
+        let wait = {
+            let mut state = self.state.lock().expect("token bucket lock poisoned");
+            let (tokens, refilled_at) = &mut *state;
+            let now = Instant::now();
+            let refilled = now.duration_since(*refilled_at).as_secs_f64() / self.limit.interval.as_secs_f64();
+            *tokens = (*tokens + refilled).min(f64::from(self.limit.burst)) - 1.0;
+            *refilled_at = now;
+            if *tokens >= 0.0 { Duration::ZERO } else { self.limit.interval.mul_f64(-*tokens) }
+        };
+        if !wait.is_zero() {
+            debug!("Rate limited, waiting {:?}", wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+// one exchange connection streaming a symbol into that symbol's book
+pub struct Feed {
+    pub symbol: String,
+    // the symbol as this exchange spells it
+    pub exchange_symbol: String,
+    pub connector: Box<dyn ExchangeConnector>,
+    pub order_book: Arc<Mutex<OrderBook>>,
+    pub summaries: SummarySender,
+    // recorded frames to play back instead of connecting
+    pub replay: Option<ReplaySource>,
+    // where to copy the received frames, if recording
+    pub recorder: Option<Recorder>,
+    pub health: Arc<FeedStatus>,
+    // levels of an exchange silent for longer are flagged as stale
+    pub stale_after: Duration,
+}
+
+// connection state of one feed, updated by its connection and read by the health rpc
+#[derive(Debug)]
+pub struct FeedStatus {
+    pub exchange: String,
+    pub symbol: String,
+    pub connected: AtomicBool,
+    // milliseconds since the epoch, 0 before the first update
+    pub last_update_ms: AtomicI64,
+    pub message_count: AtomicU64,
+    // set once the feed ran out of retries and stopped reconnecting
+    pub failed: AtomicBool,
+    // reconnection attempts since startup
+    pub reconnects: AtomicU64,
+}
+
+impl FeedStatus {
+    pub fn new(exchange: &str, symbol: &str) -> Self {
+        Self {
+            exchange: exchange.to_string(),
+            symbol: symbol.to_string(),
+            connected: AtomicBool::new(false),
+            last_update_ms: AtomicI64::new(0),
+            message_count: AtomicU64::new(0),
+            failed: AtomicBool::new(false),
+            reconnects: AtomicU64::new(0),
+        }
+    }
+
+    pub fn to_proto(&self) -> FeedHealth {
+        FeedHealth {
+            exchange: self.exchange.clone(),
+            symbol: self.symbol.clone(),
+            connected: self.connected.load(Ordering::Relaxed),
+            last_update_ms: self.last_update_ms.load(Ordering::Relaxed),
+            message_count: self.message_count.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            // depends on the merged book, filled in by the health rpc
+            merged_levels: 0,
+        }
+    }
+}
+
+// where a feed's updates go and how often, shared by every connection attempt
+#[derive(Clone)]
+pub struct FeedTarget {
+    pub symbol: String,
+    pub exchange_symbol: String,
+    pub order_book: Arc<Mutex<OrderBook>>,
+    pub summaries: SummarySender,
+    pub recorder: Option<Recorder>,
+    pub health: Arc<FeedStatus>,
+    // when set, only the latest update of each window is written to the book
+    pub coalesce: Option<Duration>,
+    pub stale_after: Duration,
+    // what the exchange's amounts measure, converted to the base asset before merging
+    pub amount_mode: AmountMode,
+    pub trades: broadcast::Sender<Trade>,
+    pub opportunities: Opportunities,
+}
+
+// fills the books with each exchange's REST snapshot, so clients see a full book before the websockets
+// deliver. the first websocket update of an exchange replaces its snapshot, failures leave it to the stream
+pub async fn seed_from_rest(feeds: &[Feed], depth: usize) {
+    let fetches = feeds.iter().filter(|feed| feed.replay.is_none() && feed.connector.rest_url().is_some()).map(|feed| async move {
+        let exchange = feed.connector.name();
+        match fetch_rest_snapshot(exchange, &feed.exchange_symbol, depth).await {
+            Ok(snapshot) => {
+                let mut order_book = feed.order_book.lock().await;
+                let (bids, asks) = snapshot.into_levels();
+                let amount_mode = feed.connector.amount_mode();
+                order_book.replace_exchange_levels(exchange, amount_mode.to_base(bids), amount_mode.to_base(asks));
+                order_book.mark_updated(exchange, now_millis());
+                feed.summaries.send_replace(Arc::new(Summary { generated_at_ms: now_millis(), ..order_book.to_summary() }));
+                info!("Seeded {} from the {} REST snapshot", feed.symbol, exchange);
+            }
+            Err(e) => warn!("No REST snapshot of {} from {}: {}", feed.symbol, exchange, e),
+        }
+    });
+    futures::future::join_all(fetches).await;
+}
+
+//Merges orderbooks fetched by websocket functions
+pub async fn run(feeds: Vec<Feed>, trades: broadcast::Sender<Trade>, opportunities: Opportunities, reconnect: ReconnectConfig, websocket: WebSocketOptions, coalesce: Option<Duration>, shutdown: CancellationToken) -> anyhow::Result<()> {
+    // exchanges limit connections per IP, so every symbol of an exchange draws from one bucket
+    let mut connect_limits: HashMap<String, Arc<TokenBucket>> = HashMap::new();
+    let feeds = feeds.into_iter().map(|feed| {
+        let connect_limit = Arc::clone(connect_limits
+            .entry(feed.connector.name().to_string())
+            .or_insert_with(|| Arc::new(TokenBucket::new(feed.connector.connect_rate_limit()))));
+        let reconnect = reconnect.clone();
+        let websocket = websocket.clone();
+        let trades = trades.clone();
+        let opportunities = opportunities.clone();
+        let shutdown = shutdown.clone();
+        let target = FeedTarget { symbol: feed.symbol, exchange_symbol: feed.exchange_symbol, order_book: feed.order_book, summaries: feed.summaries, recorder: feed.recorder, health: feed.health, coalesce, stale_after: feed.stale_after, amount_mode: feed.connector.amount_mode(), trades, opportunities };
+        tokio::spawn(async move {
+            match feed.replay {
+                Some(source) => replay_exchange(feed.connector, target, source, shutdown).await,
+                None => connect_to_exchange(feed.connector, target, reconnect, websocket, connect_limit, shutdown).await,
+            }
+        })
+    });
+    // A feed that gives up only ends its own task, its error is logged and flagged in health rather than
+    // stopping the others. Only a panicking feed fails the whole run
+    let _ = futures::future::try_join_all(feeds).await?;
+
+    Ok(())
+}
+
+// plays a recording through the exchange's parser once, without reconnecting when it runs out
+async fn replay_exchange(mut connector: Box<dyn ExchangeConnector>, target: FeedTarget, source: ReplaySource, shutdown: CancellationToken) -> Result<(), ArbError> {
+    let exchange = connector.name().to_string();
+    let symbol = target.symbol.as_str();
+    let span = info_span!("replay", exchange = %exchange, symbol = %symbol);
+    // a recording has nobody to answer pings to
+    let mut replies: MessageSink = Box::pin(futures::sink::drain().sink_map_err(|e: Infallible| match e {}));
+    // nor any snapshots, the connector has to make do with the recorded frames
+    connector.start_replay();
+    target.health.connected.store(true, Ordering::Relaxed);
+    let result = read_updates(source.into_stream(), &mut replies, connector.as_mut(), &target, None, &shutdown)
+        .instrument(span)
+        .await;
+    target.health.connected.store(false, Ordering::Relaxed);
+    match &result {
+        Ok(()) => exchange_log!(info, exchange, "Replay of {} ({}) finished", exchange, symbol),
+        Err(e) => exchange_log!(warn, exchange, "Replay of {} ({}) failed: {}", exchange, symbol, e),
+    }
+    result
+}
+
+// keeps a websocket session to the chosen exchange alive, reconnecting with exponential backoff
+async fn connect_to_exchange(mut connector: Box<dyn ExchangeConnector>, target: FeedTarget, reconnect: ReconnectConfig, websocket: WebSocketOptions, connect_limit: Arc<TokenBucket>, shutdown: CancellationToken) -> Result<(), ArbError> {
+    let exchange = connector.name().to_string();
+    let symbol = target.symbol.as_str();
+    let mut delay = reconnect.initial;
+    // attempts in a row that never got connected
+    let mut failures = 0;
+
+    loop {
+        // Wait for the exchange's connection limit instead of risking a ban
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            _ = connect_limit.acquire() => (),
+        }
+        let span = info_span!("connection", exchange = %exchange, symbol = %symbol);
+        let result = stream_exchange(connector.as_mut(), &target, &websocket, &shutdown)
+            .instrument(span)
+            .await;
+        let was_connected = target.health.connected.swap(false, Ordering::Relaxed);
+        if shutdown.is_cancelled() {
+            exchange_log!(info, exchange, "Disconnected from {} ({})", exchange, symbol);
+            return Ok(());
+        }
+        if was_connected {
+            failures = 0;
+        }
+        match result {
+            Ok(()) => {
+                exchange_log!(warn, exchange, "Connection to {} ({}) closed", exchange, symbol);
+                delay = reconnect.initial;
+            }
+            // The exchange won't stream this symbol however often we ask, stop this feed and let the others carry on
+            Err(e @ ArbError::SubscriptionRejected { .. }) => {
+                exchange_log!(error, exchange, "Giving up on {} ({}): {}", exchange, symbol, e);
+                target.health.failed.store(true, Ordering::Relaxed);
+                return Err(e);
+            }
+            Err(e) => {
+                exchange_log!(warn, exchange, "Connection to {} ({}) failed: {}", exchange, symbol, e);
+                if !was_connected {
+                    failures += 1;
+                }
+                // A dead endpoint or delisted pair would be retried forever, let the other feeds carry on
+                if reconnect.max_retries.is_some_and(|max_retries| failures > max_retries) {
+                    exchange_log!(error, exchange, "Giving up on {} ({}) after {} failed attempts: {}", exchange, symbol, failures, e);
+                    target.health.failed.store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+            }
+        }
+
+        let wait = reconnect.with_jitter(delay);
+        exchange_log!(warn, exchange, "Reconnecting to {} ({}) in {:?}", exchange, symbol, wait);
+        METRICS.reconnections.with_label_values(&[&exchange]).inc();
+        target.health.reconnects.fetch_add(1, Ordering::Relaxed);
+        target.order_book.lock().await.record_reconnect(&exchange);
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            _ = tokio::time::sleep(wait) => (),
+        }
+        delay = reconnect.next_delay(delay);
+    }
+}
+
+// connect websocket to chosen exchange, subscribe and stream updates until the connection ends
+async fn stream_exchange(connector: &mut dyn ExchangeConnector, target: &FeedTarget, websocket: &WebSocketOptions, shutdown: &CancellationToken) -> Result<(), ArbError> {
+    let exchange = connector.name().to_string();
+    let symbol = target.exchange_symbol.as_str();
+    let url = connector.ws_url(symbol);
+
+    connector.reset();
+    let (ws_sink, mut ws_stream) = connect_websocket(&url, &exchange, websocket).await?;
+    exchange_log!(info, exchange, "Connected to {}", exchange);
+
+    // Everything we send on this connection waits for a token first
+    let message_limit = Arc::new(TokenBucket::new(connector.message_rate_limit()));
+    let mut ws_sink: MessageSink = Box::pin(ws_sink.with(move |message| {
+        let message_limit = Arc::clone(&message_limit);
+        async move {
+            message_limit.acquire().await;
+            Ok::<_, tungstenite::Error>(message)
+        }
+    }));
+    target.health.connected.store(true, Ordering::Relaxed);
+
+    ws_sink.send(Message::Text(connector.subscribe_message(symbol))).await
+        .map_err(|e| ArbError::Subscribe { exchange: exchange.clone(), reason: e.to_string() })?;
+
+    if let Some(timeout) = connector.subscription_timeout() {
+        tokio::time::timeout(timeout, await_subscription(&mut ws_stream, &mut ws_sink, &mut *connector, symbol))
+            .await
+            .map_err(|_| ArbError::Subscribe { exchange: exchange.clone(), reason: format!("no confirmation within {:?}", timeout) })??;
+        exchange_log!(info, exchange, "Subscribed to {} on {}", symbol, exchange);
+    }
+
+    let idle_timeout = connector.idle_timeout();
+    read_updates(ws_stream, &mut ws_sink, connector, target, Some(idle_timeout), shutdown).await
+}
+
+// opens a websocket to url and splits it into its two halves
+pub async fn connect_websocket(url: &str, exchange: &str, options: &WebSocketOptions) -> Result<(MessageSink, MessageStream), ArbError> {
+    // tokio-tungstenite resolves, connects and runs the TLS handshake for wss:// urls, using our
+    // connector so a custom CA bundle still applies. ws:// urls, such as a local mock server, stay
+    // plaintext and both come back as the same MaybeTlsStream
+    let connector_tls = url.starts_with("wss://").then(|| options.tls.websocket_connector());
+    // an oversized message errors the stream before it is buffered, rather than exhausting memory
+    let config = WebSocketConfig {
+        max_message_size: Some(options.max_message_size),
+        max_frame_size: Some(options.max_message_size),
+        ..WebSocketConfig::default()
+    };
+    let (ws_stream, _) = tokio_tungstenite::connect_async_tls_with_config(url, Some(config), false, connector_tls).await
+        .map_err(|e| match e {
+            tungstenite::Error::Url(e) => ArbError::Url { url: url.to_string(), reason: e.to_string() },
+            // a string that isn't a uri at all fails while the request is built
+            tungstenite::Error::HttpFormat(e) => ArbError::Url { url: url.to_string(), reason: e.to_string() },
+            tungstenite::Error::Tls(e) => ArbError::Tls { exchange: exchange.to_string(), reason: e.to_string() },
+            e => ArbError::Connect { exchange: exchange.to_string(), reason: e.to_string() },
+        })?;
+    let (ws_sink, ws_stream) = ws_stream.split();
+    Ok((Box::pin(ws_sink), Box::pin(ws_stream)))
+}
+
+// reads until the exchange confirms or rejects the subscription to symbol, dropping anything else
+async fn await_subscription(ws_stream: &mut MessageStream, ws_sink: &mut MessageSink, connector: &mut dyn ExchangeConnector, symbol: &str) -> Result<(), ArbError> {
+    let exchange = connector.name().to_string();
+    loop {
+        match ws_stream.next().await {
+            Some(Ok(TMessage::Text(text))) => {
+                let Ok(v) = serde_json::from_str::<Value>(&text) else {
+                    continue;
+                };
+                match connector.subscription_reply(symbol, &v) {
+                    SubscriptionReply::Confirmed => return Ok(()),
+                    SubscriptionReply::Rejected(reason) => return Err(ArbError::SubscriptionRejected { exchange, reason }),
+                    SubscriptionReply::Unrelated => exchange_log!(debug, exchange, "Ignoring message from {} before the subscription was confirmed", exchange),
+                }
+            }
+            Some(Ok(TMessage::Ping(payload))) => {
+                ws_sink.send(TMessage::Pong(payload)).await
+                    .map_err(|e| ArbError::Connect { exchange: exchange.clone(), reason: e.to_string() })?;
+            }
+            Some(Ok(TMessage::Close(_))) | None => {
+                return Err(ArbError::Subscribe { exchange, reason: "connection closed before the subscription was confirmed".to_string() });
+            }
+            Some(Ok(_)) => (),
+            Some(Err(e)) => return Err(ArbError::Connect { exchange, reason: e.to_string() }),
+        }
+    }
+}
+
+// consumes websocket messages until the stream closes or errors, updating the shared orderbook on every depth message
+async fn read_updates(mut ws_stream: MessageStream, ws_sink: &mut MessageSink, connector: &mut dyn ExchangeConnector, target: &FeedTarget, idle_timeout: Option<Duration>, shutdown: &CancellationToken) -> Result<(), ArbError> {
+    let exchange = connector.name().to_string();
+    let symbol = target.symbol.as_str();
+    let mut feed_latency = RollingAverage::new(100);
+    // the newest update not yet written when coalescing, each one replaces this exchange's levels
+    // so only the last of a window matters
+    let mut pending: Option<(OrderBook, Instant)> = None;
+    let mut flush_at = tokio::time::Instant::now();
+
+    loop {
+        let next = async {
+            match idle_timeout {
+                Some(idle) => tokio::time::timeout(idle, ws_stream.next()).await.map_err(|_| idle),
+                None => Ok(ws_stream.next().await),
+            }
+        };
+        let msg = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = tokio::time::sleep_until(flush_at), if pending.is_some() => {
+                if let Some((update, received_at)) = pending.take() {
+                    apply_update(target, &exchange, update, received_at).await;
+                }
+                continue;
+            }
+            msg = next => msg,
+        };
+        // A silent socket may be dead without ever erroring, reconnect instead of waiting forever
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(idle) => {
+                exchange_log!(warn, exchange, "No message from {} in {:?}", exchange, idle);
+                return Err(ArbError::Idle { exchange, idle });
+            }
+        };
+        match msg {
+            Some(Ok(TMessage::Text(text))) => {
+                let received_at = Instant::now();
+                METRICS.messages_received.with_label_values(&[&exchange]).inc();
+                target.health.message_count.fetch_add(1, Ordering::Relaxed);
+                if let Some(recorder) = &target.recorder {
+                    recorder.record(RecordedFrame::new(&exchange, symbol, &text));
+                }
+                let value = serde_json::from_str::<Value>(&text).ok();
+                // Trades share the connection with the book updates
+                if let Some(mut trade) = value.as_ref().and_then(|v| parse_trade(v, &exchange)) {
+                    trade.symbol = symbol.to_string();
+                    // Nobody listening is not an error
+                    let _ = target.trades.send(trade);
+                }
+                let mut order_book_update = match connector.parse_update(&text) {
+                    Ok(update) => update,
+                    // The exchange wants this connection gone, close it and reconnect through the backoff loop
+                    Err(ArbError::ReconnectRequested { .. }) => {
+                        exchange_log!(info, exchange, "{} asked to reconnect", exchange);
+                        // Best effort, the connection is dropped either way
+                        let _ = ws_sink.send(TMessage::Close(None)).await;
+                        break;
+                    }
+                    // The local book drifted from the exchange's, resubscribe on a fresh connection for a new snapshot
+                    Err(e @ ArbError::ChecksumMismatch { .. }) => {
+                        exchange_log!(warn, exchange, "{}, resubscribing", e);
+                        METRICS.parse_errors.with_label_values(&[&exchange]).inc();
+                        let _ = ws_sink.send(TMessage::Close(None)).await;
+                        break;
+                    }
+                    // One bad frame, e.g. truncated by a proxy, is dropped rather than taking the connection down
+                    Err(e @ ArbError::Parse(_)) => {
+                        exchange_log!(warn, exchange, "Skipping message from {}: {}", exchange, e);
+                        METRICS.parse_errors.with_label_values(&[&exchange]).inc();
+                        continue;
+                    }
+                    Err(e) => {
+                        METRICS.parse_errors.with_label_values(&[&exchange]).inc();
+                        return Err(e);
+                    }
+                };
+                // Connectors with sequenced state may need a fresh snapshot first
+                if let Some(snapshot) = connector.resync(&target.exchange_symbol).await? {
+                    order_book_update = Some(snapshot);
+                }
+                let order_book_update = match order_book_update {
+                    Some(update) => update,
+                    None => continue,
+                };
+                // A crossed book from one exchange would corrupt the merge, drop it and wait for the next
+                if let Err(e) = order_book_update.validate_snapshot() {
+                    exchange_log!(warn, exchange, "Skipping update for {} from {}: {}", symbol, exchange, e);
+                    METRICS.parse_errors.with_label_values(&[&exchange]).inc();
+                    continue;
+                }
+                // Track how far behind the exchange's own clock this feed is
+                if let Some(event_time) = value.as_ref().and_then(|v| extract_event_time(v, &exchange)) {
+                    let average = feed_latency.push(now_millis() - event_time);
+                    METRICS.feed_latency.with_label_values(&[&exchange]).set(average);
+                }
+                match target.coalesce {
+                    Some(window) => {
+                        // Start a window on the first update, later ones just replace it
+                        if pending.is_none() {
+                            flush_at = tokio::time::Instant::now() + window;
+                        }
+                        pending = Some((order_book_update, received_at));
+                    }
+                    None => apply_update(target, &exchange, order_book_update, received_at).await,
+                }
+            }
+            Some(Err(e)) => {
+                exchange_log!(error, exchange, "Error receiving message from {}: {}", exchange, e);
+                return Err(ArbError::Connect { exchange, reason: e.to_string() });
+            }
+            // Exchanges drop connections that don't answer pings
+            Some(Ok(TMessage::Ping(payload))) => {
+                exchange_log!(debug, exchange, "Ping from {}", exchange);
+                ws_sink.send(TMessage::Pong(payload)).await
+                    .map_err(|e| ArbError::Connect { exchange: exchange.clone(), reason: e.to_string() })?;
+            }
+            Some(Ok(TMessage::Pong(_))) => exchange_log!(debug, exchange, "Pong from {}", exchange),
+            // The server is going away, reconnect through the backoff loop
+            Some(Ok(TMessage::Close(frame))) => {
+                match frame {
+                    Some(frame) => exchange_log!(info, exchange, "{} closed the connection: {} {}", exchange, frame.code, frame.reason),
+                    None => exchange_log!(info, exchange, "{} closed the connection", exchange),
+                }
+                break;
+            }
+            Some(_) => (),
+            None => break,
+        }
+    }
+
+    // Don't lose the last window when the stream ends
+    if let Some((update, received_at)) = pending.take() {
+        apply_update(target, &exchange, update, received_at).await;
+    }
+
+    Ok(())
+}
+
+// replaces this exchange's levels in the shared order book and wakes up the gRPC subscribers
+async fn apply_update(target: &FeedTarget, exchange: &str, update: OrderBook, received_at: Instant) {
+    let symbol = target.symbol.as_str();
+    let mut order_book_guard = target.order_book.lock().await;
+    let (bids, asks) = update.into_levels();
+    order_book_guard.replace_exchange_levels(exchange, target.amount_mode.to_base(bids), target.amount_mode.to_base(asks));
+    let now_ms = now_millis();
+    order_book_guard.mark_updated(exchange, now_ms);
+    let stale_exchanges = order_book_guard.stale_exchanges(now_ms, target.stale_after);
+    if !stale_exchanges.is_empty() {
+        warn!(symbol = %symbol, stale = ?stale_exchanges, "stale feeds in the merged book");
+    }
+    METRICS.book_writes.with_label_values(&[exchange]).inc();
+    if let Some(spread) = order_book_guard.spread() {
+        METRICS.spread.with_label_values(&[symbol]).set(spread.spread.to_f64().unwrap_or_default());
+    }
+    debug!(
+        exchange = %exchange,
+        symbol = %symbol,
+        bids = order_book_guard.bids().len(),
+        asks = order_book_guard.asks().len(),
+        spread = ?order_book_guard.spread().map(|spread| spread.spread),
+        levels_by_exchange = ?level_counts_by_exchange(&order_book_guard),
+        "order book updated"
+    );
+    // Build the summary once here instead of in every subscriber
+    let opportunity = order_book_guard.find_opportunity(target.opportunities.min_profit, &target.opportunities.fees, &stale_exchanges);
+    let summary = Arc::new(Summary { stale_exchanges, generated_at_ms: now_ms, ..order_book_guard.to_summary() });
+    drop(order_book_guard);
+    target.health.last_update_ms.store(now_ms, Ordering::Relaxed);
+    METRICS.update_latency.observe(received_at.elapsed().as_secs_f64());
+    target.summaries.send_replace(summary);
+    if let Some(opportunity) = opportunity {
+        info!(
+            symbol = %symbol,
+            buy = %opportunity.buy.exchange,
+            sell = %opportunity.sell.exchange,
+            gross_profit_per_unit = %opportunity.gross_profit_per_unit,
+            profit_per_unit = %opportunity.profit_per_unit,
+            amount = %opportunity.amount,
+            "arbitrage opportunity"
+        );
+        // Nobody listening is not an error
+        let _ = target.opportunities.sender.send(opportunity.to_proto(symbol, now_millis()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connectors::MockConnector;
+    use crate::tls::TlsConfig;
+    use crate::PriceLevel;
+
+    fn level(exchange: &str, price: &str, amount: &str) -> PriceLevel {
+        PriceLevel { exchange: exchange.to_string(), price: price.parse().unwrap(), amount: amount.parse().unwrap() }
+    }
+
+    // one exchange's book from (price, amount) pairs, best first
+    fn book(exchange: &str, bids: &[(&str, &str)], asks: &[(&str, &str)]) -> OrderBook {
+        let levels = |levels: &[(&str, &str)]| levels.iter().map(|(price, amount)| level(exchange, price, amount)).collect();
+        OrderBook::from_levels(levels(bids), levels(asks))
+    }
+
+    // a book and its summary channel as main sets them up
+    fn shared_book(depth: usize) -> (Arc<Mutex<OrderBook>>, SummarySender) {
+        let order_book = OrderBook::new(depth).unwrap();
+        let (summaries, _) = watch::channel(Arc::new(order_book.to_summary()));
+        (Arc::new(Mutex::new(order_book)), Arc::new(summaries))
+    }
+
+    // a feed playing connector's script into order_book
+    fn mock_feed(symbol: &str, connector: MockConnector, order_book: &Arc<Mutex<OrderBook>>, summaries: &SummarySender) -> Feed {
+        let exchange = connector.name().to_string();
+        Feed {
+            symbol: symbol.to_string(),
+            exchange_symbol: symbol.to_string(),
+            replay: Some(connector.replay_source(symbol)),
+            connector: Box::new(connector),
+            order_book: Arc::clone(order_book),
+            summaries: Arc::clone(summaries),
+            recorder: None,
+            health: Arc::new(FeedStatus::new(&exchange, symbol)),
+            stale_after: Duration::from_secs(10),
+        }
+    }
+
+    // runs the feeds until their scripts run out
+    async fn run_feeds(feeds: Vec<Feed>) -> anyhow::Result<()> {
+        let (trades, _) = broadcast::channel(TRADE_CHANNEL_CAPACITY);
+        let (sender, _) = broadcast::channel(OPPORTUNITY_CHANNEL_CAPACITY);
+        let opportunities = Opportunities { sender, min_profit: Decimal::ZERO, fees: Fees::default() };
+        let websocket = WebSocketOptions { tls: TlsConfig::default().connector()?, max_message_size: DEFAULT_MAX_MESSAGE_SIZE };
+        run(feeds, trades, opportunities, ReconnectConfig::default(), websocket, None, CancellationToken::new()).await
+    }
+
+    // where a feed of exchange writes, with nobody listening to its trades and opportunities
+    fn target(symbol: &str, exchange: &str, order_book: &Arc<Mutex<OrderBook>>, summaries: &SummarySender) -> FeedTarget {
+        let (trades, _) = broadcast::channel(TRADE_CHANNEL_CAPACITY);
+        let (sender, _) = broadcast::channel(OPPORTUNITY_CHANNEL_CAPACITY);
+        FeedTarget {
+            symbol: symbol.to_string(),
+            exchange_symbol: symbol.to_string(),
+            order_book: Arc::clone(order_book),
+            summaries: Arc::clone(summaries),
+            recorder: None,
+            health: Arc::new(FeedStatus::new(exchange, symbol)),
+            coalesce: None,
+            stale_after: Duration::from_secs(10),
+            amount_mode: AmountMode::Base,
+            trades,
+            opportunities: Opportunities { sender, min_profit: Decimal::ZERO, fees: Fees::default() },
+        }
+    }
+
+    // frames recorded from exchange for symbol, one per message
+    fn recording(exchange: &str, symbol: &str, messages: &[&str]) -> Vec<RecordedFrame> {
+        messages.iter().map(|message| RecordedFrame::new(exchange, symbol, message)).collect()
+    }
+
+    // plays messages through connector into a fresh book and returns it
+    async fn replay(connector: Box<dyn ExchangeConnector>, symbol: &str, messages: &[&str]) -> OrderBook {
+        let (order_book, summaries) = shared_book(10);
+        let exchange = connector.name().to_string();
+        let source = ReplaySource::new(&recording(&exchange, symbol, messages), &exchange, symbol, 0.0);
+        replay_exchange(connector, target(symbol, &exchange, &order_book, &summaries), source, CancellationToken::new()).await.unwrap();
+        Arc::try_unwrap(order_book).unwrap().into_inner()
+    }
+
+    #[tokio::test]
+    async fn streams_two_symbols_concurrently() {
+        let (eth_book, eth_summaries) = shared_book(10);
+        let (btc_book, btc_summaries) = shared_book(10);
+        let feeds = vec![
+            mock_feed("ETH-BTC", MockConnector::new("binance").with_updates(vec![book("binance", &[("0.061", "1")], &[("0.062", "1")])]), &eth_book, &eth_summaries),
+            mock_feed("ETH-BTC", MockConnector::new("bitstamp").with_updates(vec![book("bitstamp", &[("0.0605", "2")], &[("0.0615", "2")])]), &eth_book, &eth_summaries),
+            mock_feed("BTC-USD", MockConnector::new("binance").with_updates(vec![book("binance", &[("30000", "1")], &[("30010", "1")])]), &btc_book, &btc_summaries),
+            mock_feed("BTC-USD", MockConnector::new("bitstamp").with_updates(vec![book("bitstamp", &[("30005", "3")], &[("30020", "3")])]), &btc_book, &btc_summaries),
+        ];
+        run_feeds(feeds).await.unwrap();
+
+        let eth_book = eth_book.lock().await;
+        assert_eq!(eth_book.best_bid(), Some(&level("binance", "0.061", "1")));
+        assert_eq!(eth_book.best_ask(), Some(&level("bitstamp", "0.0615", "2")));
+        let btc_book = btc_book.lock().await;
+        assert_eq!(btc_book.best_bid(), Some(&level("bitstamp", "30005", "3")));
+        assert_eq!(btc_book.best_ask(), Some(&level("binance", "30010", "1")));
+        // each symbol's summaries only carry its own book
+        assert_eq!(eth_summaries.borrow().bids.len(), 2);
+        assert_eq!(btc_summaries.borrow().bids[0].price, 30005.0);
+    }
+
+    #[tokio::test]
+    async fn per_exchange_books_match_what_each_connector_fed() {
+        let (order_book, summaries) = shared_book(10);
+        let binance_updates = vec![
+            book("binance", &[("0.061", "1")], &[("0.063", "1")]),
+            book("binance", &[("0.0612", "2"), ("0.0611", "1")], &[("0.0620", "3")]),
+        ];
+        let bitstamp_updates = vec![book("bitstamp", &[("0.0615", "4")], &[("0.0618", "1"), ("0.0619", "2")])];
+        let fed: Vec<(&str, OrderBook)> = vec![
+            ("binance", book("binance", &[("0.0612", "2"), ("0.0611", "1")], &[("0.0620", "3")])),
+            ("bitstamp", book("bitstamp", &[("0.0615", "4")], &[("0.0618", "1"), ("0.0619", "2")])),
+        ];
+        run_feeds(vec![
+            mock_feed("ETH-BTC", MockConnector::new("binance").with_updates(binance_updates), &order_book, &summaries),
+            mock_feed("ETH-BTC", MockConnector::new("bitstamp").with_updates(bitstamp_updates), &order_book, &summaries),
+        ])
+        .await
+        .unwrap();
+
+        let mut books = order_book.lock().await.exchange_books();
+        books.sort_by(|a, b| a.exchange.cmp(&b.exchange));
+        assert_eq!(books.len(), fed.len());
+        let quotes = |levels: &[crate::orderbook::Level]| levels.iter().map(|level| (level.exchange.clone(), level.price, level.amount)).collect::<Vec<_>>();
+        for (book, (exchange, fed)) in books.iter().zip(fed) {
+            assert_eq!(book.exchange, exchange);
+            let (bids, asks) = fed.into_levels();
+            assert_eq!(quotes(&book.bids), quotes(&bids.iter().map(PriceLevel::to_proto).collect::<Vec<_>>()));
+            assert_eq!(quotes(&book.asks), quotes(&asks.iter().map(PriceLevel::to_proto).collect::<Vec<_>>()));
+            // stamped with when the feed wrote them
+            assert!(book.bids.iter().chain(&book.asks).all(|level| level.timestamp_ms > 0));
+        }
+    }
+
+    #[tokio::test]
+    async fn merges_two_exchanges_into_the_top_n() {
+        let (order_book, summaries) = shared_book(3);
+        let binance = MockConnector::new("binance").with_updates(vec![
+            book("binance", &[("0.0610", "1"), ("0.0608", "1"), ("0.0606", "1")], &[("0.0620", "1"), ("0.0622", "1")]),
+            // replaces the first update, its 0.0606 bid and 0.0622 ask are gone
+            book("binance", &[("0.0611", "2"), ("0.0608", "1")], &[("0.0619", "3"), ("0.0624", "1")]),
+        ]);
+        let bitstamp = MockConnector::new("bitstamp").with_updates(vec![
+            book("bitstamp", &[("0.0609", "4"), ("0.0607", "1"), ("0.0605", "2")], &[("0.0621", "2"), ("0.0623", "2")]),
+        ]);
+        run_feeds(vec![
+            mock_feed("ETH-BTC", binance, &order_book, &summaries),
+            mock_feed("ETH-BTC", bitstamp, &order_book, &summaries),
+        ])
+        .await
+        .unwrap();
+
+        let (bids, asks) = Arc::try_unwrap(order_book).unwrap().into_inner().into_levels();
+        assert_eq!(bids, vec![level("binance", "0.0611", "2"), level("bitstamp", "0.0609", "4"), level("binance", "0.0608", "1")]);
+        assert_eq!(asks, vec![level("binance", "0.0619", "3"), level("bitstamp", "0.0621", "2"), level("bitstamp", "0.0623", "2")]);
+        let summary = summaries.borrow();
+        assert_eq!((summary.bids.len(), summary.asks.len()), (3, 3));
+        assert!((summary.spread - 0.0008).abs() < 1e-12);
+    }
+
+    #[tokio::test]
+    async fn quote_notional_levels_merge_in_base_amounts() {
+        let (order_book, summaries) = shared_book(10);
+        // quotes 61 BTC worth at 0.061, which is 1000 ETH
+        let notional = MockConnector::new("kraken")
+            .with_amount_mode(AmountMode::QuoteNotional)
+            .with_updates(vec![book("kraken", &[("0.061", "61"), ("0.05", "0.5")], &[("0.0625", "125")])]);
+        let base = MockConnector::new("binance").with_updates(vec![book("binance", &[("0.061", "5")], &[("0.0625", "1")])]);
+        run_feeds(vec![
+            mock_feed("ETH-BTC", notional, &order_book, &summaries),
+            mock_feed("ETH-BTC", base, &order_book, &summaries),
+        ])
+        .await
+        .unwrap();
+
+        let (bids, asks) = Arc::try_unwrap(order_book).unwrap().into_inner().into_levels();
+        assert_eq!(bids, vec![level("kraken", "0.061", "1000"), level("binance", "0.061", "5"), level("kraken", "0.05", "10")]);
+        assert_eq!(asks, vec![level("kraken", "0.0625", "2000"), level("binance", "0.0625", "1")]);
+    }
+
+    #[tokio::test]
+    async fn replays_a_binance_recording_without_a_snapshot() {
+        // nothing answers on the REST endpoint, a replay must not need it
+        let book = replay(Box::new(crate::connectors::BinanceConnector::new(10)), "ethbtc", &[
+            r#"{"result":null,"id":1}"#,
+            r#"{"e":"depthUpdate","E":1,"s":"ETHBTC","U":101,"u":102,"b":[["0.061","1"],["0.060","2"]],"a":[["0.062","1"]]}"#,
+            r#"{"e":"depthUpdate","E":2,"s":"ETHBTC","U":103,"u":103,"b":[["0.060","0"]],"a":[["0.063","4"]]}"#,
+            // already covered by the previous diff
+            r#"{"e":"depthUpdate","E":3,"s":"ETHBTC","U":100,"u":102,"b":[["0.059","9"]],"a":[]}"#,
+        ]).await;
+        let (bids, asks) = book.into_levels();
+        assert_eq!(bids, vec![level("binance", "0.061", "1")]);
+        assert_eq!(asks, vec![level("binance", "0.062", "1"), level("binance", "0.063", "4")]);
+    }
+
+    #[tokio::test]
+    async fn replays_a_bitstamp_diff_recording_without_a_snapshot() {
+        let book = replay(Box::new(crate::connectors::BitstampConnector::new(10, true)), "ethbtc", &[
+            r#"{"event":"bts:subscription_succeeded","channel":"diff_order_book_ethbtc","data":{}}"#,
+            r#"{"event":"data","channel":"diff_order_book_ethbtc","data":{"microtimestamp":"1000","bids":[["0.061","1"]],"asks":[["0.062","1"]]}}"#,
+            r#"{"event":"data","channel":"diff_order_book_ethbtc","data":{"microtimestamp":"2000","bids":[["0.0615","3"]],"asks":[["0.062","0"],["0.0625","2"]]}}"#,
+        ]).await;
+        let (bids, asks) = book.into_levels();
+        assert_eq!(bids, vec![level("bitstamp", "0.0615", "3"), level("bitstamp", "0.061", "1")]);
+        assert_eq!(asks, vec![level("bitstamp", "0.0625", "2")]);
+    }
+
+    #[tokio::test]
+    async fn replays_a_bitstamp_snapshot_recording() {
+        let book = replay(Box::new(crate::connectors::BitstampConnector::new(10, false)), "ethbtc", &[
+            r#"{"event":"data","channel":"order_book_ethbtc","data":{"microtimestamp":"1000","bids":[["0.061","1"]],"asks":[["0.062","1"]]}}"#,
+            r#"{"event":"data","channel":"order_book_ethbtc","data":{"microtimestamp":"2000","bids":[["0.0612","2"]],"asks":[["0.0622","2"]]}}"#,
+        ]).await;
+        assert_eq!(book.best_bid(), Some(&level("bitstamp", "0.0612", "2")));
+        assert_eq!(book.best_ask(), Some(&level("bitstamp", "0.0622", "2")));
+        assert_eq!(book.bids().len(), 1);
+    }
+
+    // collects what a subscriber writes, for asserting on log output
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn exchange_targets_filter_connection_logs() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::new("arb::binance=info"))
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        for exchange in ["binance", "bitstamp"] {
+            let connector = MockConnector::new(exchange).with_updates(vec![book(exchange, &[("100", "1")], &[("101", "1")])]);
+            let source = connector.replay_source("ETH-BTC");
+            let (order_book, summaries) = shared_book(10);
+            replay_exchange(Box::new(connector), target("ETH-BTC", exchange, &order_book, &summaries), source, CancellationToken::new()).await.unwrap();
+        }
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("arb::binance: Replay of binance (ETH-BTC) finished"), "{}", logs);
+        assert!(!logs.contains("bitstamp"), "{}", logs);
+    }
+
+    #[tokio::test]
+    async fn every_update_of_the_stream_reaches_the_book() {
+        let book = replay(Box::new(crate::connectors::CoinbaseConnector::new(10)), "ETH-BTC", &[
+            r#"{"type":"snapshot","product_id":"ETH-BTC","bids":[["0.061","1"]],"asks":[["0.062","1"]]}"#,
+            r#"{"type":"l2update","product_id":"ETH-BTC","changes":[["buy","0.0612","2"]]}"#,
+            r#"{"type":"l2update","product_id":"ETH-BTC","changes":[["sell","0.0618","3"]]}"#,
+        ]).await;
+        let (bids, asks) = book.into_levels();
+        assert_eq!(bids, vec![level("coinbase", "0.0612", "2"), level("coinbase", "0.061", "1")]);
+        assert_eq!(asks, vec![level("coinbase", "0.0618", "3"), level("coinbase", "0.062", "1")]);
+    }
+
+    // an exchange the core loop knows nothing about, sending plain {"bids":..,"asks":..} books
+    struct FakeExchange;
+
+    #[async_trait::async_trait]
+    impl ExchangeConnector for FakeExchange {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn ws_url(&self, symbol: &str) -> String {
+            format!("ws://fake.invalid/{}", symbol)
+        }
+
+        fn subscribe_message(&self, symbol: &str) -> String {
+            format!(r#"{{"subscribe":"{}"}}"#, symbol)
+        }
+
+        fn parse_update(&mut self, text: &str) -> Result<Option<OrderBook>, ArbError> {
+            if !text.contains("bids") {
+                return Ok(None);
+            }
+            crate::parse_order_book_update(text, self.name()).map(Some)
+        }
+    }
+
+    #[tokio::test]
+    async fn drives_any_connector_through_the_generic_loop() {
+        let (order_book, summaries) = shared_book(10);
+        let mut received = summaries.subscribe();
+        let target = target("ETH-BTC", "fake", &order_book, &summaries);
+        let health = Arc::clone(&target.health);
+        let frames = recording("fake", "ETH-BTC", &[
+            r#"{"ack":true}"#,
+            r#"{"bids":[["0.061","1"]],"asks":[["0.062","1"]]}"#,
+            r#"{"bids":[["0.0611","2"]],"asks":[["0.0619","2"]]}"#,
+        ]);
+        let source = ReplaySource::new(&frames, "fake", "ETH-BTC", 0.0);
+        replay_exchange(Box::new(FakeExchange), target, source, CancellationToken::new()).await.unwrap();
+
+        let order_book = order_book.lock().await;
+        assert_eq!(order_book.best_bid(), Some(&level("fake", "0.0611", "2")));
+        assert_eq!(order_book.best_ask(), Some(&level("fake", "0.0619", "2")));
+        assert_eq!(order_book.bids().len(), 1);
+        assert_eq!(health.message_count.load(Ordering::Relaxed), 3);
+        assert!(received.has_changed().unwrap());
+        assert_eq!(received.borrow_and_update().bids[0].exchange, "fake");
+    }
+
+    fn websocket_options() -> WebSocketOptions {
+        WebSocketOptions { tls: TlsConfig::default().connector().unwrap(), max_message_size: DEFAULT_MAX_MESSAGE_SIZE }
+    }
+
+    #[tokio::test]
+    async fn malformed_urls_are_url_errors() {
+        for url in ["not a url", "ws://"] {
+            let result = connect_websocket(url, "binance", &websocket_options()).await;
+            assert!(matches!(result, Err(ArbError::Url { .. })), "{}: {:?}", url, result.err());
+        }
+    }
+
+    #[tokio::test]
+    async fn refused_connections_are_connect_errors() {
+        // nothing listens on the port of a listener that was just dropped
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let result = connect_websocket(&format!("ws://127.0.0.1:{}", port), "binance", &websocket_options()).await;
+        assert!(matches!(result, Err(ArbError::Connect { ref exchange, .. }) if exchange == "binance"), "{:?}", result.err());
+    }
+
+    // the exchange's message counter as served on /metrics, 0 before its first message
+    async fn scraped_message_count(addr: std::net::SocketAddr, exchange: &str) -> u64 {
+        let body = reqwest::get(format!("http://{}/metrics", addr)).await.unwrap().text().await.unwrap();
+        let series = format!("orderbook_messages_received_total{{exchange=\"{}\"}} ", exchange);
+        body.lines().find_map(|line| line.strip_prefix(&series)).map_or(0, |count| count.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn scraped_message_counter_follows_the_feed() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(crate::metrics::serve_metrics(addr, shutdown.clone()));
+        // the counters are process wide, a name of its own keeps other tests out of this one
+        let exchange = "scraped";
+        let mut before = None;
+        for _ in 0..50 {
+            if let Ok(response) = reqwest::get(format!("http://{}/metrics", addr)).await {
+                assert!(response.status().is_success());
+                before = Some(scraped_message_count(addr, exchange).await);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(before, Some(0));
+
+        let updates = vec![book(exchange, &[("100", "1")], &[]), book(exchange, &[("101", "1")], &[])];
+        replay(Box::new(MockConnector::new(exchange).with_updates(updates)), "ETH-BTC", &["{}", "{}"]).await;
+        assert_eq!(scraped_message_count(addr, exchange).await, 2);
+
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    // a sink whose sent messages come out of the returned receiver
+    fn captured_sink() -> (MessageSink, futures::channel::mpsc::UnboundedReceiver<TMessage>) {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        (Box::pin(sender.sink_map_err(|_| tungstenite::Error::ConnectionClosed)), receiver)
+    }
+
+    #[tokio::test]
+    async fn published_level_timestamps_never_go_back() {
+        let (order_book, summaries) = shared_book(10);
+        let mut published = summaries.subscribe();
+        let targets: HashMap<&str, FeedTarget> = ["binance", "bitstamp"].into_iter().map(|exchange| (exchange, target("ETH-BTC", exchange, &order_book, &summaries))).collect();
+        let mut last_generated_at_ms = 0;
+        let mut updated_at_ms: HashMap<String, i64> = HashMap::new();
+        for exchange in ["binance", "bitstamp", "binance", "bitstamp", "binance"] {
+            // a clock tick apart, so every summary gets a later stamp
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            apply_update(&targets[exchange], exchange, book(exchange, &[("100", "1")], &[("101", "1")]), Instant::now()).await;
+            assert!(published.has_changed().unwrap());
+            let summary = Arc::clone(&published.borrow_and_update());
+
+            assert!(summary.generated_at_ms > last_generated_at_ms);
+            last_generated_at_ms = summary.generated_at_ms;
+            updated_at_ms.insert(exchange.to_string(), summary.generated_at_ms);
+            // each level carries the time its own exchange last updated, never the other's
+            for level in summary.bids.iter().chain(&summary.asks) {
+                assert_eq!(level.timestamp_ms, updated_at_ms[&level.exchange], "{:?}", summary);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_within_one_coalesce_window_are_written_once() {
+        // labels of their own, the metrics are shared with every other test
+        let book_writes = |exchange: &str| METRICS.book_writes.with_label_values(&[exchange]).get();
+        for (exchange, coalesce, writes) in [("coalesced", Some(Duration::from_secs(60)), 1), ("uncoalesced", None, 20)] {
+            let (order_book, summaries) = shared_book(10);
+            let target = FeedTarget { coalesce, ..target("ETH-BTC", exchange, &order_book, &summaries) };
+            let updates = (1..=20).map(|i| book(exchange, &[(&i.to_string(), "1")], &[])).collect();
+            let mut connector = MockConnector::new(exchange).with_updates(updates);
+            let messages = futures::stream::iter(vec![TMessage::Text("{}".to_string()); 20]).map(Ok);
+            let (mut sink, _sent) = captured_sink();
+            let before = book_writes(exchange);
+            let result = read_updates(Box::pin(messages), &mut sink, &mut connector, &target, None, &CancellationToken::new()).await;
+
+            assert!(result.is_ok(), "{:?}", result);
+            assert_eq!(book_writes(exchange) - before, writes, "{}", exchange);
+            // either way the book ends on the last frame
+            assert_eq!(order_book.lock().await.best_bid().map(|level| level.price), Some(Decimal::from(20)));
+        }
+    }
+
+    #[tokio::test]
+    async fn pings_are_answered_and_close_ends_the_connection() {
+        let (order_book, summaries) = shared_book(10);
+        let target = target("ETH-BTC", "binance", &order_book, &summaries);
+        let mut connector = MockConnector::new("binance").with_updates(vec![book("binance", &[("100", "1")], &[])]);
+        let messages = vec![
+            Ok(TMessage::Ping(b"keepalive".to_vec())),
+            Ok(TMessage::Close(None)),
+            // never read, the connection is over
+            Ok(TMessage::Text("{}".to_string())),
+        ];
+        let (mut sink, mut sent) = captured_sink();
+        let result = read_updates(Box::pin(futures::stream::iter(messages)), &mut sink, &mut connector, &target, None, &CancellationToken::new()).await;
+
+        assert!(result.is_ok());
+        drop(sink);
+        assert_eq!(sent.next().await, Some(TMessage::Pong(b"keepalive".to_vec())));
+        assert_eq!(sent.next().await, None);
+        assert!(order_book.lock().await.best_bid().is_none());
+    }
+
+    #[tokio::test]
+    async fn malformed_frame_is_skipped_and_the_next_one_processed() {
+        let (order_book, summaries) = shared_book(10);
+        let target = target("ETH-BTC", "bitstamp", &order_book, &summaries);
+        let mut connector = crate::connectors::BitstampConnector::new(10, false);
+        let parse_errors = || METRICS.parse_errors.with_label_values(&["bitstamp"]).get();
+        let errors_before = parse_errors();
+        let messages = vec![
+            // cut short by a proxy
+            Ok(TMessage::Text(r#"{"event":"data","channel":"order_book_ethbtc","data":{"bids":[["0.06"#.to_string())),
+            Ok(TMessage::Text(r#"{"event":"data","channel":"order_book_ethbtc","data":{"bids":[["0.061","1"]],"asks":[["0.062","2"]]}}"#.to_string())),
+        ];
+        let (mut sink, _sent) = captured_sink();
+        let result = read_updates(Box::pin(futures::stream::iter(messages)), &mut sink, &mut connector, &target, None, &CancellationToken::new()).await;
+
+        assert!(result.is_ok(), "{:?}", result);
+        assert!(parse_errors() > errors_before);
+        let order_book = order_book.lock().await;
+        assert_eq!(order_book.best_bid(), Some(&level("bitstamp", "0.061", "1")));
+        assert_eq!(order_book.best_ask(), Some(&level("bitstamp", "0.062", "2")));
+    }
+
+    // a local websocket server running session on every connection it accepts, as a stand in for an exchange
+    async fn mock_exchange<F, Fut>(session: F) -> std::net::SocketAddr
+    where
+        F: Fn(tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(websocket) = tokio_tungstenite::accept_async(stream).await {
+                    tokio::spawn(session(websocket));
+                }
+            }
+        });
+        addr
+    }
+
+    // FakeExchange's plain books from a server at url, with its own idle timeout
+    struct LocalExchange {
+        url: String,
+        idle_timeout: Duration,
+    }
+
+    impl LocalExchange {
+        fn new(addr: std::net::SocketAddr) -> Self {
+            Self { url: format!("ws://{}", addr), idle_timeout: crate::connectors::DEFAULT_IDLE_TIMEOUT }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ExchangeConnector for LocalExchange {
+        fn name(&self) -> &str {
+            "local"
+        }
+
+        fn ws_url(&self, _symbol: &str) -> String {
+            self.url.clone()
+        }
+
+        fn subscribe_message(&self, symbol: &str) -> String {
+            FakeExchange.subscribe_message(symbol)
+        }
+
+        fn parse_update(&mut self, text: &str) -> Result<Option<OrderBook>, ArbError> {
+            FakeExchange.parse_update(text)
+        }
+
+        fn idle_timeout(&self) -> Duration {
+            self.idle_timeout
+        }
+    }
+
+    // retries right away, so tests don't sit through the backoff
+    fn quick_reconnect(max_retries: Option<u32>) -> ReconnectConfig {
+        ReconnectConfig { initial: Duration::from_millis(10), max: Duration::from_millis(10), multiplier: 1.0, max_retries }
+    }
+
+    fn unlimited() -> Arc<TokenBucket> {
+        Arc::new(TokenBucket::new(RateLimit { burst: 1, interval: Duration::ZERO }))
+    }
+
+    // polls condition until it holds, failing the test after a few seconds
+    async fn eventually(mut condition: impl FnMut() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition not met in time");
+    }
+
+    #[tokio::test]
+    async fn stalled_stream_is_an_idle_error() {
+        let (order_book, summaries) = shared_book(10);
+        let target = target("ETH-BTC", "binance", &order_book, &summaries);
+        let (mut sink, _sent) = captured_sink();
+        let stalled: MessageStream = Box::pin(futures::stream::pending());
+        let result = read_updates(stalled, &mut sink, &mut MockConnector::new("binance"), &target, Some(Duration::from_millis(20)), &CancellationToken::new()).await;
+        assert!(matches!(result, Err(ArbError::Idle { idle, .. }) if idle == Duration::from_millis(20)), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn silent_exchange_is_reconnected() {
+        let connections = Arc::new(AtomicU64::new(0));
+        let accepted = Arc::clone(&connections);
+        // accepts the subscription and never sends anything
+        let addr = mock_exchange(move |mut websocket| {
+            accepted.fetch_add(1, Ordering::Relaxed);
+            async move { while websocket.next().await.is_some() {} }
+        }).await;
+        let (order_book, summaries) = shared_book(10);
+        let target = target("ETH-BTC", "local", &order_book, &summaries);
+        let health = Arc::clone(&target.health);
+        let connector = LocalExchange { idle_timeout: Duration::from_millis(50), ..LocalExchange::new(addr) };
+        let shutdown = CancellationToken::new();
+        let feed = tokio::spawn(connect_to_exchange(Box::new(connector), target, quick_reconnect(None), websocket_options(), unlimited(), shutdown.clone()));
+
+        eventually(|| connections.load(Ordering::Relaxed) >= 2).await;
+        shutdown.cancel();
+        feed.await.unwrap().unwrap();
+        assert!(health.reconnects.load(Ordering::Relaxed) >= 1);
+        assert!(!health.failed.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_drops_the_connection() {
+        let connections = Arc::new(AtomicU64::new(0));
+        let accepted = Arc::clone(&connections);
+        // the first connection gets a book far above the size limit, the next ones a small book
+        let addr = mock_exchange(move |mut websocket| {
+            let first = accepted.fetch_add(1, Ordering::Relaxed) == 0;
+            async move {
+                let book = if first {
+                    let levels = vec![r#"["0.07","1"]"#; 500].join(",");
+                    format!(r#"{{"bids":[{}],"asks":[["0.08","1"]]}}"#, levels)
+                } else {
+                    r#"{"bids":[["0.061","1"]],"asks":[["0.062","1"]]}"#.to_string()
+                };
+                let _ = websocket.send(TMessage::Text(book)).await;
+                while websocket.next().await.is_some() {}
+            }
+        }).await;
+        let (order_book, summaries) = shared_book(10);
+        let target = target("ETH-BTC", "local", &order_book, &summaries);
+        let health = Arc::clone(&target.health);
+        let websocket = WebSocketOptions { max_message_size: 1024, ..websocket_options() };
+        let shutdown = CancellationToken::new();
+        let feed = tokio::spawn(connect_to_exchange(Box::new(LocalExchange::new(addr)), target, quick_reconnect(None), websocket, unlimited(), shutdown.clone()));
+
+        eventually(|| order_book.try_lock().is_ok_and(|order_book| order_book.best_bid().is_some())).await;
+        shutdown.cancel();
+        feed.await.unwrap().unwrap();
+        assert!(connections.load(Ordering::Relaxed) >= 2);
+        assert!(health.reconnects.load(Ordering::Relaxed) >= 1);
+        // nothing of the oversized book made it in
+        let order_book = order_book.lock().await;
+        assert_eq!(order_book.best_bid(), Some(&level("fake", "0.061", "1")));
+        assert_eq!(order_book.best_ask(), Some(&level("fake", "0.062", "1")));
+    }
+
+    #[tokio::test]
+    async fn connection_attempts_are_spaced_by_the_rate_limit() {
+        let attempts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let accepted = Arc::clone(&attempts);
+        // hangs up right away, so the feed keeps reconnecting
+        let addr = mock_exchange(move |websocket| {
+            accepted.lock().unwrap().push(Instant::now());
+            async move { drop(websocket) }
+        }).await;
+        let (order_book, summaries) = shared_book(10);
+        let target = target("ETH-BTC", "local", &order_book, &summaries);
+        let interval = Duration::from_millis(100);
+        let connects = Arc::new(TokenBucket::new(RateLimit { burst: 1, interval }));
+        let shutdown = CancellationToken::new();
+        let feed = tokio::spawn(connect_to_exchange(Box::new(LocalExchange::new(addr)), target, quick_reconnect(None), websocket_options(), connects, shutdown.clone()));
+
+        eventually(|| attempts.lock().unwrap().len() >= 4).await;
+        shutdown.cancel();
+        feed.await.unwrap().unwrap();
+        let attempts = attempts.lock().unwrap();
+        for pair in attempts.windows(2) {
+            // a little slack for when the server notices the connection
+            let gap = pair[1].duration_since(pair[0]);
+            assert!(gap >= interval - Duration::from_millis(10), "attempts {:?} apart", gap);
+        }
+    }
+
+    #[tokio::test]
+    async fn three_reconnects_are_counted() {
+        let connections = Arc::new(AtomicU64::new(0));
+        let accepted = Arc::clone(&connections);
+        // hangs up on the first three connections, then streams
+        let addr = mock_exchange(move |mut websocket| {
+            let connection = accepted.fetch_add(1, Ordering::Relaxed);
+            async move {
+                if connection < 3 {
+                    return;
+                }
+                let book = r#"{"bids":[["0.061","1"]],"asks":[["0.062","1"]]}"#;
+                let _ = websocket.send(TMessage::Text(book.to_string())).await;
+                while websocket.next().await.is_some() {}
+            }
+        }).await;
+        let (order_book, summaries) = shared_book(10);
+        let target = target("ETH-BTC", "local", &order_book, &summaries);
+        let health = Arc::clone(&target.health);
+        let shutdown = CancellationToken::new();
+        let feed = tokio::spawn(connect_to_exchange(Box::new(LocalExchange::new(addr)), target, quick_reconnect(None), websocket_options(), unlimited(), shutdown.clone()));
+
+        eventually(|| !summaries.borrow().bids.is_empty()).await;
+        shutdown.cancel();
+        feed.await.unwrap().unwrap();
+        assert_eq!(connections.load(Ordering::Relaxed), 4);
+        assert_eq!(health.reconnects.load(Ordering::Relaxed), 3);
+        assert_eq!(health.to_proto().reconnects, 3);
+        assert_eq!(summaries.borrow().reconnects.get("local"), Some(&3));
+    }
+
+    #[tokio::test]
+    async fn unresolvable_hosts_are_connect_errors() {
+        let result = connect_websocket("wss://nonexistent.invalid/ws", "bitstamp", &websocket_options()).await;
+        assert!(matches!(result, Err(ArbError::Connect { ref exchange, .. }) if exchange == "bitstamp"), "{:?}", result.err());
+    }
+
+    #[tokio::test]
+    async fn bitstamp_reconnect_request_closes_the_connection() {
+        let (order_book, summaries) = shared_book(10);
+        let target = target("ETH-BTC", "bitstamp", &order_book, &summaries);
+        let book = |price: &str| format!(r#"{{"event":"data","channel":"order_book_ethbtc","data":{{"bids":[["{}","1"]],"asks":[]}}}}"#, price);
+        let messages = vec![
+            Ok(TMessage::Text(book("0.061"))),
+            Ok(TMessage::Text(r#"{"event":"bts:request_reconnect","channel":"","data":""}"#.to_string())),
+            Ok(TMessage::Text(book("0.062"))),
+        ];
+        let (mut sink, mut sent) = captured_sink();
+        let mut connector = crate::connectors::BitstampConnector::new(10, false);
+        let result = read_updates(Box::pin(futures::stream::iter(messages)), &mut sink, &mut connector, &target, None, &CancellationToken::new()).await;
+
+        // a clean end, which connect_to_exchange follows with a new connection
+        assert!(result.is_ok());
+        drop(sink);
+        assert_eq!(sent.next().await, Some(TMessage::Close(None)));
+        assert_eq!(order_book.lock().await.best_bid(), Some(&level("bitstamp", "0.061", "1")));
+    }
+
+    // what await_subscription makes of connector's subscription to ethbtc being answered with messages
+    async fn subscribe(connector: &mut dyn ExchangeConnector, messages: &[&str]) -> Result<(), ArbError> {
+        let messages: Vec<_> = messages.iter().map(|text| TMessage::Text(text.to_string())).collect();
+        let mut stream: MessageStream = Box::pin(futures::stream::iter(messages).map(Ok));
+        let (mut sink, _sent) = captured_sink();
+        await_subscription(&mut stream, &mut sink, connector, "ethbtc").await
+    }
+
+    #[tokio::test]
+    async fn bitstamp_subscription_is_confirmed_or_fails_fast() {
+        let mut connector = crate::connectors::BitstampConnector::new(10, false);
+        let confirmed = subscribe(&mut connector, &[
+            r#"{"event":"bts:heartbeat"}"#,
+            r#"{"event":"bts:subscription_succeeded","channel":"order_book_ethbtc","data":{}}"#,
+        ]).await;
+        assert!(confirmed.is_ok());
+
+        let rejected = subscribe(&mut connector, &[r#"{"event":"bts:error","channel":"","data":{"message":"Bad subscription string."}}"#]).await;
+        assert!(matches!(rejected, Err(ArbError::SubscriptionRejected { ref reason, .. }) if reason == "Bad subscription string."), "{:?}", rejected);
+
+        // closed before any answer
+        assert!(matches!(subscribe(&mut connector, &[]).await, Err(ArbError::Subscribe { .. })));
+    }
+
+    #[tokio::test]
+    async fn binance_subscription_is_confirmed_or_fails_fast() {
+        let mut connector = crate::connectors::BinanceConnector::new(10);
+        assert!(subscribe(&mut connector, &[r#"{"result":null,"id":1}"#]).await.is_ok());
+        let rejected = subscribe(&mut connector, &[r#"{"error":{"code":2,"msg":"Invalid request"},"id":1}"#]).await;
+        assert!(matches!(rejected, Err(ArbError::SubscriptionRejected { ref exchange, ref reason }) if exchange == "binance" && reason == "Invalid request"), "{:?}", rejected);
+    }
+
+    // Bitstamp's subscription handling against a server at url
+    struct LocalBitstamp {
+        url: String,
+        bitstamp: crate::connectors::BitstampConnector,
+    }
+
+    #[async_trait::async_trait]
+    impl ExchangeConnector for LocalBitstamp {
+        fn name(&self) -> &str {
+            self.bitstamp.name()
+        }
+
+        fn ws_url(&self, _symbol: &str) -> String {
+            self.url.clone()
+        }
+
+        fn subscribe_message(&self, symbol: &str) -> String {
+            self.bitstamp.subscribe_message(symbol)
+        }
+
+        fn subscription_timeout(&self) -> Option<Duration> {
+            self.bitstamp.subscription_timeout()
+        }
+
+        fn subscription_reply(&self, symbol: &str, v: &Value) -> crate::connectors::SubscriptionReply {
+            self.bitstamp.subscription_reply(symbol, v)
+        }
+
+        fn parse_update(&mut self, text: &str) -> Result<Option<OrderBook>, ArbError> {
+            self.bitstamp.parse_update(text)
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_subscription_leaves_the_other_feed_streaming() {
+        let rejecting = mock_exchange(|mut websocket| async move {
+            websocket.next().await;
+            let error = r#"{"event":"bts:error","channel":"","data":{"code":null,"message":"Bad subscription string."}}"#;
+            let _ = websocket.send(TMessage::Text(error.to_string())).await;
+            while websocket.next().await.is_some() {}
+        }).await;
+        // a new book every few milliseconds for as long as the connection lasts
+        let streaming = mock_exchange(|mut websocket| async move {
+            for amount in 1.. {
+                let book = format!(r#"{{"bids":[["0.061","{}"]],"asks":[["0.062","1"]]}}"#, amount);
+                if websocket.send(TMessage::Text(book)).await.is_err() {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await;
+
+        let (order_book, summaries) = shared_book(10);
+        let feed = |connector: Box<dyn ExchangeConnector>| Feed {
+            symbol: "ETH-BTC".to_string(),
+            exchange_symbol: "ethbtc".to_string(),
+            health: Arc::new(FeedStatus::new(connector.name(), "ETH-BTC")),
+            connector,
+            order_book: Arc::clone(&order_book),
+            summaries: Arc::clone(&summaries),
+            replay: None,
+            recorder: None,
+            stale_after: Duration::from_secs(10),
+        };
+        let bitstamp = feed(Box::new(LocalBitstamp { url: format!("ws://{}", rejecting), bitstamp: crate::connectors::BitstampConnector::new(10, false) }));
+        let local = feed(Box::new(LocalExchange::new(streaming)));
+        let (bitstamp_health, local_health) = (Arc::clone(&bitstamp.health), Arc::clone(&local.health));
+
+        let (trades, _) = broadcast::channel(TRADE_CHANNEL_CAPACITY);
+        let (sender, _) = broadcast::channel(OPPORTUNITY_CHANNEL_CAPACITY);
+        let opportunities = Opportunities { sender, min_profit: Decimal::ZERO, fees: Fees::default() };
+        let shutdown = CancellationToken::new();
+        let run = tokio::spawn(run(vec![bitstamp, local], trades, opportunities, quick_reconnect(None), websocket_options(), None, shutdown.clone()));
+
+        eventually(|| bitstamp_health.failed.load(Ordering::Relaxed)).await;
+        // and the other exchange still delivers afterwards
+        let delivered = local_health.message_count.load(Ordering::Relaxed);
+        eventually(|| local_health.message_count.load(Ordering::Relaxed) > delivered + 2).await;
+        assert!(local_health.connected.load(Ordering::Relaxed) && !local_health.failed.load(Ordering::Relaxed));
+        assert!(!run.is_finished());
+
+        shutdown.cancel();
+        run.await.unwrap().unwrap();
+        assert!(order_book.lock().await.best_bid().is_some_and(|bid| bid.exchange == "fake"));
+    }
+
+    #[tokio::test]
+    async fn quiet_feed_is_flagged_stale() {
+        let (order_book, summaries) = shared_book(10);
+        let received = summaries.subscribe();
+        {
+            let mut book = order_book.lock().await;
+            book.replace_exchange_levels("bitstamp", vec![level("bitstamp", "0.0605", "1")], vec![level("bitstamp", "0.0600", "1")]);
+            // last heard of a minute ago
+            book.mark_updated("bitstamp", now_millis() - 60_000);
+        }
+        let target = target("ETH-BTC", "binance", &order_book, &summaries);
+        let update = book("binance", &[("0.0610", "1")], &[("0.0620", "1")]);
+        apply_update(&target, "binance", update, Instant::now()).await;
+
+        let summary = Arc::clone(&received.borrow());
+        assert_eq!(summary.stale_exchanges, ["bitstamp"]);
+        // its levels are still shown, only flagged
+        assert!(summary.bids.iter().any(|level| level.exchange == "bitstamp"));
+    }
+
+    #[tokio::test]
+    async fn failing_exchange_gives_up_after_max_retries() {
+        let unreachable = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (order_book, summaries) = shared_book(10);
+        let target = target("ETH-BTC", "local", &order_book, &summaries);
+        let health = Arc::clone(&target.health);
+        let feed = connect_to_exchange(Box::new(LocalExchange::new(unreachable)), target, quick_reconnect(Some(2)), websocket_options(), unlimited(), CancellationToken::new());
+        let result = tokio::time::timeout(Duration::from_secs(5), feed).await.expect("the feed gives up");
+
+        assert!(matches!(result, Err(ArbError::Connect { .. })), "{:?}", result);
+        assert!(health.failed.load(Ordering::Relaxed));
+        // the first attempt and two retries
+        assert_eq!(health.reconnects.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,1784 @@
+// order book types shared by the server binary and the benchmarks: the merged book,
+// its price levels and the generic exchange message parser, plus the exchange feeds
+// and the gRPC service so other crates can embed the aggregator
+
+pub mod connectors;
+pub mod feed;
+pub mod metrics;
+pub mod recording;
+pub mod server;
+pub mod tls;
+pub mod tui;
+pub mod webhook;
+
+use std::collections::{BTreeMap, HashMap};
+use std::cmp::Reverse;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rust_decimal::{Decimal, RoundingStrategy};
+use rust_decimal::prelude::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+use orderbook::{ExchangeBook, Summary, SummaryDelta, Level, Opportunity};
+
+// generated gRPC types and service traits
+pub mod orderbook {
+    tonic::include_proto!("orderbook"); 
+
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("orderbook_descriptor");
+}
+
+// errors raised while connecting to exchanges and parsing their messages
+#[derive(Debug, thiserror::Error)]
+pub enum ArbError {
+    #[error("invalid url {url}: {reason}")]
+    Url { url: String, reason: String },
+    #[error("failed to connect to {exchange}: {reason}")]
+    Connect { exchange: String, reason: String },
+    #[error("TLS error with {exchange}: {reason}")]
+    Tls { exchange: String, reason: String },
+    #[error("failed to subscribe to {exchange}: {reason}")]
+    Subscribe { exchange: String, reason: String },
+    #[error("{exchange} rejected the subscription: {reason}")]
+    SubscriptionRejected { exchange: String, reason: String },
+    #[error("failed to parse message: {0}")]
+    Parse(String),
+    #[error("gap in {exchange} update sequence: expected update {expected}, got {got}")]
+    SequenceGap { exchange: String, expected: u64, got: u64 },
+    #[error("{exchange} does not list {symbol}")]
+    UnsupportedSymbol { exchange: String, symbol: String },
+    #[error("{exchange} sent nothing for {idle:?}")]
+    Idle { exchange: String, idle: Duration },
+    #[error("{exchange} asked to reconnect")]
+    ReconnectRequested { exchange: String },
+    #[error("{exchange} sent a crossed book: bid {bid} is not below ask {ask}")]
+    CrossedBook { exchange: String, bid: Decimal, ask: Decimal },
+    #[error("{exchange} book is out of sync: checksum {expected} does not match the local book")]
+    ChecksumMismatch { exchange: String, expected: u32 },
+}
+
+impl From<serde_json::Error> for ArbError {
+    fn from(e: serde_json::Error) -> Self {
+        ArbError::Parse(e.to_string())
+    }
+}
+
+// internal price level, kept in decimal to avoid float rounding and NaN when sorting.
+// decimals serialize as strings so nothing is lost through JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PriceLevel {
+    pub exchange: String,
+    pub price: Decimal,
+    pub amount: Decimal,
+}
+
+impl PriceLevel {
+    // converts to the proto level at the gRPC boundary
+    pub fn to_proto(&self) -> Level {
+        Level {
+            exchange: self.exchange.clone(),
+            price: self.price.to_f64().unwrap_or_default(),
+            amount: self.amount.to_f64().unwrap_or_default(),
+            // only the book knows when the exchange sent it
+            timestamp_ms: 0,
+        }
+    }
+}
+
+// what the amounts of an exchange's levels measure. the merged book keeps base asset amounts so
+// levels of different exchanges add up and compare
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AmountMode {
+    // units of the base asset, ETH in ETH-BTC
+    #[default]
+    Base,
+    // value in the quote currency, price times the base amount
+    QuoteNotional,
+}
+
+impl AmountMode {
+    // the levels with their amounts in the base asset. a notional at a zero price has no base
+    // amount and comes out as zero, which removes the level
+    pub fn to_base(self, levels: Vec<PriceLevel>) -> Vec<PriceLevel> {
+        match self {
+            AmountMode::Base => levels,
+            AmountMode::QuoteNotional => levels
+                .into_iter()
+                .map(|level| PriceLevel { amount: level.amount.checked_div(level.price).unwrap_or_default(), ..level })
+                .collect(),
+        }
+    }
+}
+
+// decimals a symbol's prices and amounts are shown with in summaries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PriceFormat {
+    pub price_decimals: u32,
+    pub amount_decimals: u32,
+}
+
+impl Default for PriceFormat {
+    fn default() -> Self {
+        Self { price_decimals: 8, amount_decimals: 8 }
+    }
+}
+
+impl PriceFormat {
+    // fiat and stablecoin quoted pairs tick in cents, crypto quoted pairs need the full precision
+    pub fn for_symbol(symbol: &str) -> Self {
+        match symbol.rsplit_once('-').map(|(_, quote)| quote) {
+            Some("USD" | "USDT" | "USDC" | "EUR" | "GBP") => Self { price_decimals: 2, amount_decimals: 8 },
+            _ => Self::default(),
+        }
+    }
+
+    // prices round to the nearest tick, amounts round down so a level never shows more than it holds
+    pub fn to_proto(&self, level: &PriceLevel) -> Level {
+        PriceLevel {
+            exchange: level.exchange.clone(),
+            price: level.price.round_dp_with_strategy(self.price_decimals, RoundingStrategy::MidpointAwayFromZero),
+            amount: level.amount.round_dp_with_strategy(self.amount_decimals, RoundingStrategy::ToZero),
+        }
+        .to_proto()
+    }
+}
+
+// number of levels kept per side unless configured otherwise, and the allowed range
+pub const DEFAULT_DEPTH: usize = 10;
+pub const MAX_DEPTH: usize = 100;
+
+// spread between the best bid and ask, which may come from different exchanges.
+// a crossed book (best bid above best ask) is an arbitrage opportunity between the two venues
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpreadInfo {
+    pub spread: Decimal,
+    pub crossed: bool,
+    pub best_bid_exchange: String,
+    pub best_ask_exchange: String,
+}
+
+// serializable mirror of an OrderBook, the spread is recomputed when the book is rebuilt
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderBookSnapshot {
+    pub max_depth: usize,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+impl From<&OrderBook> for OrderBookSnapshot {
+    fn from(book: &OrderBook) -> Self {
+        Self {
+            max_depth: book.max_depth,
+            bids: book.bids.iter().cloned().collect(),
+            asks: book.asks.iter().cloned().collect(),
+        }
+    }
+}
+
+impl TryFrom<OrderBookSnapshot> for OrderBook {
+    type Error = ArbError;
+
+    fn try_from(snapshot: OrderBookSnapshot) -> Result<Self, ArbError> {
+        let mut book = OrderBook::new(snapshot.max_depth).map_err(|e| ArbError::Parse(e.to_string()))?;
+        book.merge_and_sort(snapshot.bids, snapshot.asks);
+        Ok(book)
+    }
+}
+
+// the book as JSON, see OrderBookSnapshot for the layout
+pub fn to_json(book: &OrderBook) -> String {
+    serde_json::to_string(&OrderBookSnapshot::from(book)).expect("order book serializes")
+}
+
+// rebuilds a book written by to_json
+pub fn from_json(json: &str) -> Result<OrderBook, ArbError> {
+    serde_json::from_str::<OrderBookSnapshot>(json)?.try_into()
+}
+
+// writes the book next to its final path first so a crash never leaves a truncated snapshot
+pub fn save_snapshot(book: &OrderBook, path: &Path) -> std::io::Result<()> {
+    let partial = path.with_extension("json.partial");
+    std::fs::write(&partial, to_json(book))?;
+    std::fs::rename(partial, path)
+}
+
+// the book saved at path and when it was saved, in milliseconds since the epoch, unless it is
+// missing, unreadable or older than max_age
+pub fn load_snapshot(path: &Path, max_age: Duration) -> Option<(OrderBook, i64)> {
+    let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()?;
+    let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+    if age > max_age {
+        warn!("Ignoring snapshot {}: {:?} old, older than {:?}", path.display(), age, max_age);
+        return None;
+    }
+    let json = std::fs::read_to_string(path)
+        .inspect_err(|e| warn!("Failed to read snapshot {}: {}", path.display(), e))
+        .ok()?;
+    let book = from_json(&json)
+        .inspect_err(|e| warn!("Ignoring snapshot {}: {}", path.display(), e))
+        .ok()?;
+    let saved_at_ms = modified.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_millis() as i64).unwrap_or_default();
+    Some((book, saved_at_ms))
+}
+
+//initiate the orderbook struct
+#[derive(Debug)]
+pub struct OrderBook {
+    bids: Bids,
+    asks: Asks,
+    spread: Option<SpreadInfo>,
+    // levels kept per side, the statistics of the summary cover all of them
+    max_depth: usize,
+    // levels per side the summary lists, None lists every kept level
+    output_depth: Option<usize>,
+    // levels smaller than this are dust and never enter the book
+    min_amount: Decimal,
+    // rounding applied to the levels of the summary
+    price_format: PriceFormat,
+    // whether max_depth applies to each exchange rather than to the merged book
+    depth_per_exchange: bool,
+    // moving average of the spread, None until both sides had a level
+    spread_ema: Option<f64>,
+    spread_ema_alpha: f64,
+    // when each exchange last updated its levels, in milliseconds since the epoch
+    updated_at_ms: BTreeMap<String, i64>,
+    // the latest bids and asks of each exchange as it sent them, in the book's quote currency, before merging
+    exchange_levels: BTreeMap<String, (Vec<PriceLevel>, Vec<PriceLevel>)>,
+    // how often each exchange's feed reconnected, reported in the summaries
+    reconnects: BTreeMap<String, u64>,
+    // multiplies an exchange's prices as they come in, converting the currency it quotes in to the book's
+    price_factors: HashMap<String, Decimal>,
+}
+
+// orders one side of the book so that the best price sorts first
+pub trait PriceKey: Ord + Copy + std::fmt::Debug {
+    fn from_price(price: Decimal) -> Self;
+}
+
+impl PriceKey for Decimal {
+    fn from_price(price: Decimal) -> Self {
+        price
+    }
+}
+
+impl PriceKey for Reverse<Decimal> {
+    fn from_price(price: Decimal) -> Self {
+        Reverse(price)
+    }
+}
+
+// where a level sorts on its side: best price first, then the larger amount, then by exchange name
+// so levels tied on price and amount still come out in the same order on every run
+type LevelKey<K> = (K, Reverse<Decimal>, String);
+
+// one side of a book kept sorted by (price, amount, exchange) as levels come in, so an update is
+// O(log n) instead of a full sort and the top of the book is just the first entries
+#[derive(Debug, Clone)]
+pub struct BookSide<K: PriceKey> {
+    levels: BTreeMap<LevelKey<K>, PriceLevel>,
+}
+
+// highest price first
+pub type Bids = BookSide<Reverse<Decimal>>;
+// lowest price first
+pub type Asks = BookSide<Decimal>;
+
+impl<K: PriceKey> Default for BookSide<K> {
+    fn default() -> Self {
+        Self { levels: BTreeMap::new() }
+    }
+}
+
+impl<K: PriceKey> BookSide<K> {
+    // the key of exchange's level at price, found among the few levels sharing that price
+    fn key_of(&self, price: Decimal, exchange: &str) -> Option<LevelKey<K>> {
+        let price = K::from_price(price);
+        self.levels
+            .range((price, Reverse(Decimal::MAX), String::new())..)
+            .take_while(|((level_price, _, _), _)| *level_price == price)
+            .find(|((_, _, level_exchange), _)| level_exchange == exchange)
+            .map(|(key, _)| key.clone())
+    }
+
+    // adds to the amount already quoted by the same exchange at the same price
+    pub fn insert(&mut self, mut level: PriceLevel) {
+        if let Some(key) = self.key_of(level.price, &level.exchange) {
+            if let Some(existing) = self.levels.remove(&key) {
+                level.amount += existing.amount;
+            }
+        }
+        self.levels.insert((K::from_price(level.price), Reverse(level.amount), level.exchange.clone()), level);
+    }
+
+    // like insert, except a zero amount is how exchanges signal a removed price,
+    // so it deletes that exchange's level instead of being inserted
+    pub fn apply(&mut self, level: PriceLevel) {
+        if level.amount.is_zero() {
+            if let Some(key) = self.key_of(level.price, &level.exchange) {
+                self.levels.remove(&key);
+            }
+        } else {
+            self.insert(level);
+        }
+    }
+
+    pub fn remove_exchange(&mut self, exchange: &str) {
+        self.levels.retain(|(_, _, level_exchange), _| level_exchange != exchange);
+    }
+
+    // drops the worst levels beyond `depth`
+    pub fn truncate(&mut self, depth: usize) {
+        while self.levels.len() > depth {
+            self.levels.pop_last();
+        }
+    }
+
+    // drops each exchange's levels beyond its best `depth`, so a venue quoting many better prices
+    // can't push another one out of the book
+    pub fn truncate_per_exchange(&mut self, depth: usize) {
+        let mut kept: HashMap<String, usize> = HashMap::new();
+        self.levels.retain(|(_, _, exchange), _| {
+            let count = kept.entry(exchange.clone()).or_default();
+            *count += 1;
+            *count <= depth
+        });
+    }
+
+    pub fn first(&self) -> Option<&PriceLevel> {
+        self.levels.values().next()
+    }
+
+    // best price first
+    pub fn iter(&self) -> impl Iterator<Item = &PriceLevel> + Clone {
+        self.levels.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    pub fn into_vec(self) -> Vec<PriceLevel> {
+        self.levels.into_values().collect()
+    }
+}
+
+impl<K: PriceKey> FromIterator<PriceLevel> for BookSide<K> {
+    fn from_iter<I: IntoIterator<Item = PriceLevel>>(levels: I) -> Self {
+        let mut side = Self::default();
+        for level in levels {
+            side.insert(level);
+        }
+        side
+    }
+}
+
+// size available across the given levels, zero if it is more than a Decimal holds
+pub fn total_amount<'a>(levels: impl IntoIterator<Item = &'a PriceLevel>) -> Decimal {
+    levels.into_iter().try_fold(Decimal::ZERO, |total, level| total.checked_add(level.amount)).unwrap_or_default()
+}
+
+// volume weighted average price over the given levels, zero for an empty side or a notional that
+// overflows a Decimal
+pub fn vwap<'a>(levels: impl IntoIterator<Item = &'a PriceLevel> + Clone) -> Decimal {
+    let total_amount = total_amount(levels.clone());
+    if total_amount.is_zero() {
+        return Decimal::ZERO;
+    }
+    levels
+        .into_iter()
+        .try_fold(Decimal::ZERO, |notional, level| notional.checked_add(level.price.checked_mul(level.amount)?))
+        .and_then(|notional| notional.checked_div(total_amount))
+        .unwrap_or_default()
+}
+
+// halfway between the best bid and the best ask, None while either side is empty
+fn mid(book: &OrderBook) -> Option<Decimal> {
+    Some(book.best_bid()?.price.checked_add(book.best_ask()?.price)? / Decimal::TWO)
+}
+
+pub fn mid_price(book: &OrderBook) -> Option<f64> {
+    mid(book)?.to_f64()
+}
+
+// the mid weighted by the size at the top of the book, (best bid * ask amount + best ask * bid amount) /
+// (bid amount + ask amount), so it leans towards the thinner side. None while either side is empty
+// or both best levels have no amount
+pub fn micro_price(book: &OrderBook) -> Option<f64> {
+    let (best_bid, best_ask) = (book.best_bid()?, book.best_ask()?);
+    let total_amount = best_bid.amount.checked_add(best_ask.amount)?;
+    if total_amount.is_zero() {
+        return None;
+    }
+    let weighted = best_bid.price.checked_mul(best_ask.amount)?.checked_add(best_ask.price.checked_mul(best_bid.amount)?)?;
+    weighted.checked_div(total_amount)?.to_f64()
+}
+
+// one step of an exponential moving average, alpha is the weight of the new sample. written as a step
+// towards the sample so a repeated sample leaves the average exactly where it is
+pub fn update_ema(prev: f64, sample: f64, alpha: f64) -> f64 {
+    prev + alpha * (sample - prev)
+}
+
+// weight of the newest spread in the spread EMA unless configured otherwise
+pub const DEFAULT_SPREAD_EMA_ALPHA: f64 = 0.1;
+
+// best ask minus best bid relative to the mid price, in basis points so symbols at different
+// price levels compare. zero for an empty side, a zero mid or one a Decimal can't hold
+pub fn spread_bps(book: &OrderBook) -> f64 {
+    let (Some(best_bid), Some(best_ask), Some(mid_price)) = (book.best_bid(), book.best_ask(), mid(book)) else {
+        return 0.0;
+    };
+    if mid_price.is_zero() {
+        return 0.0;
+    }
+    (best_ask.price - best_bid.price)
+        .checked_div(mid_price)
+        .and_then(|relative| relative.checked_mul(Decimal::from(10_000)))
+        .and_then(|bps| bps.to_f64())
+        .unwrap_or_default()
+}
+
+// how many of the merged book's levels, bids and asks together, each exchange contributes
+pub fn level_counts_by_exchange(book: &OrderBook) -> HashMap<String, usize> {
+    let mut counts = HashMap::new();
+    for level in book.bids.iter().chain(book.asks.iter()) {
+        *counts.entry(level.exchange.clone()).or_insert(0) += 1;
+    }
+    counts
+}
+
+// (bid volume - ask volume) / (bid volume + ask volume) over the book's levels, in [-1, 1].
+// positive leans towards buyers, zero for an empty book or totals a Decimal can't hold
+pub fn imbalance(book: &OrderBook) -> f64 {
+    let bid_total = total_amount(book.bids.iter());
+    let ask_total = total_amount(book.asks.iter());
+    let Some(total) = bid_total.checked_add(ask_total).filter(|total| !total.is_zero()) else {
+        return 0.0;
+    };
+    ((bid_total - ask_total) / total).to_f64().unwrap_or_default()
+}
+
+impl OrderBook {
+    pub fn new(max_depth: usize) -> anyhow::Result<Self> {
+        if !(1..=MAX_DEPTH).contains(&max_depth) {
+            return Err(anyhow::anyhow!("depth must be between 1 and {}, got {}", MAX_DEPTH, max_depth));
+        }
+        Ok(Self {
+            bids: Bids::default(),
+            asks: Asks::default(),
+            spread: None,
+            max_depth,
+            min_amount: Decimal::ZERO,
+            price_format: PriceFormat::default(),
+            depth_per_exchange: false,
+            output_depth: None,
+            spread_ema: None,
+            spread_ema_alpha: DEFAULT_SPREAD_EMA_ALPHA,
+            updated_at_ms: BTreeMap::new(),
+            exchange_levels: BTreeMap::new(),
+            reconnects: BTreeMap::new(),
+            price_factors: HashMap::new(),
+        })
+    }
+
+    // drops levels with less than min_amount on merge, zero keeps everything
+    pub fn with_min_amount(mut self, min_amount: Decimal) -> Self {
+        self.min_amount = min_amount;
+        self
+    }
+
+    // rounds the summary levels to price_format, the book itself keeps full precision
+    pub fn with_price_format(mut self, price_format: PriceFormat) -> Self {
+        self.price_format = price_format;
+        self
+    }
+
+    // lists only the best depth levels of each side in the summary while keeping max_depth of them for
+    // its VWAP, totals and imbalance. a depth at or above max_depth lists them all
+    pub fn with_output_depth(mut self, depth: usize) -> Self {
+        self.output_depth = Some(depth);
+        self
+    }
+
+    // keeps max_depth levels of every exchange on merge instead of max_depth levels overall
+    pub fn with_depth_per_exchange(mut self, depth_per_exchange: bool) -> Self {
+        self.depth_per_exchange = depth_per_exchange;
+        self
+    }
+
+    // multiplies every price exchange sends by factor before merging, e.g. the USDT/USD rate for a
+    // USD book fed with USDT quotes, so its levels sort and cross against the others' in one currency
+    pub fn with_price_factor(mut self, exchange: &str, factor: Decimal) -> Self {
+        self.price_factors.insert(exchange.to_string(), factor);
+        self
+    }
+
+    // weight of each new spread in the spread EMA, between 0 (never moves) and 1 (the raw spread)
+    pub fn with_spread_ema_alpha(mut self, alpha: f64) -> Self {
+        self.spread_ema_alpha = alpha;
+        self
+    }
+
+    // the smoothed spread, None while a side of the book is empty
+    pub fn spread_ema(&self) -> Option<f64> {
+        self.spread_ema
+    }
+
+    // wraps levels parsed from an exchange message
+    pub fn from_levels(bids: Vec<PriceLevel>, asks: Vec<PriceLevel>) -> Self {
+        Self {
+            bids: bids.into_iter().collect(),
+            asks: asks.into_iter().collect(),
+            spread: None,
+            max_depth: DEFAULT_DEPTH,
+            min_amount: Decimal::ZERO,
+            price_format: PriceFormat::default(),
+            depth_per_exchange: false,
+            output_depth: None,
+            spread_ema: None,
+            spread_ema_alpha: DEFAULT_SPREAD_EMA_ALPHA,
+            updated_at_ms: BTreeMap::new(),
+            exchange_levels: BTreeMap::new(),
+            reconnects: BTreeMap::new(),
+            price_factors: HashMap::new(),
+        }
+    }
+
+    pub fn bids(&self) -> &Bids {
+        &self.bids
+    }
+
+    pub fn asks(&self) -> &Asks {
+        &self.asks
+    }
+
+    // None until both sides have a level
+    pub fn spread(&self) -> Option<&SpreadInfo> {
+        self.spread.as_ref()
+    }
+
+    // highest bid across all exchanges, with the exchange quoting it
+    pub fn best_bid(&self) -> Option<&PriceLevel> {
+        self.bids.first()
+    }
+
+    // lowest ask across all exchanges, with the exchange quoting it
+    pub fn best_ask(&self) -> Option<&PriceLevel> {
+        self.asks.first()
+    }
+
+    // None while either side is empty, a book without a bid or an ask has no spread rather than a zero one
+    pub fn calculate_spread(&mut self) -> Option<&SpreadInfo> {
+        self.spread = match (self.best_bid(), self.best_ask()) {
+            (Some(best_bid), Some(best_ask)) => {
+                let spread = best_ask.price - best_bid.price;
+                Some(SpreadInfo {
+                    spread,
+                    crossed: spread < Decimal::ZERO,
+                    best_bid_exchange: best_bid.exchange.clone(),
+                    best_ask_exchange: best_ask.exchange.clone(),
+                })
+            }
+            _ => None,
+        };
+        self.spread.as_ref()
+    }
+    
+    pub fn merge_and_sort(&mut self, new_bids: Vec<PriceLevel>, new_asks: Vec<PriceLevel>) {
+        // Both sides stay sorted as levels are inserted, bids from high to low and asks from low to high.
+        // Dust is applied as a zero amount so it also clears a level that shrank below the minimum
+        let min_amount = self.min_amount;
+        let without_dust = |mut level: PriceLevel| {
+            if level.amount < min_amount {
+                level.amount = Decimal::ZERO;
+            }
+            level
+        };
+        for level in new_bids {
+            self.bids.apply(without_dust(level));
+        }
+        for level in new_asks {
+            self.asks.apply(without_dust(level));
+        }
+    
+        // Limit to the configured depth
+        if self.depth_per_exchange {
+            self.truncate_per_exchange(self.max_depth);
+        } else {
+            self.truncate(self.max_depth);
+        }
+    
+        // Calculate the spread
+        self.calculate_spread();
+
+        // Fold it into the moving average, which starts at the first spread rather than at zero
+        if let Some(spread) = &self.spread {
+            let spread = spread.spread.to_f64().unwrap_or_default();
+            self.spread_ema = Some(self.spread_ema.map_or(spread, |ema| update_ema(ema, spread, self.spread_ema_alpha)));
+        }
+    }
+
+    // the book's writers take turns through its mutex, so every call sees the levels the previous one
+    // left, and only ever touches the levels of exchange. another exchange's levels leave the merged
+    // book only when they are outside the depth, and come back as soon as they are back inside it
+    pub fn replace_exchange_levels(&mut self, exchange: &str, mut new_bids: Vec<PriceLevel>, mut new_asks: Vec<PriceLevel>) {
+        // Convert to the book's quote currency first, everything below works on converted prices
+        if let Some(factor) = self.price_factors.get(exchange) {
+            for level in new_bids.iter_mut().chain(new_asks.iter_mut()) {
+                level.price *= factor;
+            }
+        }
+
+        // Drop the previous snapshot of this exchange so only its current levels remain
+        self.bids.remove_exchange(exchange);
+        self.asks.remove_exchange(exchange);
+
+        // Keep this exchange's own view, the merge may truncate it away
+        let own_levels = |levels: &[PriceLevel]| levels.iter().take(self.max_depth).cloned().collect();
+        self.exchange_levels.insert(exchange.to_string(), (own_levels(&new_bids), own_levels(&new_asks)));
+
+        // Merge the other exchanges' latest levels again too, a level cut by an earlier, better update
+        // of this exchange would otherwise stay missing until its own exchange sends it again. Merging
+        // adds to an existing level, so what is left of them goes first
+        for (other, (bids, asks)) in &self.exchange_levels {
+            if other != exchange {
+                self.bids.remove_exchange(other);
+                self.asks.remove_exchange(other);
+                new_bids.extend(bids.iter().cloned());
+                new_asks.extend(asks.iter().cloned());
+            }
+        }
+
+        self.merge_and_sort(new_bids, new_asks);
+    }
+
+    // starts the book from a saved one, each of exchanges' levels as if the exchange had sent them at
+    // saved_at_ms, so they age into stale and get replaced like any update. levels of other exchanges
+    // are dropped, without a feed nothing would ever replace them. the saved prices are already in
+    // the book's quote currency
+    pub fn restore(&mut self, saved: OrderBook, saved_at_ms: i64, exchanges: &[String]) {
+        let (saved_bids, saved_asks) = saved.into_levels();
+        let (mut new_bids, mut new_asks) = (Vec::new(), Vec::new());
+        for exchange in exchanges {
+            let own_levels = |levels: &[PriceLevel]| -> Vec<PriceLevel> {
+                levels.iter().filter(|level| &level.exchange == exchange).take(self.max_depth).cloned().collect()
+            };
+            let (bids, asks) = (own_levels(&saved_bids), own_levels(&saved_asks));
+            if bids.is_empty() && asks.is_empty() {
+                continue;
+            }
+            new_bids.extend(bids.iter().cloned());
+            new_asks.extend(asks.iter().cloned());
+            self.exchange_levels.insert(exchange.clone(), (bids, asks));
+            self.mark_updated(exchange, saved_at_ms);
+        }
+        self.merge_and_sort(new_bids, new_asks);
+    }
+
+    // the latest levels of every exchange on its own, up to max_depth per side, as ExchangeBooks
+    pub fn exchange_books(&self) -> Vec<ExchangeBook> {
+        self.exchange_levels
+            .iter()
+            .map(|(exchange, (bids, asks))| ExchangeBook {
+                exchange: exchange.clone(),
+                bids: bids.iter().map(|level| self.level_to_proto(level)).collect(),
+                asks: asks.iter().map(|level| self.level_to_proto(level)).collect(),
+            })
+            .collect()
+    }
+
+    // a level rounded to the price format and stamped with the time its exchange last updated the book
+    fn level_to_proto(&self, level: &PriceLevel) -> Level {
+        Level {
+            timestamp_ms: self.updated_at_ms.get(&level.exchange).copied().unwrap_or_default(),
+            ..self.price_format.to_proto(level)
+        }
+    }
+
+    // records that exchange's levels are current as of at_ms
+    pub fn mark_updated(&mut self, exchange: &str, at_ms: i64) {
+        self.updated_at_ms.insert(exchange.to_string(), at_ms);
+    }
+
+    // counts a reconnection of exchange's feed
+    pub fn record_reconnect(&mut self, exchange: &str) {
+        *self.reconnects.entry(exchange.to_string()).or_default() += 1;
+    }
+
+    // exchanges whose levels haven't been updated for longer than stale_after. their sockets may
+    // still be open, but their quotes can no longer be trusted
+    pub fn stale_exchanges(&self, now_ms: i64, stale_after: Duration) -> Vec<String> {
+        let stale_after_ms = i64::try_from(stale_after.as_millis()).unwrap_or(i64::MAX);
+        self.updated_at_ms
+            .iter()
+            .filter(|(_, &updated_at_ms)| now_ms.saturating_sub(updated_at_ms) > stale_after_ms)
+            .map(|(exchange, _)| exchange.clone())
+            .collect()
+    }
+
+    pub fn to_summary(&self) -> Summary {
+        let output_depth = self.output_depth.unwrap_or(usize::MAX);
+        Summary {
+            bids: self.bids.iter().take(output_depth).map(|level| self.level_to_proto(level)).collect(),
+            asks: self.asks.iter().take(output_depth).map(|level| self.level_to_proto(level)).collect(),
+            spread: self.spread.as_ref().and_then(|spread| spread.spread.to_f64()).unwrap_or_default(),
+            has_spread: self.spread.is_some(),
+            crossed: self.spread.as_ref().is_some_and(|spread| spread.crossed),
+            bid_vwap: vwap(self.bids.iter()).to_f64().unwrap_or_default(),
+            ask_vwap: vwap(self.asks.iter()).to_f64().unwrap_or_default(),
+            bid_total_amount: total_amount(self.bids.iter()).to_f64().unwrap_or_default(),
+            ask_total_amount: total_amount(self.asks.iter()).to_f64().unwrap_or_default(),
+            spread_bps: spread_bps(self),
+            mid_price: mid_price(self).unwrap_or_default(),
+            micro_price: micro_price(self).unwrap_or_default(),
+            spread_ema: self.spread_ema.unwrap_or_default(),
+            imbalance: imbalance(self),
+            reconnects: self.reconnects.iter().map(|(exchange, &count)| (exchange.clone(), count)).collect(),
+            // depends on the current time, set by whoever publishes the summary
+            generated_at_ms: 0,
+            // depends on the current time, see stale_exchanges
+            stale_exchanges: Vec::new(),
+        }
+    }
+
+    pub fn truncate(&mut self, depth: usize) {
+        // Limit the depth of the order book
+        self.bids.truncate(depth);
+        self.asks.truncate(depth);
+    }
+
+    // keeps the best depth levels of each exchange on both sides
+    pub fn truncate_per_exchange(&mut self, depth: usize) {
+        self.bids.truncate_per_exchange(depth);
+        self.asks.truncate_per_exchange(depth);
+    }
+
+    // the bids and asks, best first
+    pub fn into_levels(self) -> (Vec<PriceLevel>, Vec<PriceLevel>) {
+        (self.bids.into_vec(), self.asks.into_vec())
+    }
+
+    // a single exchange never quotes a bid at or above its own ask, so such a book is corrupt or
+    // mis-parsed. crossing between different exchanges is a real arbitrage and is left alone
+    pub fn validate_snapshot(&self) -> Result<(), ArbError> {
+        let best_bids = best_by_exchange(self.bids.iter());
+        for (exchange, ask) in best_by_exchange(self.asks.iter()) {
+            match best_bids.get(exchange) {
+                Some(bid) if bid.price >= ask.price => return Err(ArbError::CrossedBook { exchange: exchange.to_string(), bid: bid.price, ask: ask.price }),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // the most profitable cross between two different exchanges, buying one's best ask and selling
+    // into another's best bid, when it pays more than min_profit per unit after both taker fees.
+    // exchanges in exclude, e.g. stale ones, take no part
+    pub fn find_opportunity(&self, min_profit: Decimal, fees: &Fees, exclude: &[String]) -> Option<ArbOpportunity> {
+        let included = |level: &&PriceLevel| !exclude.contains(&level.exchange);
+        let best_bids = best_by_exchange(self.bids.iter().filter(included));
+        let best_asks = best_by_exchange(self.asks.iter().filter(included));
+        let mut best: Option<ArbOpportunity> = None;
+        for (buy_exchange, buy) in &best_asks {
+            for (sell_exchange, sell) in &best_bids {
+                let profit_per_unit = net_profit(buy, sell, fees);
+                if buy_exchange == sell_exchange || profit_per_unit <= min_profit {
+                    continue;
+                }
+                if best.as_ref().is_none_or(|best| profit_per_unit > best.profit_per_unit) {
+                    best = Some(ArbOpportunity {
+                        buy: (*buy).clone(),
+                        sell: (*sell).clone(),
+                        gross_profit_per_unit: sell.price - buy.price,
+                        profit_per_unit,
+                        amount: buy.amount.min(sell.amount),
+                        executable: ArbResult::default(),
+                    });
+                }
+            }
+        }
+        // Only the winning pair is walked past its top levels
+        if let Some(best) = &mut best {
+            best.executable = executable_arb(self, &best.buy.exchange, &best.sell.exchange, min_profit, fees).unwrap_or_default();
+        }
+        best
+    }
+}
+
+// each exchange's best level on one side, which is sorted best first
+fn best_by_exchange<'a>(levels: impl IntoIterator<Item = &'a PriceLevel>) -> HashMap<&'a str, &'a PriceLevel> {
+    let mut best = HashMap::new();
+    for level in levels {
+        best.entry(level.exchange.as_str()).or_insert(level);
+    }
+    best
+}
+
+// taker fee rates charged on each leg of an arbitrage, 0.001 is 0.1% of the traded notional
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Fees {
+    pub binance_taker: Decimal,
+    pub bitstamp_taker: Decimal,
+    pub coinbase_taker: Decimal,
+    pub kraken_taker: Decimal,
+}
+
+impl Fees {
+    // the taker fee rate of an exchange, zero for exchanges without a configured fee
+    pub fn taker(&self, exchange: &str) -> Decimal {
+        match exchange {
+            "binance" => self.binance_taker,
+            "bitstamp" => self.bitstamp_taker,
+            "coinbase" => self.coinbase_taker,
+            "kraken" => self.kraken_taker,
+            _ => Decimal::ZERO,
+        }
+    }
+}
+
+// profit per unit of buying at buy and selling at sell, once both exchanges took their taker fee
+pub fn net_profit(buy: &PriceLevel, sell: &PriceLevel, fees: &Fees) -> Decimal {
+    let proceeds = sell.price * (Decimal::ONE - fees.taker(&sell.exchange));
+    let cost = buy.price * (Decimal::ONE + fees.taker(&buy.exchange));
+    proceeds - cost
+}
+
+// what trading a cross between two exchanges down the book yields, after fees
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ArbResult {
+    pub amount: Decimal,
+    pub profit: Decimal,
+}
+
+impl ArbResult {
+    // the profit averaged over every unit traded, zero when nothing is
+    pub fn profit_per_unit(&self) -> Decimal {
+        if self.amount.is_zero() {
+            return Decimal::ZERO;
+        }
+        self.profit / self.amount
+    }
+}
+
+// buys up buy_exchange's asks from the best while selling down sell_exchange's bids from the best, for as
+// long as each matched slice pays more than min_profit per unit after both taker fees. only the levels
+// kept in the book are walked. None when not even the top levels pay
+pub fn executable_arb(book: &OrderBook, buy_exchange: &str, sell_exchange: &str, min_profit: Decimal, fees: &Fees) -> Option<ArbResult> {
+    let mut asks = book.asks.iter().filter(|level| level.exchange == buy_exchange);
+    let mut bids = book.bids.iter().filter(|level| level.exchange == sell_exchange);
+    let (mut ask, mut bid) = (asks.next()?, bids.next()?);
+    // what is left of the current level on each side
+    let (mut ask_left, mut bid_left) = (ask.amount, bid.amount);
+    let mut result = ArbResult::default();
+    loop {
+        let profit_per_unit = net_profit(ask, bid, fees);
+        if profit_per_unit <= min_profit {
+            break;
+        }
+        let amount = ask_left.min(bid_left);
+        result.amount += amount;
+        result.profit += amount * profit_per_unit;
+        ask_left -= amount;
+        bid_left -= amount;
+        if ask_left.is_zero() {
+            let Some(next) = asks.next() else { break };
+            ask = next;
+            ask_left = ask.amount;
+        }
+        if bid_left.is_zero() {
+            let Some(next) = bids.next() else { break };
+            bid = next;
+            bid_left = bid.amount;
+        }
+    }
+    (!result.amount.is_zero()).then_some(result)
+}
+
+// buying at one exchange's ask and selling at another's higher bid. the amount is limited by the
+// smaller of the two top levels, executable walks further down both books
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArbOpportunity {
+    pub buy: PriceLevel,
+    pub sell: PriceLevel,
+    // before fees
+    pub gross_profit_per_unit: Decimal,
+    // after both taker fees
+    pub profit_per_unit: Decimal,
+    pub amount: Decimal,
+    pub executable: ArbResult,
+}
+
+impl ArbOpportunity {
+    pub fn to_proto(&self, symbol: &str, timestamp_ms: i64) -> Opportunity {
+        Opportunity {
+            symbol: symbol.to_string(),
+            buy_exchange: self.buy.exchange.clone(),
+            buy_price: self.buy.price.to_f64().unwrap_or_default(),
+            sell_exchange: self.sell.exchange.clone(),
+            sell_price: self.sell.price.to_f64().unwrap_or_default(),
+            profit_per_unit: self.profit_per_unit.to_f64().unwrap_or_default(),
+            gross_profit_per_unit: self.gross_profit_per_unit.to_f64().unwrap_or_default(),
+            amount: self.amount.to_f64().unwrap_or_default(),
+            executable_amount: self.executable.amount.to_f64().unwrap_or_default(),
+            executable_profit: self.executable.profit.to_f64().unwrap_or_default(),
+            executable_profit_per_unit: self.executable.profit_per_unit().to_f64().unwrap_or_default(),
+            timestamp_ms,
+        }
+    }
+}
+
+// the change of one side between two summaries, keyed by exchange and price. None when a side holds
+// the same key twice, which happens when rounding folds two prices together, and only a snapshot is exact
+fn level_changes(previous: &[Level], next: &[Level]) -> Option<Vec<Level>> {
+    fn key(level: &Level) -> (String, u64) {
+        (level.exchange.clone(), level.price.to_bits())
+    }
+    fn index(levels: &[Level]) -> Option<HashMap<(String, u64), &Level>> {
+        let index: HashMap<_, _> = levels.iter().map(|level| (key(level), level)).collect();
+        (index.len() == levels.len()).then_some(index)
+    }
+    let (before, after) = (index(previous)?, index(next)?);
+    let removed = previous
+        .iter()
+        .filter(|level| !after.contains_key(&key(level)))
+        .map(|level| Level { amount: 0.0, ..level.clone() });
+    let changed = next
+        .iter()
+        .filter(|level| !before.get(&key(level)).is_some_and(|before| same_level(before, level)))
+        .cloned();
+    Some(removed.chain(changed).collect())
+}
+
+// whether two summary levels quote the same, whenever their exchanges last updated
+fn same_level(a: &Level, b: &Level) -> bool {
+    a.exchange == b.exchange && a.price == b.price && a.amount == b.amount
+}
+
+// whether two summaries show the same book. when they were generated and when each level's exchange
+// last updated are left out, an exchange resending its levels changes nothing a subscriber needs
+pub fn same_book(a: &Summary, b: &Summary) -> bool {
+    let same_levels = |a: &[Level], b: &[Level]| a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_level(a, b));
+    let untimed = |summary: &Summary| Summary { generated_at_ms: 0, ..summary_stats(summary) };
+    same_levels(&a.bids, &b.bids) && same_levels(&a.asks, &b.asks) && untimed(a) == untimed(b)
+}
+
+// the fields of a summary other than its levels
+fn summary_stats(summary: &Summary) -> Summary {
+    Summary { bids: Vec::new(), asks: Vec::new(), stale_exchanges: summary.stale_exchanges.clone(), reconnects: summary.reconnects.clone(), ..*summary }
+}
+
+// a delta carrying the whole of summary, for a new subscriber or a periodic resync
+pub fn summary_snapshot(summary: &Summary) -> SummaryDelta {
+    SummaryDelta {
+        snapshot: true,
+        bids: summary.bids.clone(),
+        asks: summary.asks.clone(),
+        stats: Some(summary_stats(summary)),
+    }
+}
+
+// what changed from previous to next, falling back to a snapshot when the change can't be expressed as a delta
+pub fn summary_delta(previous: &Summary, next: &Summary) -> SummaryDelta {
+    match (level_changes(&previous.bids, &next.bids), level_changes(&previous.asks, &next.asks)) {
+        (Some(bids), Some(asks)) => SummaryDelta { snapshot: false, bids, asks, stats: Some(summary_stats(next)) },
+        _ => summary_snapshot(next),
+    }
+}
+
+// the order of two summary levels at the same price, matching the book's
+fn tied_level_order(a: &Level, b: &Level) -> std::cmp::Ordering {
+    b.amount.total_cmp(&a.amount).then_with(|| a.exchange.cmp(&b.exchange))
+}
+
+// rebuilds the next summary from the previous one and a delta, levels sorted the way the book
+// sorts them: best price first, then the larger amount, then by exchange
+pub fn apply_summary_delta(summary: &mut Summary, delta: &SummaryDelta) {
+    if delta.snapshot {
+        summary.bids.clear();
+        summary.asks.clear();
+    }
+    for (levels, changes) in [(&mut summary.bids, &delta.bids), (&mut summary.asks, &delta.asks)] {
+        for change in changes {
+            levels.retain(|level| level.exchange != change.exchange || level.price != change.price);
+            if change.amount != 0.0 {
+                levels.push(change.clone());
+            }
+        }
+    }
+    summary.bids.sort_by(|a, b| b.price.total_cmp(&a.price).then_with(|| tied_level_order(a, b)));
+    summary.asks.sort_by(|a, b| a.price.total_cmp(&b.price).then_with(|| tied_level_order(a, b)));
+    if let Some(stats) = &delta.stats {
+        let (bids, asks) = (std::mem::take(&mut summary.bids), std::mem::take(&mut summary.asks));
+        *summary = Summary { bids, asks, ..stats.clone() };
+    }
+}
+
+// parses a decimal sent as a JSON string, as all supported exchanges do
+pub fn parse_decimal(value: &Value, field: &str) -> Result<Decimal, ArbError> {
+    value
+        .as_str()
+        .ok_or(ArbError::Parse(format!("{} is not a string", field)))?
+        .parse::<Decimal>()
+        .map_err(|_| ArbError::Parse(format!("failed to parse {} as decimal", field)))
+}
+
+// parses an array of [price, amount] string pairs
+pub fn parse_levels(levels: &Value, exchange: &str, side: &str) -> Result<Vec<PriceLevel>, ArbError> {
+    levels
+        .as_array()
+        .ok_or(ArbError::Parse(format!("{}s is not an array", side)))?
+        .iter()
+        .map(|level| {
+            Ok(PriceLevel {
+                exchange: exchange.to_string(),
+                price: parse_decimal(&level[0], &format!("{} price", side))?,
+                amount: parse_decimal(&level[1], &format!("{} amount", side))?,
+            })
+        })
+        .collect()
+}
+
+// parses the data to separate bids and asks fetched and fills the orderbook based on the proto arcchitecture 
+pub fn parse_order_book_update(message: &str, exchange: &str) -> Result<OrderBook, ArbError> {
+    let v: Value = serde_json::from_str(message)?;
+
+    // Bitstamp wraps the book in "data", Binance sends it as is
+    let book = if exchange == "bitstamp" {
+        v.get("data").ok_or(ArbError::Parse("The message did not contain the 'data' field".to_string()))?
+    } else {
+        &v
+    };
+    let bids = parse_levels(&book["bids"], exchange, "bid")?;
+    let asks = parse_levels(&book["asks"], exchange, "ask")?;
+    Ok(OrderBook::from_levels(bids, asks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // decimals written as in an exchange message, exact unlike float literals
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    fn level(exchange: &str, price: &str, amount: &str) -> PriceLevel {
+        PriceLevel { exchange: exchange.to_string(), price: dec(price), amount: dec(amount) }
+    }
+
+    // one exchange's levels from (price, amount) pairs
+    fn levels(exchange: &str, levels: &[(&str, &str)]) -> Vec<PriceLevel> {
+        levels.iter().map(|(price, amount)| level(exchange, price, amount)).collect()
+    }
+
+    #[test]
+    fn restamped_summary_shows_the_same_book() {
+        let mut book = OrderBook::new(10).unwrap();
+        book.replace_exchange_levels("binance", levels("binance", &[("100", "1")]), levels("binance", &[("101", "2")]));
+        book.mark_updated("binance", 1_000);
+        let first = Summary { generated_at_ms: 1_000, ..book.to_summary() };
+
+        // the exchange resends the same levels a second later
+        book.replace_exchange_levels("binance", levels("binance", &[("100", "1")]), levels("binance", &[("101", "2")]));
+        book.mark_updated("binance", 2_000);
+        let restamped = Summary { generated_at_ms: 2_000, ..book.to_summary() };
+        assert_ne!(first, restamped);
+        assert!(same_book(&first, &restamped));
+        let delta = summary_delta(&first, &restamped);
+        assert!(!delta.snapshot);
+        assert!(delta.bids.is_empty() && delta.asks.is_empty());
+
+        book.replace_exchange_levels("binance", levels("binance", &[("100", "3")]), levels("binance", &[("101", "2")]));
+        let changed = Summary { generated_at_ms: 3_000, ..book.to_summary() };
+        assert!(!same_book(&restamped, &changed));
+        let delta = summary_delta(&restamped, &changed);
+        assert_eq!(delta.bids.len(), 1);
+        assert_eq!(delta.bids[0].amount, 3.0);
+        assert!(delta.asks.is_empty());
+    }
+
+    // a path in the temp dir no other test uses
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("orderbook-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut book = OrderBook::new(5).unwrap();
+        book.merge_and_sort(
+            vec![level("binance", "0.061", "1.5"), level("bitstamp", "0.0605", "0.1")],
+            vec![level("bitstamp", "0.062", "2"), level("binance", "0.0625", "0.00000001")],
+        );
+        let path = temp_path("round-trip");
+        save_snapshot(&book, &path).unwrap();
+        let (loaded, saved_at_ms) = load_snapshot(&path, Duration::from_secs(60)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(OrderBookSnapshot::from(&loaded), OrderBookSnapshot::from(&book));
+        assert_eq!(loaded.spread(), book.spread());
+        assert!(saved_at_ms > 0);
+    }
+
+    #[test]
+    fn stale_snapshot_is_ignored() {
+        let mut book = OrderBook::new(5).unwrap();
+        book.merge_and_sort(levels("binance", &[("100", "1")]), levels("binance", &[("101", "1")]));
+        let path = temp_path("stale");
+        save_snapshot(&book, &path).unwrap();
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3_600);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(an_hour_ago).unwrap();
+        let loaded = load_snapshot(&path, Duration::from_secs(60));
+        let loaded_with_more_leeway = load_snapshot(&path, Duration::from_secs(7_200));
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.is_none());
+        assert!(loaded_with_more_leeway.is_some());
+        assert!(load_snapshot(&temp_path("missing"), Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn restored_levels_age_and_unfed_exchanges_are_dropped() {
+        let mut saved = OrderBook::new(10).unwrap();
+        saved.merge_and_sort(
+            vec![level("binance", "100", "1"), level("kraken", "102", "1")],
+            vec![level("binance", "101", "1"), level("kraken", "103", "1")],
+        );
+        let mut book = OrderBook::new(10).unwrap();
+        book.restore(saved, 1_000, &["binance".to_string(), "bitstamp".to_string()]);
+
+        // kraken isn't fed this run, its saved levels would never be replaced
+        assert_eq!(book.bids().iter().cloned().collect::<Vec<_>>(), vec![level("binance", "100", "1")]);
+        assert_eq!(book.asks().iter().cloned().collect::<Vec<_>>(), vec![level("binance", "101", "1")]);
+        assert_eq!(book.stale_exchanges(1_500, Duration::from_secs(1)), Vec::<String>::new());
+        assert_eq!(book.stale_exchanges(5_000, Duration::from_secs(1)), vec!["binance"]);
+        assert_eq!(book.to_summary().bids[0].timestamp_ms, 1_000);
+
+        // the feed's first update replaces what was restored
+        book.replace_exchange_levels("binance", levels("binance", &[("99", "2")]), levels("binance", &[("100.5", "2")]));
+        assert_eq!(book.best_bid(), Some(&level("binance", "99", "2")));
+        assert_eq!(book.bids().len(), 1);
+    }
+
+    #[test]
+    fn repeated_snapshots_replace_an_exchange_levels() {
+        let mut book = OrderBook::new(10).unwrap();
+        let bids = levels("binance", &[("100", "1"), ("99", "2"), ("98", "3")]);
+        let asks = levels("binance", &[("101", "1"), ("102", "2"), ("103", "3")]);
+        book.replace_exchange_levels("binance", bids.clone(), asks.clone());
+        book.replace_exchange_levels("binance", bids.clone(), asks.clone());
+        assert_eq!(book.bids().len(), 3);
+        assert_eq!(book.asks().len(), 3);
+        // the amounts are the latest ones, not summed over both snapshots
+        assert_eq!(book.bids().iter().cloned().collect::<Vec<_>>(), bids);
+
+        book.replace_exchange_levels("bitstamp", levels("bitstamp", &[("100", "5")]), levels("bitstamp", &[("101", "5")]));
+        book.replace_exchange_levels("binance", levels("binance", &[("97", "1")]), levels("binance", &[("104", "1")]));
+        assert_eq!(book.bids().iter().cloned().collect::<Vec<_>>(), vec![level("bitstamp", "100", "5"), level("binance", "97", "1")]);
+        assert_eq!(book.asks().iter().cloned().collect::<Vec<_>>(), vec![level("bitstamp", "101", "5"), level("binance", "104", "1")]);
+    }
+
+    #[test]
+    fn spread_is_exact_in_decimal() {
+        // 0.3 - 0.1 is 0.19999999999999998 in f64
+        assert_ne!(0.3_f64 - 0.1_f64, 0.2_f64);
+        let update = parse_order_book_update(r#"{"bids":[["0.1","1"]],"asks":[["0.3","1"]]}"#, "binance").unwrap();
+        let mut book = OrderBook::new(10).unwrap();
+        let (bids, asks) = update.into_levels();
+        book.merge_and_sort(bids, asks);
+        assert_eq!(book.spread().unwrap().spread, dec("0.2"));
+        assert_eq!(dec("0.1") + dec("0.2"), dec("0.3"));
+        // converted only at the gRPC boundary, to the nearest double of 0.2
+        assert_eq!(book.to_summary().spread, 0.2);
+    }
+
+    #[test]
+    fn depth_caps_both_sides() {
+        let mut book = OrderBook::new(5).unwrap();
+        for (round, exchange) in ["binance", "bitstamp", "binance", "kraken"].into_iter().enumerate() {
+            let bids = (0..8).map(|i| level(exchange, &format!("{}", 100 - i - round), "1")).collect();
+            let asks = (0..8).map(|i| level(exchange, &format!("{}", 101 + i + round), "1")).collect();
+            book.replace_exchange_levels(exchange, bids, asks);
+            assert_eq!(book.bids().len(), 5);
+            assert_eq!(book.asks().len(), 5);
+        }
+        assert_eq!(book.to_summary().bids.len(), 5);
+    }
+
+    #[test]
+    fn depth_must_be_between_1_and_100() {
+        assert!(OrderBook::new(0).is_err());
+        assert!(OrderBook::new(1).is_ok());
+        assert!(OrderBook::new(MAX_DEPTH).is_ok());
+        assert!(OrderBook::new(MAX_DEPTH + 1).is_err());
+    }
+
+    #[test]
+    fn crossed_book_names_both_venues() {
+        let mut book = OrderBook::new(10).unwrap();
+        book.replace_exchange_levels("binance", levels("binance", &[("101", "1")]), levels("binance", &[("102", "1")]));
+        book.replace_exchange_levels("bitstamp", levels("bitstamp", &[("99", "1")]), levels("bitstamp", &[("100.5", "1")]));
+        let spread = book.spread().unwrap();
+        assert_eq!(spread.spread, dec("-0.5"));
+        assert!(spread.crossed);
+        assert_eq!(spread.best_bid_exchange, "binance");
+        assert_eq!(spread.best_ask_exchange, "bitstamp");
+        let summary = book.to_summary();
+        assert!(summary.crossed);
+        assert_eq!(summary.spread, -0.5);
+    }
+
+    #[test]
+    fn normal_book_is_not_crossed() {
+        let mut book = OrderBook::new(10).unwrap();
+        book.replace_exchange_levels("binance", levels("binance", &[("100", "1")]), levels("binance", &[("102", "1")]));
+        book.replace_exchange_levels("bitstamp", levels("bitstamp", &[("101", "1")]), levels("bitstamp", &[("103", "1")]));
+        let spread = book.spread().unwrap();
+        assert_eq!(spread.spread, dec("1"));
+        assert!(!spread.crossed);
+        assert_eq!((spread.best_bid_exchange.as_str(), spread.best_ask_exchange.as_str()), ("bitstamp", "binance"));
+    }
+
+    #[test]
+    fn malformed_messages_are_parse_errors() {
+        for message in ["not json", r#"{"bids":[["0.06"]],"asks":[]}"#, r#"{"bids":[["abc","1"]],"asks":[]}"#] {
+            let result = parse_order_book_update(message, "binance");
+            assert!(matches!(result, Err(ArbError::Parse(_))), "{}: {:?}", message, result);
+        }
+    }
+
+    #[test]
+    fn vwap_weights_prices_by_amount() {
+        // (100 * 1 + 101 * 3) / 4
+        assert_eq!(vwap(&levels("binance", &[("100", "1"), ("101", "3")])), dec("100.75"));
+        assert_eq!(vwap(&levels("binance", &[("0.061", "2")])), dec("0.061"));
+        assert_eq!(vwap(&[]), Decimal::ZERO);
+
+        let mut book = OrderBook::new(10).unwrap();
+        book.merge_and_sort(levels("binance", &[("99", "2"), ("98", "2")]), Vec::new());
+        let summary = book.to_summary();
+        assert_eq!(summary.bid_vwap, 98.5);
+        assert_eq!(summary.ask_vwap, 0.0);
+    }
+
+    #[test]
+    fn totals_add_up_each_side() {
+        let mut book = OrderBook::new(10).unwrap().with_output_depth(1);
+        book.merge_and_sort(
+            [levels("binance", &[("100", "1.5")]), levels("bitstamp", &[("99", "2.25")])].concat(),
+            levels("bitstamp", &[("101", "0.5"), ("102", "4")]),
+        );
+        let summary = book.to_summary();
+        // the totals cover the whole book, not just the levels sent
+        assert_eq!(summary.bids.len(), 1);
+        assert_eq!(summary.bid_total_amount, 3.75);
+        assert_eq!(summary.ask_total_amount, 4.5);
+        assert_eq!(OrderBook::new(10).unwrap().to_summary().bid_total_amount, 0.0);
+    }
+
+    #[test]
+    fn same_price_levels_of_an_exchange_are_combined() {
+        let mut book = OrderBook::new(10).unwrap();
+        book.merge_and_sort(
+            [levels("binance", &[("100", "1"), ("100", "2")]), levels("bitstamp", &[("100", "4")])].concat(),
+            levels("binance", &[("101", "0.5"), ("101", "0.25")]),
+        );
+        let (bids, asks) = book.into_levels();
+        // other exchanges at the same price stay separate levels
+        assert_eq!(bids, [levels("bitstamp", &[("100", "4")]), levels("binance", &[("100", "3")])].concat());
+        assert_eq!(asks, levels("binance", &[("101", "0.75")]));
+    }
+
+    #[test]
+    fn zero_amount_removes_a_price() {
+        let mut book = OrderBook::new(10).unwrap();
+        book.merge_and_sort(
+            [levels("binance", &[("100", "1"), ("99", "2")]), levels("bitstamp", &[("100", "3")])].concat(),
+            levels("binance", &[("101", "1")]),
+        );
+        // only binance's level at 100 goes, and a removal of an unknown price is not inserted
+        book.merge_and_sort(levels("binance", &[("100", "0"), ("98", "0")]), levels("binance", &[("101", "0")]));
+        assert_eq!(book.best_bid(), Some(&level("bitstamp", "100", "3")));
+        assert!(book.best_ask().is_none());
+        let summary = book.to_summary();
+        assert_eq!(summary.bids.len(), 2);
+        assert!(summary.bids.iter().all(|level| level.amount > 0.0));
+        assert_eq!(summary.bid_total_amount, 5.0);
+    }
+
+    #[test]
+    fn best_levels_name_their_exchange() {
+        let mut book = OrderBook::new(10).unwrap();
+        assert!(book.best_bid().is_none() && book.best_ask().is_none());
+        book.replace_exchange_levels("binance", levels("binance", &[("0.0610", "1"), ("0.0609", "5")]), levels("binance", &[("0.0614", "1")]));
+        book.replace_exchange_levels("bitstamp", levels("bitstamp", &[("0.0611", "2")]), levels("bitstamp", &[("0.0612", "3"), ("0.0615", "1")]));
+        assert_eq!(book.best_bid(), Some(&level("bitstamp", "0.0611", "2")));
+        assert_eq!(book.best_ask(), Some(&level("bitstamp", "0.0612", "3")));
+
+        // binance takes the bid over once it quotes higher
+        book.replace_exchange_levels("binance", levels("binance", &[("0.0613", "1")]), levels("binance", &[("0.0614", "1")]));
+        assert_eq!(book.best_bid(), Some(&level("binance", "0.0613", "1")));
+        assert_eq!(book.best_ask(), Some(&level("bitstamp", "0.0612", "3")));
+    }
+
+    #[test]
+    fn crossed_book_of_one_exchange_is_corrupt() {
+        let corrupt = OrderBook::from_levels(levels("bitstamp", &[("0.0612", "1"), ("0.0610", "1")]), levels("bitstamp", &[("0.0611", "1")]));
+        assert!(matches!(
+            corrupt.validate_snapshot(),
+            Err(ArbError::CrossedBook { exchange, bid, ask }) if exchange == "bitstamp" && bid == dec("0.0612") && ask == dec("0.0611")
+        ));
+        // touching is as wrong as crossing
+        let locked = OrderBook::from_levels(levels("binance", &[("0.0611", "1")]), levels("binance", &[("0.0611", "1")]));
+        assert!(locked.validate_snapshot().is_err());
+    }
+
+    #[test]
+    fn cross_between_exchanges_is_valid() {
+        let mut book = OrderBook::new(10).unwrap();
+        book.replace_exchange_levels("binance", levels("binance", &[("0.0612", "1")]), levels("binance", &[("0.0613", "1")]));
+        book.replace_exchange_levels("bitstamp", levels("bitstamp", &[("0.0609", "1")]), levels("bitstamp", &[("0.0610", "1")]));
+        // binance bids above bitstamp's ask, an arbitrage rather than bad data
+        assert!(book.spread().unwrap().crossed);
+        assert!(book.validate_snapshot().is_ok());
+        assert!(OrderBook::from_levels(levels("binance", &[("0.0610", "1")]), Vec::new()).validate_snapshot().is_ok());
+    }
+
+    #[test]
+    fn spread_bps_is_relative_to_the_mid() {
+        let book = |bid: &str, ask: &str| OrderBook::from_levels(levels("binance", &[(bid, "1")]), levels("bitstamp", &[(ask, "1")]));
+        // 10 wide around a mid of 100, then the same relative spread at a thousandth of the price
+        assert_eq!(spread_bps(&book("95", "105")), 1000.0);
+        assert_eq!(spread_bps(&book("0.095", "0.105")), 1000.0);
+        // one tick on bitcoin, 0.01 around 30000.005
+        assert!((spread_bps(&book("30000", "30000.01")) - 0.0033333).abs() < 1e-6);
+        assert_eq!(spread_bps(&book("0.06", "0.06")), 0.0);
+        assert_eq!(spread_bps(&OrderBook::from_levels(Vec::new(), levels("binance", &[("101", "1")]))), 0.0);
+        assert_eq!(spread_bps(&OrderBook::new(10).unwrap()), 0.0);
+        assert_eq!(spread_bps(&book("0", "0")), 0.0);
+    }
+
+    #[test]
+    fn json_round_trip_is_lossless() {
+        let mut book = OrderBook::new(3).unwrap();
+        // more digits than an f64 holds
+        book.merge_and_sort(
+            levels("binance", &[("0.061234567890123456789", "1.000000000000000001"), ("0.0611", "2")]),
+            levels("bitstamp", &[("0.0613", "0.00000001")]),
+        );
+        let json = to_json(&book);
+        assert!(json.contains(r#""price":"0.061234567890123456789""#), "{}", json);
+        let rebuilt = from_json(&json).unwrap();
+        assert_eq!(OrderBookSnapshot::from(&rebuilt), OrderBookSnapshot::from(&book));
+        assert_eq!(rebuilt.spread(), book.spread());
+        assert_eq!(to_json(&rebuilt), json);
+
+        assert!(matches!(from_json(r#"{"max_depth":3,"bids":[]}"#), Err(ArbError::Parse(_))));
+        assert!(from_json(r#"{"max_depth":0,"bids":[],"asks":[]}"#).is_err());
+    }
+
+    // binance bidding 101 above bitstamp's 100 ask
+    fn crossed_two_exchange_book() -> OrderBook {
+        let mut book = OrderBook::new(10).unwrap();
+        book.replace_exchange_levels("binance", levels("binance", &[("101", "2")]), levels("binance", &[("102", "1")]));
+        book.replace_exchange_levels("bitstamp", levels("bitstamp", &[("99", "1")]), levels("bitstamp", &[("100", "0.5")]));
+        book
+    }
+
+    #[test]
+    fn detector_buys_the_low_ask_and_sells_the_high_bid() {
+        let book = crossed_two_exchange_book();
+        let opportunity = book.find_opportunity(Decimal::ZERO, &Fees::default(), &[]).unwrap();
+        assert_eq!(opportunity.buy, level("bitstamp", "100", "0.5"));
+        assert_eq!(opportunity.sell, level("binance", "101", "2"));
+        assert_eq!(opportunity.gross_profit_per_unit, dec("1"));
+        assert_eq!(opportunity.profit_per_unit, dec("1"));
+        assert_eq!(opportunity.amount, dec("0.5"));
+        let proto = opportunity.to_proto("BTC-USD", 1_000);
+        assert_eq!((proto.buy_exchange.as_str(), proto.sell_exchange.as_str(), proto.amount), ("bitstamp", "binance", 0.5));
+
+        // below the threshold, or with one side stale, nothing is reported
+        assert!(book.find_opportunity(dec("1"), &Fees::default(), &[]).is_none());
+        assert!(book.find_opportunity(Decimal::ZERO, &Fees::default(), &["bitstamp".to_string()]).is_none());
+        let mut uncrossed = OrderBook::new(10).unwrap();
+        uncrossed.replace_exchange_levels("binance", levels("binance", &[("99", "1")]), levels("binance", &[("102", "1")]));
+        uncrossed.replace_exchange_levels("bitstamp", levels("bitstamp", &[("98", "1")]), levels("bitstamp", &[("100", "1")]));
+        assert!(uncrossed.find_opportunity(Decimal::ZERO, &Fees::default(), &[]).is_none());
+    }
+
+    #[test]
+    fn fees_turn_a_gross_cross_unprofitable() {
+        let (buy, sell) = (level("bitstamp", "100", "1"), level("binance", "101", "1"));
+        let low_fees = Fees { binance_taker: dec("0.001"), bitstamp_taker: dec("0.004"), ..Fees::default() };
+        // 101 * 0.999 - 100 * 1.004
+        assert_eq!(net_profit(&buy, &sell, &low_fees), dec("0.499"));
+        let high_fees = Fees { binance_taker: dec("0.005"), bitstamp_taker: dec("0.006"), ..Fees::default() };
+        // 101 * 0.995 - 100 * 1.006
+        assert_eq!(net_profit(&buy, &sell, &high_fees), dec("-0.105"));
+
+        let book = crossed_two_exchange_book();
+        assert_eq!(book.find_opportunity(Decimal::ZERO, &low_fees, &[]).unwrap().profit_per_unit, dec("0.499"));
+        // still a gross profit of 1 per unit, but the fees eat it
+        assert!(book.find_opportunity(Decimal::ZERO, &high_fees, &[]).is_none());
+    }
+
+    #[test]
+    fn dust_is_left_out_of_the_book() {
+        let dusty = || [levels("binance", &[("100", "0.0001"), ("99", "1")]), levels("bitstamp", &[("99.5", "0.002")])].concat();
+        let mut unfiltered = OrderBook::new(10).unwrap();
+        unfiltered.merge_and_sort(dusty(), Vec::new());
+        assert_eq!(unfiltered.bids().len(), 3);
+
+        let mut book = OrderBook::new(10).unwrap().with_min_amount(dec("0.001"));
+        book.merge_and_sort(dusty(), levels("binance", &[("101", "0.0009"), ("102", "0.001")]));
+        assert_eq!(book.bids().iter().cloned().collect::<Vec<_>>(), [levels("bitstamp", &[("99.5", "0.002")]), levels("binance", &[("99", "1")])].concat());
+        assert_eq!(book.asks().iter().cloned().collect::<Vec<_>>(), levels("binance", &[("102", "0.001")]));
+
+        // a level shrinking to dust leaves the book too
+        book.merge_and_sort(levels("binance", &[("99", "0.0005")]), Vec::new());
+        assert_eq!(book.bids().len(), 1);
+    }
+
+    #[test]
+    fn mid_price_is_halfway_between_the_best_levels() {
+        let mut book = OrderBook::new(10).unwrap();
+        assert_eq!(mid_price(&book), None);
+        book.merge_and_sort(levels("binance", &[("0.0610", "1"), ("0.0600", "1")]), Vec::new());
+        assert_eq!(mid_price(&book), None);
+        assert_eq!(book.to_summary().mid_price, 0.0);
+
+        book.merge_and_sort(Vec::new(), levels("bitstamp", &[("0.0614", "1"), ("0.0620", "1")]));
+        assert_eq!(mid_price(&book), Some(0.0612));
+        assert_eq!(book.to_summary().mid_price, 0.0612);
+    }
+
+    #[test]
+    fn exchanges_silent_for_longer_than_stale_after_are_stale() {
+        let mut book = OrderBook::new(10).unwrap();
+        book.mark_updated("binance", 20_000);
+        book.mark_updated("bitstamp", 9_000);
+        let stale_after = Duration::from_secs(10);
+        // exactly stale_after old is still fresh
+        assert!(book.stale_exchanges(19_000, stale_after).is_empty());
+        assert_eq!(book.stale_exchanges(19_001, stale_after), ["bitstamp"]);
+        let mut stale = book.stale_exchanges(40_000, stale_after);
+        stale.sort();
+        assert_eq!(stale, ["binance", "bitstamp"]);
+    }
+
+    #[test]
+    fn price_formats_round_each_symbol() {
+        let usd = PriceFormat::for_symbol("BTC-USD");
+        assert_eq!(usd, PriceFormat { price_decimals: 2, amount_decimals: 8 });
+        let rounded = usd.to_proto(&level("bitstamp", "43210.125", "0.123456789"));
+        assert_eq!((rounded.price, rounded.amount), (43210.13, 0.12345678));
+
+        let btc = PriceFormat::for_symbol("ETH-BTC");
+        assert_eq!(btc, PriceFormat::default());
+        let rounded = btc.to_proto(&level("binance", "0.0500000049", "1.999999999"));
+        assert_eq!((rounded.price, rounded.amount), (0.05, 1.99999999));
+
+        // a configured format applies to the summary, the book keeps full precision
+        let mut book = OrderBook::new(10).unwrap().with_price_format(PriceFormat { price_decimals: 5, amount_decimals: 4 });
+        book.replace_exchange_levels("binance", levels("binance", &[("0.050004", "1.23456")]), levels("binance", &[("0.050016", "0.00009")]));
+        let summary = book.to_summary();
+        assert_eq!((summary.bids[0].price, summary.bids[0].amount), (0.05, 1.2345));
+        assert_eq!((summary.asks[0].price, summary.asks[0].amount), (0.05002, 0.0));
+        assert_eq!(book.bids().iter().next().unwrap().price, dec("0.050004"));
+    }
+
+    #[test]
+    fn delta_stream_rebuilds_the_snapshot_stream() {
+        type Levels<'a> = &'a [(&'a str, &'a str)];
+        let mut book = OrderBook::new(3).unwrap();
+        let updates: [(&str, Levels, Levels); 6] = [
+            ("binance", &[("100", "1"), ("99", "2")], &[("101", "1"), ("102", "2")]),
+            ("bitstamp", &[("100", "3"), ("98", "1")], &[("101.5", "1")]),
+            // an amount changes, a level goes and another comes
+            ("binance", &[("100", "1.5")], &[("101", "1"), ("103", "4")]),
+            // pushes levels out of the top 3
+            ("bitstamp", &[("100.5", "1"), ("100.2", "1"), ("100.1", "1")], &[("100.9", "2")]),
+            ("binance", &[], &[]),
+            ("bitstamp", &[("97", "5")], &[("104", "1")]),
+        ];
+
+        let mut rebuilt = Summary::default();
+        let mut previous: Option<Summary> = None;
+        for (exchange, bids, asks) in updates {
+            book.replace_exchange_levels(exchange, levels(exchange, bids), levels(exchange, asks));
+            let summary = book.to_summary();
+            let delta = match &previous {
+                Some(previous) => summary_delta(previous, &summary),
+                None => summary_snapshot(&summary),
+            };
+            assert_eq!(delta.snapshot, previous.is_none());
+            apply_summary_delta(&mut rebuilt, &delta);
+            assert_eq!(rebuilt, summary);
+            previous = Some(summary);
+        }
+    }
+
+    #[test]
+    fn truncate_per_exchange_keeps_both_venues() {
+        let exchange_levels = |exchange: &str, prices: &[&str]| prices.iter().map(|price| level(exchange, price, "1")).collect::<Vec<_>>();
+        let mut bids = exchange_levels("binance", &["105", "104", "103"]);
+        bids.extend(exchange_levels("bitstamp", &["102", "101", "100"]));
+        let mut book = OrderBook::from_levels(bids, Vec::new());
+
+        // binance quotes every better price, an overall cut would leave bitstamp out
+        book.truncate_per_exchange(2);
+        let prices: Vec<_> = book.bids().iter().map(|level| (level.exchange.as_str(), level.price)).collect();
+        assert_eq!(prices, vec![("binance", dec("105")), ("binance", dec("104")), ("bitstamp", dec("102")), ("bitstamp", dec("101"))]);
+
+        let mut book = OrderBook::new(2).unwrap().with_depth_per_exchange(true);
+        book.replace_exchange_levels("binance", exchange_levels("binance", &["105", "104", "103"]), exchange_levels("binance", &["106", "107", "108"]));
+        book.replace_exchange_levels("bitstamp", exchange_levels("bitstamp", &["102", "101", "100"]), exchange_levels("bitstamp", &["109", "110", "111"]));
+        for side in [book.bids().iter().collect::<Vec<_>>(), book.asks().iter().collect()] {
+            assert_eq!(side.iter().filter(|level| level.exchange == "binance").count(), 2);
+            assert_eq!(side.iter().filter(|level| level.exchange == "bitstamp").count(), 2);
+        }
+
+        // without the option the better venue takes the whole depth
+        let mut book = OrderBook::new(2).unwrap();
+        book.replace_exchange_levels("binance", exchange_levels("binance", &["105", "104"]), Vec::new());
+        book.replace_exchange_levels("bitstamp", exchange_levels("bitstamp", &["102"]), Vec::new());
+        assert!(book.bids().iter().all(|level| level.exchange == "binance"));
+    }
+
+    #[test]
+    fn spread_ema_over_a_known_series() {
+        let mut ema = 10.0;
+        let mut series = Vec::new();
+        for sample in [20.0, 20.0, 0.0, 8.75] {
+            ema = update_ema(ema, sample, 0.5);
+            series.push(ema);
+        }
+        assert_eq!(series, vec![15.0, 17.5, 8.75, 8.75]);
+        // alpha 1 follows the samples, alpha 0 never moves
+        assert_eq!(update_ema(10.0, 4.0, 1.0), 4.0);
+        assert_eq!(update_ema(10.0, 4.0, 0.0), 10.0);
+
+        // the book starts from its first spread rather than from zero
+        let mut book = OrderBook::new(10).unwrap().with_spread_ema_alpha(0.5);
+        assert_eq!(book.spread_ema(), None);
+        for (ask, ema) in [("104", 4.0), ("102", 3.0), ("101", 2.0)] {
+            book.replace_exchange_levels("binance", levels("binance", &[("100", "1")]), levels("binance", &[(ask, "1")]));
+            assert_eq!(book.spread_ema(), Some(ema));
+            assert_eq!(book.to_summary().spread_ema, ema);
+        }
+    }
+
+    #[test]
+    fn imbalance_of_hand_computed_books() {
+        let book = |bids: &[(&str, &str)], asks: &[(&str, &str)]| OrderBook::from_levels(levels("binance", bids), levels("binance", asks));
+        // 3 against 1: (3 - 1) / 4
+        assert_eq!(imbalance(&book(&[("100", "2"), ("99", "1")], &[("101", "1")])), 0.5);
+        assert_eq!(imbalance(&book(&[("100", "1")], &[("101", "1.5"), ("102", "2.5")])), -0.6);
+        assert_eq!(imbalance(&book(&[("100", "2")], &[("101", "0.5"), ("102", "1.5")])), 0.0);
+        // one-sided and empty books
+        assert_eq!(imbalance(&book(&[("100", "1")], &[])), 1.0);
+        assert_eq!(imbalance(&book(&[], &[("101", "1")])), -1.0);
+        assert_eq!(imbalance(&book(&[], &[])), 0.0);
+
+        let mut merged = OrderBook::new(10).unwrap();
+        merged.replace_exchange_levels("binance", levels("binance", &[("100", "3")]), levels("binance", &[("101", "1")]));
+        assert_eq!(merged.to_summary().imbalance, 0.5);
+    }
+
+    #[test]
+    fn spread_needs_both_sides() {
+        let mut book = OrderBook::new(10).unwrap();
+        assert!(book.calculate_spread().is_none());
+        let summary = book.to_summary();
+        assert!(!summary.has_spread);
+        assert_eq!(summary.spread, 0.0);
+
+        book.replace_exchange_levels("binance", levels("binance", &[("100", "1")]), Vec::new());
+        assert!(book.spread().is_none());
+        assert!(!book.to_summary().has_spread);
+
+        book.replace_exchange_levels("bitstamp", Vec::new(), levels("bitstamp", &[("100.5", "1")]));
+        assert_eq!(book.spread().map(|spread| spread.spread), Some(dec("0.5")));
+        let summary = book.to_summary();
+        assert!(summary.has_spread);
+        assert_eq!(summary.spread, 0.5);
+
+        // a zero spread is a spread
+        book.replace_exchange_levels("bitstamp", Vec::new(), levels("bitstamp", &[("100", "1")]));
+        let summary = book.to_summary();
+        assert!(summary.has_spread);
+        assert_eq!(summary.spread, 0.0);
+    }
+
+    #[test]
+    fn micro_price_leans_towards_the_thinner_side() {
+        let book = |bids: &[(&str, &str)], asks: &[(&str, &str)]| OrderBook::from_levels(levels("binance", bids), levels("binance", asks));
+        // three times the size on the bid, so the next trade is more likely to lift the ask
+        let skewed = book(&[("100", "3")], &[("102", "1")]);
+        assert_eq!(mid_price(&skewed), Some(101.0));
+        assert_eq!(micro_price(&skewed), Some(101.5));
+        let skewed = book(&[("100", "1")], &[("102", "3")]);
+        assert_eq!(micro_price(&skewed), Some(100.5));
+
+        // equal sizes agree with the mid
+        let balanced = book(&[("100", "2")], &[("102", "2")]);
+        assert_eq!(micro_price(&balanced), mid_price(&balanced));
+
+        assert_eq!(micro_price(&book(&[("100", "1")], &[])), None);
+        assert_eq!(micro_price(&book(&[("100", "0")], &[("102", "0")])), None);
+    }
+
+    #[test]
+    fn level_counts_of_a_mixed_book() {
+        let mut book = OrderBook::new(5).unwrap();
+        book.replace_exchange_levels("binance", levels("binance", &[("100", "1"), ("99.8", "1"), ("99.6", "1"), ("99.4", "1")]), levels("binance", &[("101", "1"), ("101.2", "1")]));
+        book.replace_exchange_levels("bitstamp", levels("bitstamp", &[("99.9", "1"), ("99.7", "1"), ("99.5", "1")]), levels("bitstamp", &[("100.9", "1")]));
+        // the top 5 bids are binance 100, 99.8, 99.6 and bitstamp 99.9, 99.7
+        assert_eq!(level_counts_by_exchange(&book), HashMap::from([("binance".to_string(), 5), ("bitstamp".to_string(), 3)]));
+
+        assert!(level_counts_by_exchange(&OrderBook::new(5).unwrap()).is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_writers_never_drop_each_others_levels() {
+        let book = std::sync::Arc::new(tokio::sync::Mutex::new(OrderBook::new(10).unwrap()));
+        // each exchange quotes 5 levels a side, so both always fit in the 10 kept
+        let writer = |exchange: &'static str, other: &'static str, offset: u32| {
+            let book = std::sync::Arc::clone(&book);
+            tokio::spawn(async move {
+                for round in 0..2_000u32 {
+                    let price = |step: u32| Decimal::from(1_000 + offset + (round % 7) * 10 + step);
+                    let bids = (0..5).map(|step| PriceLevel { exchange: exchange.to_string(), price: price(step) - Decimal::from(100), amount: Decimal::ONE }).collect();
+                    let asks = (0..5).map(|step| PriceLevel { exchange: exchange.to_string(), price: price(step) + Decimal::from(100), amount: Decimal::ONE }).collect();
+                    let mut book = book.lock().await;
+                    book.replace_exchange_levels(exchange, bids, asks);
+
+                    let counts = level_counts_by_exchange(&book);
+                    assert_eq!(counts[exchange], 10);
+                    assert!(matches!(counts.get(other), None | Some(10)), "{} lost levels: {:?}", other, counts);
+                    assert!(book.bids().iter().zip(book.bids().iter().skip(1)).all(|(a, b)| a.price >= b.price));
+                    assert!(book.asks().iter().zip(book.asks().iter().skip(1)).all(|(a, b)| a.price <= b.price));
+                    drop(book);
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        let (binance, bitstamp) = (writer("binance", "bitstamp", 0), writer("bitstamp", "binance", 5));
+        binance.await.unwrap();
+        bitstamp.await.unwrap();
+
+        let book = book.lock().await;
+        assert_eq!(level_counts_by_exchange(&book), HashMap::from([("binance".to_string(), 10), ("bitstamp".to_string(), 10)]));
+        assert_eq!(book.bids().len(), 10);
+        assert_eq!(book.asks().len(), 10);
+    }
+
+    #[test]
+    fn executable_arb_walks_down_multi_level_crossed_books() {
+        let mut book = OrderBook::new(10).unwrap();
+        book.replace_exchange_levels("bitstamp", Vec::new(), levels("bitstamp", &[("100", "1"), ("101", "2"), ("103", "5")]));
+        book.replace_exchange_levels("binance", levels("binance", &[("104", "2"), ("102", "2"), ("100.5", "3")]), Vec::new());
+        let no_fees = Fees::default();
+
+        // 1 at 100 against 104, 1 at 101 against 104, 1 at 101 against 102, then 103 is above 102
+        let result = executable_arb(&book, "bitstamp", "binance", Decimal::ZERO, &no_fees).unwrap();
+        assert_eq!(result, ArbResult { amount: dec("3"), profit: dec("8") });
+        assert_eq!(result.profit_per_unit(), dec("8") / dec("3"));
+
+        // the last slice only pays 1 a unit
+        let result = executable_arb(&book, "bitstamp", "binance", Decimal::ONE, &no_fees).unwrap();
+        assert_eq!(result, ArbResult { amount: dec("2"), profit: dec("7") });
+
+        // a 1% fee buying on bitstamp makes 101 cost 102.01, which the 102 bid doesn't cover
+        let fees = Fees { bitstamp_taker: dec("0.01"), ..Fees::default() };
+        let result = executable_arb(&book, "bitstamp", "binance", Decimal::ZERO, &fees).unwrap();
+        assert_eq!(result, ArbResult { amount: dec("2"), profit: dec("4.99") });
+
+        // the other way round nothing crosses
+        assert_eq!(executable_arb(&book, "binance", "bitstamp", Decimal::ZERO, &no_fees), None);
+    }
+
+    #[test]
+    fn tied_prices_sort_by_amount_then_exchange() {
+        let quotes = [("kraken", "2"), ("binance", "1"), ("bitstamp", "2"), ("coinbase", "3")];
+        let expected = vec![("coinbase".to_string(), dec("3")), ("bitstamp".to_string(), dec("2")), ("kraken".to_string(), dec("2")), ("binance".to_string(), dec("1"))];
+        let ranked = |levels: Vec<&PriceLevel>| levels.into_iter().map(|level| (level.exchange.clone(), level.amount)).collect::<Vec<_>>();
+        // the order the exchanges report in makes no difference
+        for order in [[0, 1, 2, 3], [3, 2, 1, 0], [1, 3, 0, 2]] {
+            let mut book = OrderBook::new(10).unwrap();
+            for index in order {
+                let (exchange, amount) = quotes[index];
+                book.replace_exchange_levels(exchange, levels(exchange, &[("100", amount)]), levels(exchange, &[("101", amount)]));
+            }
+            assert_eq!(ranked(book.bids().iter().collect()), expected);
+            assert_eq!(ranked(book.asks().iter().collect()), expected);
+
+            // and the summary keeps that order
+            let summary = book.to_summary();
+            let exchanges: Vec<_> = summary.bids.iter().map(|level| level.exchange.as_str()).collect();
+            assert_eq!(exchanges, ["coinbase", "bitstamp", "kraken", "binance"]);
+        }
+    }
+
+    #[test]
+    fn summary_prices_stay_sorted_through_the_float_conversion() {
+        // the largest and smallest decimals, and prices a float can't tell apart
+        let quotes = [
+            ("binance", "79228162514264337593543950335", "0.0000000000000000000000000001"),
+            ("bitstamp", "0.1000000000000000000000000001", "1"),
+            ("coinbase", "0.1", "1"),
+            ("kraken", "0.0000000000000000000000000001", "79228162514264337593543950335"),
+        ];
+        let mut book = OrderBook::new(10).unwrap();
+        for (exchange, price, amount) in quotes {
+            book.replace_exchange_levels(exchange, levels(exchange, &[(price, amount)]), levels(exchange, &[(price, amount)]));
+        }
+        let summary = book.to_summary();
+
+        let prices = |levels: &[Level]| levels.iter().map(|level| level.price).collect::<Vec<_>>();
+        assert!(summary.bids.iter().chain(&summary.asks).all(|level| level.price.is_finite() && level.amount.is_finite()));
+        assert!(prices(&summary.bids).windows(2).all(|pair| pair[0] >= pair[1]), "{:?}", summary.bids);
+        assert!(prices(&summary.asks).windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", summary.asks);
+        // the float tie keeps the exact decimal order
+        let exchanges = |levels: &[Level]| levels.iter().map(|level| level.exchange.clone()).collect::<Vec<_>>();
+        assert_eq!(exchanges(&summary.bids), ["binance", "bitstamp", "coinbase", "kraken"]);
+        assert_eq!(exchanges(&summary.asks), ["kraken", "coinbase", "bitstamp", "binance"]);
+        assert_eq!(summary.bids[1].price, summary.bids[2].price);
+    }
+
+    #[test]
+    fn price_factor_converts_before_merging() {
+        let mut book = OrderBook::new(10).unwrap().with_price_factor("binance", dec("0.9995"));
+        // BTCUSDT quotes next to a BTCUSD book
+        book.replace_exchange_levels("binance", levels("binance", &[("30010", "1")]), levels("binance", &[("30020", "1")]));
+        book.replace_exchange_levels("bitstamp", levels("bitstamp", &[("30000", "2")]), levels("bitstamp", &[("30005", "2")]));
+
+        // in USDT the books cross by 5, in USD binance bids below bitstamp and asks below it too
+        let spread = book.spread().unwrap();
+        assert_eq!(spread.spread, dec("4.99"));
+        assert!(!spread.crossed);
+        assert_eq!((spread.best_bid_exchange.as_str(), spread.best_ask_exchange.as_str()), ("bitstamp", "binance"));
+        let (bids, asks) = book.into_levels();
+        // exchanges without a factor are left alone
+        assert_eq!(bids, vec![level("bitstamp", "30000", "2"), level("binance", "29994.995", "1")]);
+        assert_eq!(asks, vec![level("binance", "30004.99", "1"), level("bitstamp", "30005", "2")]);
+    }
+
+    #[test]
+    fn quote_notional_amounts_convert_to_base() {
+        let notional = levels("kraken", &[("0.05", "2"), ("0.0625", "0.5"), ("0", "3")]);
+        // 2 BTC at 0.05 is 40 ETH, a zero price has no base amount
+        assert_eq!(AmountMode::QuoteNotional.to_base(notional.clone()), levels("kraken", &[("0.05", "40"), ("0.0625", "8"), ("0", "0")]));
+        assert_eq!(AmountMode::Base.to_base(notional.clone()), notional);
+        assert_eq!(AmountMode::default(), AmountMode::Base);
+
+        // converted levels add up with base quoting exchanges at the same price
+        let mut book = OrderBook::new(10).unwrap();
+        book.replace_exchange_levels("kraken", AmountMode::QuoteNotional.to_base(levels("kraken", &[("0.05", "2")])), Vec::new());
+        book.replace_exchange_levels("binance", levels("binance", &[("0.05", "10")]), Vec::new());
+        assert_eq!(book.to_summary().bid_total_amount, 50.0);
+    }
+}
@@ -1,444 +1,674 @@
-// WebSocket crates
-use tokio::net::TcpStream;
-use tokio_tungstenite::tungstenite::protocol::Message as TMessage;
-use tungstenite::Message;
-use url::Url;
-
-use futures::stream::{self, Stream};
-use futures::StreamExt;
-use futures::SinkExt;
+// the orderbook-server binary: parses the flags, builds the books and feeds from the
+// library and serves them over gRPC
 
 use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, watch, Mutex};
 use std::sync::Arc;
-use std::pin::Pin;
-use log::error;
-use serde_json::json;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use rust_decimal::Decimal;
+use clap::Parser;
+
+// order book core
+use rust_challenge::{orderbook, load_snapshot, save_snapshot, Fees, OrderBookSnapshot, OrderBook, PriceFormat, DEFAULT_DEPTH, DEFAULT_SPREAD_EMA_ALPHA, MAX_DEPTH};
+use rust_challenge::connectors::{canonical_symbol, exchange_connectors, normalize_symbol, quoted_in_other_currency, ConnectorOptions};
+use rust_challenge::feed::{run, seed_from_rest, Feed, FeedStatus, Opportunities, ReconnectConfig, WebSocketOptions, DEFAULT_MAX_MESSAGE_SIZE, OPPORTUNITY_CHANNEL_CAPACITY, TRADE_CHANNEL_CAPACITY};
+use rust_challenge::metrics::serve_metrics;
+use rust_challenge::recording::{read_recording, Recorder, ReplaySource};
+use rust_challenge::tls::TlsConfig;
+use rust_challenge::tui::run_tui;
+use rust_challenge::webhook::run_webhook;
+use rust_challenge::server::{serve_websocket, wait_for_first_updates, MyOrderbookAggregator, SummaryHistory, DEFAULT_GRPC_ADDR, DEFAULT_SUMMARY_BUFFER};
 
 // gRPC crates
-use orderbook::orderbook_aggregator_server::{OrderbookAggregator, OrderbookAggregatorServer};
-use orderbook::{Summary, Level, Empty};
-use tonic::{Request, Response, Status};
+use orderbook::orderbook_aggregator_server::OrderbookAggregatorServer;
+use tonic::codec::CompressionEncoding;
 use tonic::transport::Server;
 
-
-// used to parse orderbook update
-use serde_json::Value;
-use env_logger;
-
-// gRPC server implementations
-mod orderbook {
-    tonic::include_proto!("orderbook"); 
+// server configuration, every flag falls back to the environment variable named after it
+#[derive(Debug, Parser)]
+#[command(name = "orderbook-server", about = "Streams the merged order book of several exchanges over gRPC")]
+pub struct Args {
+    /// Pairs to stream, comma separated, e.g. ethbtc or ETH-BTC,BTC/USDT. SYMBOL is read when SYMBOLS is unset
+    #[arg(long, env = "SYMBOLS", value_delimiter = ',')]
+    pub symbol: Vec<String>,
+    /// Levels per side in the merged book
+    #[arg(long, env = "DEPTH", default_value_t = DEFAULT_DEPTH, value_parser = parse_depth)]
+    pub depth: usize,
+    /// Levels per side sent to clients, at most DEPTH. The summary's VWAP, totals and imbalance still cover all DEPTH levels
+    #[arg(long, env = "OUTPUT_DEPTH", value_parser = parse_depth)]
+    pub output_depth: Option<usize>,
+    /// Address the gRPC server listens on
+    #[arg(long, env = "GRPC_ADDR", default_value = DEFAULT_GRPC_ADDR)]
+    pub grpc_addr: SocketAddr,
+    /// Compresses the gRPC responses for clients that accept it, only gzip is supported
+    #[arg(long, env = "GRPC_COMPRESSION", value_parser = ["gzip"])]
+    pub grpc_compression: Option<String>,
+    /// Largest websocket message in bytes accepted from an exchange, bigger ones drop the connection
+    #[arg(long, env = "MAX_MESSAGE_SIZE", default_value_t = DEFAULT_MAX_MESSAGE_SIZE)]
+    pub max_message_size: usize,
+    /// An exchange whose levels haven't changed for this many milliseconds is reported as stale and left out of opportunities
+    #[arg(long, env = "STALE_MS", default_value_t = 10_000)]
+    pub stale_ms: u64,
+    /// Consecutive failed connection attempts after which an exchange feed gives up, retries forever by default
+    #[arg(long, env = "MAX_RETRIES")]
+    pub max_retries: Option<u32>,
+    /// Streams the summaries as JSON to WebSocket clients at this address, ws://<addr>/<symbol>
+    #[arg(long, env = "WS_ADDR")]
+    pub ws_addr: Option<SocketAddr>,
+    /// Serves Prometheus metrics on /metrics at this address
+    #[arg(long, env = "METRICS_ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Shortest gap in milliseconds between two summaries sent to a client, every change is sent by default
+    #[arg(long, env = "TICK_MS", value_parser = clap::value_parser!(u64).range(10..=10_000))]
+    pub tick_ms: Option<u64>,
+    /// Summaries a slow client can fall behind by before the oldest is dropped, 1 sends it only the latest
+    #[arg(long, env = "SUMMARY_BUFFER", default_value_t = DEFAULT_SUMMARY_BUFFER, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=1024))]
+    pub summary_buffer: usize,
+    /// Keeps this many recent summaries of each book for clients asking to replay them, 0 keeps none
+    #[arg(long, env = "HISTORY", default_value_t = 0)]
+    pub history: usize,
+    /// Writes each exchange's latest update to the book at most once per window of this many milliseconds
+    #[arg(long, env = "COALESCE_MS")]
+    pub coalesce_ms: Option<u64>,
+    /// Exchanges to connect to, comma separated, all supported exchanges by default
+    #[arg(long, env = "EXCHANGES", value_delimiter = ',')]
+    pub exchanges: Vec<String>,
+    /// Log filter such as info, or info,arb::binance=debug to trace one exchange's connection and messages
+    #[arg(long, env = "RUST_LOG")]
+    pub log_level: Option<String>,
+    /// json for structured records, plain text otherwise
+    #[arg(long, env = "LOG_FORMAT")]
+    pub log_format: Option<String>,
+    /// Prints the merged books as JSON once every feed delivered a book, then exits without serving
+    #[arg(long, conflicts_with = "record")]
+    pub snapshot: bool,
+    /// Checks the symbols, exchanges, endpoints and addresses, prints what would be started and exits without connecting
+    #[arg(long)]
+    pub check_config: bool,
+    /// Draws the live merged books in the terminal alongside serving gRPC. Logs still go to stderr, redirect it
+    #[arg(long, conflicts_with_all = ["snapshot", "check_config"])]
+    pub tui: bool,
+    /// Fills the books from the exchanges' REST snapshots before their websockets deliver
+    #[arg(long, env = "REST_SNAPSHOT", conflicts_with = "replay")]
+    pub rest_snapshot: bool,
+    /// Appends every received frame to this file for later replay
+    #[arg(long)]
+    pub record: Option<String>,
+    /// Rebuilds the books from a recording instead of connecting to the exchanges
+    #[arg(long)]
+    pub replay: Option<String>,
+    /// Saves each book to <dir>/<symbol>.json on shutdown and starts from it on the next run
+    #[arg(long, env = "SNAPSHOT_DIR")]
+    pub snapshot_dir: Option<PathBuf>,
+    /// Snapshots older than this many seconds are ignored on startup
+    #[arg(long, env = "SNAPSHOT_MAX_AGE_SECS", default_value_t = 60)]
+    pub snapshot_max_age_secs: u64,
+    /// Levels with a smaller amount are left out of the books, 0 keeps every level
+    #[arg(long, env = "MIN_AMOUNT", default_value_t = Decimal::ZERO)]
+    pub min_amount: Decimal,
+    /// Weight of each new spread in the summary's spread_ema, between 0 and 1
+    #[arg(long, env = "SPREAD_EMA_ALPHA", default_value_t = DEFAULT_SPREAD_EMA_ALPHA, value_parser = parse_alpha)]
+    pub spread_ema_alpha: f64,
+    /// Streams Bitstamp's diff channel on top of a REST snapshot instead of its full book snapshots
+    #[arg(long, env = "BITSTAMP_DIFF")]
+    pub bitstamp_diff: bool,
+    /// Applies the depth to every exchange separately so each one keeps its best levels in the book
+    #[arg(long, env = "DEPTH_PER_EXCHANGE")]
+    pub depth_per_exchange: bool,
+    /// Smallest profit per unit, in the quote currency, for a cross between two exchanges to be reported
+    #[arg(long, env = "MIN_PROFIT", default_value_t = Decimal::ZERO)]
+    pub min_profit: Decimal,
+    /// Posts every reported opportunity as JSON to this url, e.g. a Slack or Discord incoming webhook
+    #[arg(long, env = "WEBHOOK_URL")]
+    pub webhook_url: Option<String>,
+    /// Seconds between two webhook posts at least, opportunities in between are not posted
+    #[arg(long, env = "WEBHOOK_INTERVAL_SECS", default_value_t = 60)]
+    pub webhook_interval_secs: u64,
+    /// Binance taker fee rate, 0.001 is 0.1%
+    #[arg(long, env = "BINANCE_TAKER_FEE", default_value = "0.001")]
+    pub binance_taker_fee: Decimal,
+    /// Bitstamp taker fee rate
+    #[arg(long, env = "BITSTAMP_TAKER_FEE", default_value = "0.004")]
+    pub bitstamp_taker_fee: Decimal,
+    /// Coinbase taker fee rate
+    #[arg(long, env = "COINBASE_TAKER_FEE", default_value = "0.006")]
+    pub coinbase_taker_fee: Decimal,
+    /// Kraken taker fee rate
+    #[arg(long, env = "KRAKEN_TAKER_FEE", default_value = "0.004")]
+    pub kraken_taker_fee: Decimal,
+    /// Decimals of a symbol's prices and amounts in summaries as SYMBOL=PRICE:AMOUNT, comma separated.
+    /// Fiat quoted pairs default to 2:8 and the others to 8:8
+    #[arg(long, env = "PRICE_FORMATS", value_delimiter = ',', value_parser = parse_price_format)]
+    pub price_format: Vec<(String, PriceFormat)>,
+    /// Converts an exchange's prices to the book's quote currency as EXCHANGE=FACTOR, comma separated, e.g.
+    /// binance=0.9995 for USDT at 0.9995 USD. Applies to the symbols the exchange lists in another quote currency
+    #[arg(long, env = "QUOTE_ADJUST", value_delimiter = ',', value_parser = parse_quote_adjust)]
+    pub quote_adjust: Vec<(String, Decimal)>,
+    /// Multiplier on the recorded pace, 0 replays without delays
+    #[arg(long, env = "REPLAY_SPEED", default_value_t = 1.0, value_parser = parse_replay_speed)]
+    pub replay_speed: f64,
 }
 
-//initiate the orderbook struct
-#[derive(Debug)]
-pub struct OrderBook {
-    bids: Vec<orderbook::Level>,
-    asks: Vec<orderbook::Level>,
-    spread: f64,
-}
+impl Args {
+    // canonical pairs from --symbol, falling back to the single SYMBOL variable
+    pub fn symbols(&self) -> anyhow::Result<Vec<String>> {
+        let values = match (self.symbol.is_empty(), env::var("SYMBOL")) {
+            (false, _) => self.symbol.clone(),
+            (true, Ok(value)) => value.split(',').map(str::to_string).collect(),
+            (true, Err(_)) => return Err(anyhow::anyhow!("--symbol, SYMBOLS or SYMBOL must be set")),
+        };
+        // first mention wins, a pair repeated anywhere in the list is streamed once
+        let mut seen = HashSet::new();
+        let symbols: Vec<String> = values
+            .iter()
+            .filter(|symbol| !symbol.trim().is_empty())
+            .map(|symbol| canonical_symbol(symbol))
+            .filter(|symbol| seen.insert(symbol.clone()))
+            .collect();
+        if symbols.is_empty() {
+            return Err(anyhow::anyhow!("no symbol configured in {:?}", values));
+        }
+        Ok(symbols)
+    }
 
-#[derive(Debug)]
-pub struct MyOrderbookAggregator {
-    pub order_book: Arc<Mutex<OrderBook>>,
-}
+    // taker fees of the lowest volume tier unless configured
+    pub fn fees(&self) -> Fees {
+        Fees {
+            binance_taker: self.binance_taker_fee,
+            bitstamp_taker: self.bitstamp_taker_fee,
+            coinbase_taker: self.coinbase_taker_fee,
+            kraken_taker: self.kraken_taker_fee,
+        }
+    }
 
-impl OrderBook {
-    pub fn calculate_spread(&mut self) {
-        if let (Some(best_bid), Some(best_ask)) = (self.bids.first(), self.asks.first()) {
-            self.spread = best_ask.price - best_bid.price;
-        } else {
-            self.spread = 0.0;
+    // the configured rounding of symbol, or the default for its quote currency
+    pub fn price_format(&self, symbol: &str) -> PriceFormat {
+        self.price_format
+            .iter()
+            .find(|(configured, _)| configured == symbol)
+            .map_or_else(|| PriceFormat::for_symbol(symbol), |(_, format)| *format)
+    }
+
+    // levels per side sent to clients, which can't be more than the book keeps
+    pub fn output_depth(&self) -> anyhow::Result<Option<usize>> {
+        match self.output_depth {
+            Some(output_depth) if output_depth > self.depth => Err(anyhow::anyhow!("output depth {} is above the depth of {} levels kept per side", output_depth, self.depth)),
+            output_depth => Ok(output_depth),
         }
     }
-    
-    pub fn merge_and_sort(&mut self, new_bids: Vec<orderbook::Level>, new_asks: Vec<orderbook::Level>) {
-        self.bids.extend(new_bids);
-        self.asks.extend(new_asks);
-    
-        // Sort bids from high to low
-        self.bids.sort_unstable_by(|a, b| b.price.partial_cmp(&a.price).unwrap_or(std::cmp::Ordering::Equal));
-        // Sort asks from low to high
-        self.asks.sort_unstable_by(|a, b| a.price.partial_cmp(&b.price).unwrap_or(std::cmp::Ordering::Equal));
-    
-        // Limit to top 10
-        self.bids.truncate(10);
-        self.asks.truncate(10);
-    
-        // Calculate the spread
-        self.calculate_spread();
-    }
-
-    pub fn truncate(&mut self, depth: usize) {
-        // Limit the depth of the order book
-        self.bids.truncate(depth);
-        self.asks.truncate(depth);
+
+    pub fn connector_options(&self) -> ConnectorOptions {
+        ConnectorOptions { depth: self.depth, bitstamp_diff: self.bitstamp_diff }
+    }
+
+    // where the book of symbol is saved, when snapshots are enabled
+    pub fn snapshot_path(&self, symbol: &str) -> Option<PathBuf> {
+        self.snapshot_dir.as_ref().map(|dir| dir.join(format!("{}.json", symbol)))
+    }
+
+    // the exchanges to connect to, rejecting names no connector answers to
+    pub fn exchanges(&self) -> anyhow::Result<Vec<String>> {
+        let supported: Vec<String> = exchange_connectors(self.connector_options()).iter().map(|connector| connector.name().to_string()).collect();
+        if self.exchanges.is_empty() {
+            return Ok(supported);
+        }
+        let mut exchanges = Vec::new();
+        for exchange in &self.exchanges {
+            let exchange = exchange.trim().to_lowercase();
+            if !supported.contains(&exchange) {
+                return Err(anyhow::anyhow!("unknown exchange {:?}, expected one of {}", exchange, supported.join(", ")));
+            }
+            exchanges.push(exchange);
+        }
+        Ok(exchanges)
     }
 }
-impl MyOrderbookAggregator {
-    pub fn new(order_book: Arc<Mutex<OrderBook>>) -> Self {
-        Self { order_book }
+
+fn parse_depth(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(depth) if (1..=MAX_DEPTH).contains(&depth) => Ok(depth),
+        _ => Err(format!("depth must be between 1 and {}, got {:?}", MAX_DEPTH, value)),
     }
 }
 
-// implementation of the gRPC server-side functions 
-#[tonic::async_trait]
-impl OrderbookAggregator for MyOrderbookAggregator {
-    type BookSummaryStream = Pin<Box<dyn Stream<Item = Result<Summary, Status>> + Send + Sync + 'static>>;
-
-    async fn book_summary(
-        &self,
-        request: Request<Empty>,
-    ) -> Result<Response<Self::BookSummaryStream>, Status> {
-        log::info!("Received request: {:?}", request);
-
-        let order_book_clone = self.order_book.clone();
-
-        let output_stream = stream::unfold(order_book_clone, |order_book| async move {
-            let data = order_book.lock().await;
-            
-            let bids = data.bids.iter().map(|level| Level {
-                exchange: level.exchange.clone(),
-                price: level.price,
-                amount: level.amount,
-            }).collect();
-            
-            let asks = data.asks.iter().map(|level| Level {
-                exchange: level.exchange.clone(),
-                price: level.price,
-                amount: level.amount,
-            }).collect();
-
-            let update = Summary {
-                bids,
-                asks,
-                spread: data.spread,
-            };
-            log::info!("Sending response: {:?}", update);
-            Some((Ok(update), Arc::clone(&order_book)))
-        });
+fn parse_price_format(value: &str) -> Result<(String, PriceFormat), String> {
+    let invalid = || format!("price format must look like ETH-BTC=5:4, got {:?}", value);
+    let (symbol, decimals) = value.split_once('=').ok_or_else(invalid)?;
+    let (price, amount) = decimals.split_once(':').ok_or_else(invalid)?;
+    let decimals = |decimals: &str| decimals.trim().parse::<u32>().ok().filter(|&decimals| decimals <= 28).ok_or_else(invalid);
+    Ok((canonical_symbol(symbol), PriceFormat { price_decimals: decimals(price)?, amount_decimals: decimals(amount)? }))
+}
+
+fn parse_quote_adjust(value: &str) -> Result<(String, Decimal), String> {
+    let invalid = || format!("quote adjustment must look like binance=0.9995 with a positive factor, got {:?}", value);
+    let (exchange, factor) = value.split_once('=').ok_or_else(invalid)?;
+    let factor = factor.trim().parse::<Decimal>().ok().filter(|factor| *factor > Decimal::ZERO).ok_or_else(invalid)?;
+    Ok((exchange.trim().to_lowercase(), factor))
+}
+
+fn parse_alpha(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(alpha) if alpha > 0.0 && alpha <= 1.0 => Ok(alpha),
+        _ => Err(format!("alpha must be above 0 and at most 1, got {:?}", value)),
+    }
+}
 
-        Ok(Response::new(Box::pin(output_stream)))
+fn parse_replay_speed(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(speed) if speed.is_finite() && speed >= 0.0 => Ok(speed),
+        _ => Err(format!("replay speed must be a non-negative number, got {:?}", value)),
     }
 }
 
+// how long --snapshot waits for every feed to deliver its first book
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    // flags first, each one falls back to its environment variable
+    let args = Args::parse();
+    if args.check_config {
+        return Ok(check_config(&args)?);
+    }
+
     // Initialize the logger
-    env_logger::init();
+    init_logging(&args)?;
+
+    let symbols = args.symbols()?;
+    let depth = args.depth;
+    let output_depth = args.output_depth()?;
+    let addr = args.grpc_addr;
+    let metrics_addr = args.metrics_addr;
+
+    // replay recorded frames from a file instead of connecting to the exchanges
+    let recording = match &args.replay {
+        Some(path) => {
+            info!("Replaying {}", path);
+            Some(read_recording(path)?)
+        }
+        None => None,
+    };
+    let replay_speed = args.replay_speed;
+
+    let tick = args.tick_ms.map(Duration::from_millis);
+    let coalesce = args.coalesce_ms.filter(|&millis| millis > 0).map(Duration::from_millis);
+    let exchanges = args.exchanges()?;
+
+    // built once up front so a bad CA bundle fails at startup
+    let websocket = WebSocketOptions { tls: TlsConfig::from_env()?.connector()?, max_message_size: args.max_message_size };
+
+    // append every received frame to a file for later replay
+    let (recorder, recorder_task) = match &args.record {
+        Some(path) => {
+            info!("Recording frames to {}", path);
+            let (recorder, task) = Recorder::spawn(path).await?;
+            (Some(recorder), Some(task))
+        }
+        None => (None, None),
+    };
+
+    // initialize shared state, one book per symbol fed by every exchange
+    let (trades_tx, _) = broadcast::channel(TRADE_CHANNEL_CAPACITY);
+    let (opportunities_tx, _) = broadcast::channel(OPPORTUNITY_CHANNEL_CAPACITY);
+    let opportunities = Opportunities { sender: opportunities_tx.clone(), min_profit: args.min_profit, fees: args.fees() };
+    let mut books = HashMap::new();
+    let mut summaries = HashMap::new();
+    let mut feeds = Vec::new();
+    for symbol in symbols {
+        let mut order_book = OrderBook::new(depth)?.with_min_amount(args.min_amount).with_price_format(args.price_format(&symbol)).with_depth_per_exchange(args.depth_per_exchange).with_spread_ema_alpha(args.spread_ema_alpha);
+        if let Some(output_depth) = output_depth {
+            order_book = order_book.with_output_depth(output_depth);
+        }
+        for (exchange, factor) in &args.quote_adjust {
+            if quoted_in_other_currency(&symbol, exchange) {
+                info!("Converting {} prices of {} by {}", exchange, symbol, factor);
+                order_book = order_book.with_price_factor(exchange, *factor);
+            }
+        }
+        // start from the last saved book rather than empty, the feeds replace it as they connect.
+        // only the exchanges streaming the symbol this run are kept
+        if let Some((snapshot, saved_at_ms)) = args.snapshot_path(&symbol).and_then(|path| load_snapshot(&path, Duration::from_secs(args.snapshot_max_age_secs))) {
+            info!("Loaded {} snapshot", symbol);
+            let fed: Vec<String> = exchanges.iter().filter(|exchange| normalize_symbol(&symbol, exchange).is_ok()).cloned().collect();
+            order_book.restore(snapshot, saved_at_ms, &fed);
+        }
+        let (summary_tx, _) = watch::channel(Arc::new(order_book.to_summary()));
+        let order_book = Arc::new(Mutex::new(order_book));
+        let summary_tx = Arc::new(summary_tx);
+        for connector in exchange_connectors(args.connector_options()).into_iter().filter(|connector| exchanges.iter().any(|name| name == connector.name())) {
+            // not every exchange lists every pair, stream the pair from the ones that do
+            let exchange_symbol = match normalize_symbol(&symbol, connector.name()) {
+                Ok(exchange_symbol) => exchange_symbol,
+                Err(e) => {
+                    warn!("Skipping {}: {}", connector.name(), e);
+                    continue;
+                }
+            };
+            let replay = recording.as_ref().map(|frames| ReplaySource::new(frames, connector.name(), &symbol, replay_speed));
+            feeds.push(Feed {
+                symbol: symbol.clone(),
+                exchange_symbol,
+                health: Arc::new(FeedStatus::new(connector.name(), &symbol)),
+                connector,
+                order_book: Arc::clone(&order_book),
+                summaries: Arc::clone(&summary_tx),
+                replay,
+                recorder: recorder.clone(),
+                stale_after: Duration::from_millis(args.stale_ms),
+            });
+        }
+        books.insert(symbol.clone(), order_book);
+        summaries.insert(symbol, summary_tx);
+    }
+    for symbol in books.keys() {
+        if !feeds.iter().any(|feed| &feed.symbol == symbol) {
+            return Err(anyhow::anyhow!("no supported exchange lists {}", symbol).into());
+        }
+    }
+    let health: Vec<Arc<FeedStatus>> = feeds.iter().map(|feed| Arc::clone(&feed.health)).collect();
+
+    // stop everything cleanly on Ctrl-C / SIGTERM
+    let shutdown = CancellationToken::new();
+    tokio::spawn(shutdown_signal(shutdown.clone()));
+
+    // expose prometheus metrics
+    if let Some(metrics_addr) = metrics_addr {
+        info!("Serving metrics on {}", metrics_addr);
+        let metrics_shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(metrics_addr, metrics_shutdown).await {
+                error!("Metrics server failed: {}", e);
+            }
+        });
+    }
 
-    // get symbol from env
-    let symbol = env::var("SYMBOL")?;
+    // alerts on a subscription of their own taken before the feeds start, so a slow endpoint only delays the alerts
+    if let Some(url) = args.webhook_url.clone() {
+        info!("Posting opportunities to the webhook at most every {}s", args.webhook_interval_secs);
+        let receiver = opportunities_tx.subscribe();
+        let interval = Duration::from_secs(args.webhook_interval_secs);
+        let webhook_shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run_webhook(url, receiver, interval, webhook_shutdown).await {
+                error!("Webhook failed: {}", e);
+            }
+        });
+    }
 
-    // initialize shared state
-    let order_book = Arc::new(Mutex::new(OrderBook {
-        bids: Vec::new(),
-        asks: Vec::new(),
-        spread: 0.0,
-    }));
+    // kept from before the feeds start, so a late subscriber can replay the first summaries too
+    let history: HashMap<String, Arc<SummaryHistory>> = match args.history {
+        0 => HashMap::new(),
+        capacity => summaries.iter().map(|(symbol, sender)| (symbol.clone(), SummaryHistory::record(sender, capacity, shutdown.clone()))).collect(),
+    };
 
+    if args.rest_snapshot {
+        seed_from_rest(&feeds, depth).await;
+    }
+
+    // stream exchange updates in the background while the gRPC server is running
+    let reconnect = ReconnectConfig { max_retries: args.max_retries, ..ReconnectConfig::default() };
+    let feeds_shutdown = shutdown.clone();
+    let feeds_trades = trades_tx.clone();
+    let feeds = tokio::spawn(async move {
+        match run(feeds, feeds_trades, opportunities, reconnect, websocket, coalesce, feeds_shutdown).await {
+            Ok(()) => info!("Completed without error."),
+            Err(err) => error!("Error occurred: {:?}", err),
+        }
+    });
 
-    let url_binance = format!("wss://stream.binance.com:9443/ws/{}@depth20@100ms", symbol);
-    let url_bitstamp = format!("wss://ws.bitstamp.net");
-    
-    match run(url_binance, url_bitstamp, symbol, Arc::clone(&order_book)).await {
-        Ok(()) => println!("Completed without error."),
-        Err(err) => eprintln!("Error occurred: {:?}", err),
+    // print the merged books once every feed delivered one and stop, without serving anything
+    if args.snapshot {
+        let ready = tokio::select! {
+            ready = tokio::time::timeout(SNAPSHOT_TIMEOUT, wait_for_first_updates(&health, &summaries)) => ready,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        shutdown.cancel();
+        feeds.await?;
+        ready.map_err(|_| anyhow::anyhow!("not every feed delivered a book within {:?}", SNAPSHOT_TIMEOUT))?;
+        let mut snapshot = serde_json::Map::new();
+        for (symbol, order_book) in &books {
+            snapshot.insert(symbol.clone(), serde_json::to_value(OrderBookSnapshot::from(&*order_book.lock().await))?);
+        }
+        println!("{}", serde_json::to_string_pretty(&snapshot)?);
+        return Ok(());
     }
 
-    // launch gRPC server
-    let addr = "[::1]:50051".parse().unwrap();
-    let orderbook_aggregator = MyOrderbookAggregator::new(Arc::clone(&order_book));
-
-    Server::builder()
-        .add_service(OrderbookAggregatorServer::new(orderbook_aggregator))
-        .serve(addr)
-        .await?;
-   
-    Ok(())
-}
+    // the live view reads the same summaries as the gRPC streams
+    if args.tui {
+        let tui_summaries = summaries.clone();
+        let tui_shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run_tui(&tui_summaries, tui_shutdown).await {
+                error!("Terminal view failed: {}", e);
+            }
+        });
+    }
 
-//Merges orderbooks fetched by websocket functions
-async fn run(url_binance: String, url_bitstamp: String, symbol: String, order_book: Arc<Mutex<OrderBook>>,) -> anyhow::Result<()> {
-    let binance_orderbook = Arc::clone(&order_book);
-    let bitstamp_orderbook = Arc::clone(&order_book);
-    let binance_symbol = symbol.clone();
-    let binance = tokio::spawn(async move {
-        connect_to_exchange(url_binance, "binance", &binance_symbol, binance_orderbook).await
-    });
-    let bitstamp = tokio::spawn(async move {
-        connect_to_exchange(url_bitstamp, "bitstamp", &symbol, bitstamp_orderbook).await
-    });
-    let _ = tokio::try_join!(binance, bitstamp)?;
-    let _order_book_guard = order_book.lock().await;
+    // launch gRPC server
+    info!("Serving gRPC on {}", addr);
+    let snapshot_books = books.clone();
+    let orderbook_aggregator = MyOrderbookAggregator::new(books, summaries, trades_tx, opportunities_tx, health, tick, shutdown.clone()).with_history(history).with_summary_buffer(args.summary_buffer);
+
+    // the same summaries as JSON for consumers that don't speak gRPC
+    if let Some(ws_addr) = args.ws_addr {
+        info!("Serving WebSocket summaries on {}", ws_addr);
+        let aggregator = orderbook_aggregator.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_websocket(ws_addr, aggregator).await {
+                error!("WebSocket server failed: {}", e);
+            }
+        });
+    }
 
-    Ok(())
-}
+    // lets grpcurl and friends discover the service without the proto file
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(orderbook::FILE_DESCRIPTOR_SET)
+        .build()?;
 
+    // clients that don't advertise gzip still get plain responses
+    let mut service = OrderbookAggregatorServer::new(orderbook_aggregator);
+    if args.grpc_compression.is_some() {
+        service = service.send_compressed(CompressionEncoding::Gzip).accept_compressed(CompressionEncoding::Gzip);
+    }
 
-// connect websocket to chosen exchange
-async fn connect_to_exchange(url: String, exchange: &str, symbol: &str, order_book: Arc<Mutex<OrderBook>>) -> anyhow::Result<()> {
-    
-    if exchange == "binance" {
-        let modified_url = Url::parse(&url).unwrap();
-        let domain = modified_url.domain().unwrap().to_string();
-        let addr = modified_url.socket_addrs(|| None).unwrap().first().unwrap().to_string();
-        let stream = TcpStream::connect(addr).await.unwrap();
-        let connector = tokio_native_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
-        let tls_stream = connector.connect(&domain, stream).await.unwrap();
-        
-        let (mut ws_stream, _) = tokio_tungstenite::client_async(&url, tls_stream).await
-            .map_err(|e| anyhow::anyhow!("Failed to connect to {}: {}", exchange, e))?;
-        //println!("Successfully connected to : {}", exchange);
-
-        let subscribe_message_binance = format!(
-            r#"{{
-                "method": "SUBSCRIBE",
-                "params": [
-                    "{}@aggTrade",
-                    "{}@depth"
-                ],
-                "id": 1
-            }}"#,
-            symbol, symbol
-        );
-        ws_stream.send(Message::Text(subscribe_message_binance)).await?;
-
-        while let Some(msg) = ws_stream.next().await {
-            match msg {
-                Ok(TMessage::Text(text)) => {
-                    
-                    let order_book_update = parse_order_book_update(&text, exchange)?;
-                    // Update shared order book
-                    let mut order_book_guard = order_book.lock().await;
-                    // Prepare new Levels from the update
-                    let new_bids: Vec<orderbook::Level> = order_book_update.bids.into_iter().map(|level| orderbook::Level {
-                        exchange: exchange.to_string(),
-                        price: level.price,
-                        amount: level.amount,
-                    }).collect();
-
-                    let new_asks: Vec<orderbook::Level> = order_book_update.asks.into_iter().map(|level| orderbook::Level {
-                        exchange: exchange.to_string(),
-                        price: level.price,
-                        amount: level.amount,
-                    }).collect();
-
-                    // Merge and sort the order books
-                    order_book_guard.merge_and_sort(new_bids, new_asks);
-
-                    break;
-                }
-                Err(e) => {
-                    error!("Error receiving message from {}: {}", exchange, e);
-                    break;
-                }
-                _ => (),
+    let served = Server::builder()
+        .add_service(service)
+        .add_service(reflection)
+        .serve_with_shutdown(addr, shutdown.cancelled())
+        .await;
+
+    // make sure the feeds stop as well if the server exited on its own
+    shutdown.cancel();
+    feeds.await?;
+
+    // the feeds are stopped, so these are the final books
+    for (symbol, order_book) in &snapshot_books {
+        if let Some(path) = args.snapshot_path(symbol) {
+            match save_snapshot(&*order_book.lock().await, &path) {
+                Ok(()) => info!("Saved {} snapshot to {}", symbol, path.display()),
+                Err(e) => error!("Failed to save {} snapshot to {}: {}", symbol, path.display(), e),
             }
         }
     }
-    
 
-    else if exchange == "bitstamp" {
+    // the feeds dropped their recorders, let the writer flush what is left
+    drop(recorder);
+    if let Some(task) = recorder_task {
+        if let Err(e) = task.await? {
+            error!("Recording failed: {}", e);
+        }
+    }
 
-        let modified_url = Url::parse(&url).unwrap();
-        let domain = modified_url.domain().unwrap().to_string();
-        let addr = modified_url.socket_addrs(|| None).unwrap().first().unwrap().to_string();
-        let stream = TcpStream::connect(addr).await.unwrap();
-        let connector = tokio_native_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
-        let tls_stream = connector.connect(&domain, stream).await.unwrap();
+    served?;
+    Ok(())
+}
 
-        let (mut ws_stream, _) = tokio_tungstenite::client_async(&url, tls_stream).await
-            .map_err(|e| anyhow::anyhow!("Failed to connect to {}: {}", exchange, e))?;
-        //println!("Successfully connected to : {}", exchange);
+// the --check-config report: every feed a start would open and the endpoints it would use. fails when
+// an endpoint doesn't parse, a symbol is listed nowhere or the TLS setup or recording can't be loaded
+fn check_config(args: &Args) -> anyhow::Result<()> {
+    let symbols = args.symbols()?;
+    let exchanges = args.exchanges()?;
+    args.output_depth()?;
+    TlsConfig::from_env()?.connector()?;
+    if let Some(path) = &args.replay {
+        let frames = read_recording(path)?;
+        println!("replay: {} frames from {}", frames.len(), path);
+    }
+    println!("grpc: {}", args.grpc_addr);
+    if let Some(ws_addr) = args.ws_addr {
+        println!("websocket: {}", ws_addr);
+    }
+    if let Some(metrics_addr) = args.metrics_addr {
+        println!("metrics: {}", metrics_addr);
+    }
 
-            
-        let subscribe_message_bitstamp = json!({
-            "event": "bts:subscribe",
-            "data": {
-                "channel": format!("order_book_{}", symbol)
-            }
-        }).to_string();
-        
-        ws_stream.send(Message::Text(subscribe_message_bitstamp)).await?;
-        
-        while let Some(msg) = ws_stream.next().await {
-            match msg {
-                Ok(TMessage::Text(text)) => {
-                    // Check the event type to ensure it is an order book update
-                    let v: Value = serde_json::from_str(&text)?;
-                    let event = v.get("event").and_then(|e| e.as_str());
-                    if event == Some("data") {
-                        let order_book_update = parse_order_book_update(&text, exchange)?;
-                        // Update shared order book
-                        let mut order_book_guard = order_book.lock().await;
-                        let new_bids: Vec<orderbook::Level> = order_book_update.bids.into_iter().map(|level| orderbook::Level {
-                            exchange: exchange.to_string(),
-                            price: level.price,
-                            amount: level.amount,
-                        }).collect();
-        
-                        let new_asks: Vec<orderbook::Level> = order_book_update.asks.into_iter().map(|level| orderbook::Level {
-                            exchange: exchange.to_string(),
-                            price: level.price,
-                            amount: level.amount,
-                        }).collect();
-        
-                        // Merge and sort the order books
-                        order_book_guard.merge_and_sort(new_bids, new_asks);
-
-                        break;
+    let mut problems = Vec::new();
+    let mut check_url = |exchange: &str, url: &str, schemes: &[&str]| match reqwest::Url::parse(url) {
+        Ok(parsed) if schemes.contains(&parsed.scheme()) => (),
+        Ok(parsed) => problems.push(format!("{}: {} should use {} rather than {}", exchange, url, schemes.join(" or "), parsed.scheme())),
+        Err(e) => problems.push(format!("{}: {} is not a valid url: {}", exchange, url, e)),
+    };
+    let connectors: Vec<_> = exchange_connectors(args.connector_options()).into_iter().filter(|connector| exchanges.iter().any(|name| name == connector.name())).collect();
+    let mut unlisted = Vec::new();
+    for symbol in &symbols {
+        println!("{}", symbol);
+        let mut listed = false;
+        for connector in &connectors {
+            match normalize_symbol(symbol, connector.name()) {
+                Ok(exchange_symbol) => {
+                    listed = true;
+                    let ws_url = connector.ws_url(&exchange_symbol);
+                    check_url(connector.name(), &ws_url, &["ws", "wss"]);
+                    match connector.rest_url() {
+                        Some(rest_url) => {
+                            check_url(connector.name(), &rest_url, &["http", "https"]);
+                            println!("  {}: {} on {}, snapshots from {}", connector.name(), exchange_symbol, ws_url, rest_url);
+                        }
+                        None => println!("  {}: {} on {}", connector.name(), exchange_symbol, ws_url),
                     }
                 }
-                Err(e) => {
-                    error!("Error receiving message from {}: {}", exchange, e);
-                    break;
-                }
-                _ => (),
+                Err(e) => println!("  {}: skipped, {}", connector.name(), e),
             }
         }
+        if !listed {
+            unlisted.push(format!("no supported exchange lists {}", symbol));
+        }
     }
+    problems.extend(unlisted);
 
+    if problems.is_empty() {
+        println!("config ok");
+        return Ok(());
+    }
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    Err(anyhow::anyhow!("{} problem(s) in the config", problems.len()))
+}
+
+// plain text logs by default, --log-format json for structured records. --log-level filters both
+fn init_logging(args: &Args) -> anyhow::Result<()> {
+    let filter = match &args.log_level {
+        Some(level) => tracing_subscriber::EnvFilter::try_new(level).map_err(|e| anyhow::anyhow!("invalid log level {:?}: {}", level, e))?,
+        None => tracing_subscriber::EnvFilter::new("error"),
+    };
+    // logs go to stderr so the --snapshot output on stdout stays parseable
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr);
+    if args.log_format.as_deref() == Some("json") {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
     Ok(())
 }
 
-// parses the data to separate bids and asks fetched and fills the orderbook based on the proto arcchitecture 
-fn parse_order_book_update(message: &str, exchange: &str) -> anyhow::Result<OrderBook> {
-    
-    let v: Value = serde_json::from_str(message)?;
-
-    if exchange == "bitstamp" {
-        if let Some(data) = v.get("data") {
-            let bids = data["bids"]
-                .as_array()
-                .ok_or(anyhow::anyhow!("bids is not an array"))?
-                .iter()
-                .map(|bid| {
-                    let price = bid[0]
-                        .as_str()
-                        .ok_or(anyhow::anyhow!("bid price is not a string"))?
-                        .parse::<f64>()
-                        .map_err(|_| anyhow::anyhow!("failed to parse bid price as f64"))?;
-
-                    let amount = bid[1]
-                        .as_str()
-                        .ok_or(anyhow::anyhow!("bid amount is not a string"))?
-                        .parse::<f64>()
-                        .map_err(|_| anyhow::anyhow!("failed to parse bid amount as f64"))?;
-
-                    Ok(orderbook::Level {
-                        exchange: exchange.to_string(),
-                        price,
-                        amount,
-                    })
-                })
-                .collect::<Result<Vec<_>, anyhow::Error>>()?;
-
-            let asks = data["asks"]
-                .as_array()
-                .ok_or(anyhow::anyhow!("asks is not an array"))?
-                .iter()
-                .map(|ask| {
-                    let price = ask[0]
-                        .as_str()
-                        .ok_or(anyhow::anyhow!("ask price is not a string"))?
-                        .parse::<f64>()
-                        .map_err(|_| anyhow::anyhow!("failed to parse ask price as f64"))?;
-
-                    let amount = ask[1]
-                        .as_str()
-                        .ok_or(anyhow::anyhow!("ask amount is not a string"))?
-                        .parse::<f64>()
-                        .map_err(|_| anyhow::anyhow!("failed to parse ask amount as f64"))?;
-
-                    Ok(orderbook::Level {
-                        exchange: exchange.to_string(),
-                        price,
-                        amount,
-                    })
-                })
-                .collect::<Result<Vec<_>, anyhow::Error>>()?;
-
-            return Ok(OrderBook { bids, asks, spread: 0.0 });
-        } else {
-            Err(anyhow::anyhow!("The message did not contain the 'data' field"))
+// cancels the token once the process is asked to stop
+async fn shutdown_signal(shutdown: CancellationToken) {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    } else {
-        let parsed_bids = v["bids"]
-            .as_array()
-            .ok_or(anyhow::anyhow!("bids is not an array"))?; 
-        
-        let bids = parsed_bids
-            .iter()
-            .map(|bid| {
-                let price = bid[0].as_str().ok_or_else(|| {
-                    let err = format!("Bid price is not a string. Value was: {:?}", bid[0]);
-                    println!("{}", &err);
-                    anyhow::anyhow!(err)
-                })?.parse::<f64>().map_err(|_| {
-                    let err = format!("Could not parse bid price as f64. Value was: {:?}", bid[0]);
-                    println!("{}", &err);
-                    anyhow::anyhow!(err)
-                })?;
-        
-                let amount = bid[1].as_str().ok_or_else(|| {
-                    let err = format!("Bid amount is not a string. Value was: {:?}", bid[1]);
-                    println!("{}", &err);
-                    anyhow::anyhow!(err)
-                })?.parse::<f64>().map_err(|_| {
-                    let err = format!("Could not parse bid amount as f64. Value was: {:?}", bid[1]);
-                    println!("{}", &err);
-                    anyhow::anyhow!(err)
-                })?;
-        
-                Ok(orderbook::Level {
-                    exchange: exchange.to_string(),
-                    price,
-                    amount,
-                })
-            })
-            .collect::<Result<Vec<_>, anyhow::Error>>()?;
-
-
-        let parsed_asks = v["asks"]
-            .as_array()
-            .ok_or(anyhow::anyhow!("asks is not an array"))?;
-
-        let asks = parsed_asks
-            .iter()
-            .map(|ask| {
-                let price = ask[0].as_str().ok_or_else(|| {
-                    let err = format!("Ask price is not a string. Value was: {:?}", ask[0]);
-                    println!("{}", &err);
-                    anyhow::anyhow!(err)
-                })?.parse::<f64>().map_err(|_| {
-                    let err = format!("Could not parse ask price as f64. Value was: {:?}", ask[0]);
-                    println!("{}", &err);
-                    anyhow::anyhow!(err)
-                })?;
-        
-                let amount = ask[1].as_str().ok_or_else(|| {
-                    let err = format!("Ask amount is not a string. Value was: {:?}", ask[1]);
-                    println!("{}", &err);
-                    anyhow::anyhow!(err)
-                })?.parse::<f64>().map_err(|_| {
-                    let err = format!("Could not parse ask amount as f64. Value was: {:?}", ask[1]);
-                    println!("{}", &err);
-                    anyhow::anyhow!(err)
-                })?;
-        
-                Ok(orderbook::Level {
-                    exchange: exchange.to_string(),
-                    price,
-                    amount,
-                })
-            })
-            .collect::<Result<Vec<_>, anyhow::Error>>()?;
-
-        Ok(OrderBook { bids, asks, spread: 0.0 })
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate => (),
     }
+    info!("Shutdown requested");
+    shutdown.cancel();
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(flags: &[&str]) -> Args {
+        Args::try_parse_from(std::iter::once("orderbook-server").chain(flags.iter().copied())).expect("valid flags")
+    }
+
+    #[test]
+    fn symbols_are_canonical_and_deduplicated() {
+        let args = args(&["--symbol", "ethbtc,BTC/USD,ETH-BTC,btc-usd,eth_btc"]);
+        assert_eq!(args.symbols().unwrap(), vec!["ETH-BTC", "BTC-USD"]);
+    }
+
+    #[test]
+    fn blank_symbols_are_an_error() {
+        assert!(args(&["--symbol", " , "]).symbols().is_err());
+    }
+
+    #[test]
+    fn depth_flag_is_validated() {
+        assert_eq!(args(&["--depth", "5"]).depth, 5);
+        assert_eq!(args(&[]).depth, DEFAULT_DEPTH);
+        assert!(Args::try_parse_from(["orderbook-server", "--depth", "0"]).is_err());
+        assert!(Args::try_parse_from(["orderbook-server", "--depth", "101"]).is_err());
+    }
+
+    #[test]
+    fn args_parse_from_a_command_line() {
+        let command_line: Vec<String> = "orderbook-server --symbol btcusdt --depth 20 --grpc-addr 127.0.0.1:6000 --tick-ms 250 --exchanges binance,kraken --log-level debug"
+            .split(' ')
+            .map(str::to_string)
+            .collect();
+        let args = Args::try_parse_from(command_line).unwrap();
+        assert_eq!(args.symbol, ["btcusdt"]);
+        assert_eq!(args.depth, 20);
+        assert_eq!(args.grpc_addr, "127.0.0.1:6000".parse().unwrap());
+        assert_eq!(args.tick_ms, Some(250));
+        assert_eq!(args.exchanges, ["binance", "kraken"]);
+        assert_eq!(args.log_level.as_deref(), Some("debug"));
+
+        assert_eq!(args.summary_buffer, DEFAULT_SUMMARY_BUFFER);
+
+        assert!(Args::try_parse_from(["orderbook-server", "--tick-ms", "5"]).is_err());
+        assert_eq!(Args::try_parse_from(["orderbook-server", "--summary-buffer", "8"]).unwrap().summary_buffer, 8);
+        assert!(Args::try_parse_from(["orderbook-server", "--summary-buffer", "0"]).is_err());
+        assert!(Args::try_parse_from(["orderbook-server", "--grpc-addr", "localhost"]).is_err());
+    }
+
+    #[test]
+    fn check_config_passes_a_good_config_and_fails_a_bad_one() {
+        assert!(check_config(&args(&["--symbol", "ethbtc,btc-usd", "--exchanges", "binance,bitstamp"])).is_ok());
+
+        // listed on no exchange
+        assert!(check_config(&args(&["--symbol", "ethbtc,doge-xyz"])).is_err());
+        assert!(check_config(&args(&["--exchanges", "binance,ftx"])).is_err());
+        assert!(check_config(&args(&["--depth", "5", "--output-depth", "10"])).is_err());
+    }
+}
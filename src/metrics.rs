@@ -0,0 +1,100 @@
+// prometheus metrics shared by the feeds, and the HTTP endpoint that serves them
+
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::convert::Infallible;
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+
+// metrics crates
+use prometheus::{Encoder, GaugeVec, Histogram, HistogramOpts, IntCounterVec, Opts, Registry, TextEncoder};
+
+// process wide prometheus metrics, served over HTTP when METRICS_ADDR is set
+pub struct Metrics {
+    pub registry: Registry,
+    pub messages_received: IntCounterVec,
+    pub reconnections: IntCounterVec,
+    pub parse_errors: IntCounterVec,
+    pub spread: GaugeVec,
+    pub update_latency: Histogram,
+    pub feed_latency: GaugeVec,
+    pub book_writes: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let messages_received = IntCounterVec::new(
+            Opts::new("orderbook_messages_received_total", "Websocket messages received per exchange"),
+            &["exchange"],
+        ).expect("valid metric");
+        let reconnections = IntCounterVec::new(
+            Opts::new("orderbook_reconnections_total", "Reconnections per exchange"),
+            &["exchange"],
+        ).expect("valid metric");
+        let parse_errors = IntCounterVec::new(
+            Opts::new("orderbook_parse_errors_total", "Messages that failed to parse per exchange"),
+            &["exchange"],
+        ).expect("valid metric");
+        let spread = GaugeVec::new(
+            Opts::new("orderbook_spread", "Current merged spread per symbol"),
+            &["symbol"],
+        ).expect("valid metric");
+        let update_latency = Histogram::with_opts(HistogramOpts::new(
+            "orderbook_update_latency_seconds",
+            "Time from receiving a message to the merged book being updated",
+        )).expect("valid metric");
+        let feed_latency = GaugeVec::new(
+            Opts::new("orderbook_feed_latency_ms", "Rolling average delay between the exchange event time and local receipt"),
+            &["exchange"],
+        ).expect("valid metric");
+
+        registry.register(Box::new(messages_received.clone())).expect("unique metric");
+        registry.register(Box::new(reconnections.clone())).expect("unique metric");
+        registry.register(Box::new(parse_errors.clone())).expect("unique metric");
+        registry.register(Box::new(spread.clone())).expect("unique metric");
+        registry.register(Box::new(update_latency.clone())).expect("unique metric");
+        let book_writes = IntCounterVec::new(
+            Opts::new("orderbook_book_writes_total", "Times a feed locked and rewrote the merged book"),
+            &["exchange"],
+        ).expect("valid metric");
+        registry.register(Box::new(feed_latency.clone())).expect("unique metric");
+        registry.register(Box::new(book_writes.clone())).expect("unique metric");
+
+        Self { registry, messages_received, reconnections, parse_errors, spread, update_latency, feed_latency, book_writes }
+    }
+
+    // renders every metric in the prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+// serves the prometheus registry on /metrics until shutdown
+pub async fn serve_metrics(addr: SocketAddr, shutdown: CancellationToken) -> Result<(), hyper::Error> {
+    let make_service = hyper::service::make_service_fn(|_| async {
+        Ok::<_, Infallible>(hyper::service::service_fn(|request: hyper::Request<hyper::Body>| async move {
+            let response = if request.uri().path() == "/metrics" {
+                hyper::Response::builder()
+                    .header(hyper::header::CONTENT_TYPE, TextEncoder::new().format_type())
+                    .body(hyper::Body::from(METRICS.render()))
+            } else {
+                hyper::Response::builder()
+                    .status(hyper::StatusCode::NOT_FOUND)
+                    .body(hyper::Body::empty())
+            };
+            Ok::<_, Infallible>(response.expect("valid response"))
+        }))
+    });
+
+    hyper::Server::try_bind(&addr)?
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+}
@@ -0,0 +1,204 @@
+// recording exchange frames to a file and replaying them instead of a live connection
+
+// WebSocket crates
+use tokio_tungstenite::tungstenite::protocol::Message as TMessage;
+
+use futures::stream;
+
+use std::collections::VecDeque;
+use tokio::sync::mpsc;
+use tokio::io::AsyncWriteExt;
+use std::time::Duration;
+use tracing::warn;
+use serde_json::json;
+
+// order book core
+use crate::ArbError;
+use crate::connectors::canonical_symbol;
+use crate::feed::{now_millis, MessageStream};
+
+// used to parse orderbook update
+use serde_json::Value;
+
+// one websocket frame received from an exchange, as stored in a recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedFrame {
+    // local receipt time in milliseconds since the epoch
+    pub timestamp: i64,
+    pub exchange: String,
+    pub symbol: String,
+    pub text: String,
+}
+
+impl RecordedFrame {
+    pub fn new(exchange: &str, symbol: &str, text: &str) -> Self {
+        Self { timestamp: now_millis(), exchange: exchange.to_string(), symbol: symbol.to_string(), text: text.to_string() }
+    }
+
+    pub fn to_line(&self) -> String {
+        json!({
+            "timestamp": self.timestamp,
+            "exchange": self.exchange,
+            "symbol": self.symbol,
+            "text": self.text,
+        })
+        .to_string()
+    }
+
+    // one recording line: {"timestamp":..,"exchange":..,"symbol":..,"text":..}
+    pub fn from_line(line: &str) -> Result<Self, ArbError> {
+        let v: Value = serde_json::from_str(line)?;
+        let field = |name: &str| {
+            v.get(name)
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| ArbError::Parse(format!("recorded frame has no {}", name)))
+        };
+        Ok(Self {
+            timestamp: v
+                .get("timestamp")
+                .and_then(Value::as_i64)
+                .ok_or_else(|| ArbError::Parse("recorded frame has no timestamp".to_string()))?,
+            exchange: field("exchange")?,
+            symbol: field("symbol")?,
+            text: field("text")?,
+        })
+    }
+}
+
+// reads every frame of a newline delimited recording, skipping blank lines
+pub fn read_recording(path: &str) -> anyhow::Result<Vec<RecordedFrame>> {
+    let content = std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("cannot read recording {}: {}", path, e))?;
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| RecordedFrame::from_line(line).map_err(|e| anyhow::anyhow!("{}:{}: {}", path, number + 1, e)))
+        .collect()
+}
+
+// frames waiting for the writer before new ones get dropped
+const RECORDER_CAPACITY: usize = 1024;
+
+const RECORDER_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// hands received frames to a background writer without ever blocking the read loop
+#[derive(Clone)]
+pub struct Recorder {
+    frames: mpsc::Sender<RecordedFrame>,
+}
+
+impl Recorder {
+    // creates the file and starts the writer, which runs until every recorder clone is dropped
+    pub async fn spawn(path: &str) -> anyhow::Result<(Self, tokio::task::JoinHandle<std::io::Result<()>>)> {
+        let file = tokio::fs::File::create(path).await.map_err(|e| anyhow::anyhow!("cannot create recording {}: {}", path, e))?;
+        let (frames, receiver) = mpsc::channel(RECORDER_CAPACITY);
+        let task = tokio::spawn(write_recording(tokio::io::BufWriter::new(file), receiver));
+        Ok((Self { frames }, task))
+    }
+
+    pub fn record(&self, frame: RecordedFrame) {
+        if let Err(mpsc::error::TrySendError::Full(frame)) = self.frames.try_send(frame) {
+            warn!("Recording is falling behind, dropped a {} frame", frame.exchange);
+        }
+    }
+}
+
+// writes one line per frame, flushing periodically and once the channel closes
+async fn write_recording<W: tokio::io::AsyncWrite + Unpin>(mut writer: W, mut frames: mpsc::Receiver<RecordedFrame>) -> std::io::Result<()> {
+    let mut flush = tokio::time::interval(RECORDER_FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(frame) => {
+                    writer.write_all(frame.to_line().as_bytes()).await?;
+                    writer.write_all(b"\n").await?;
+                }
+                None => break,
+            },
+            _ = flush.tick() => writer.flush().await?,
+        }
+    }
+    writer.flush().await
+}
+
+// recorded frames of one feed, played back at `speed` times the recorded pace (0 = no delays)
+pub struct ReplaySource {
+    frames: VecDeque<RecordedFrame>,
+    speed: f64,
+}
+
+impl ReplaySource {
+    pub fn new(frames: &[RecordedFrame], exchange: &str, symbol: &str, speed: f64) -> Self {
+        let frames = frames
+            .iter()
+            .filter(|frame| frame.exchange == exchange && canonical_symbol(&frame.symbol) == canonical_symbol(symbol))
+            .cloned()
+            .collect();
+        Self { frames, speed }
+    }
+
+    // yields the frames as websocket text messages, so they go through the same read loop as a live feed
+    pub fn into_stream(self) -> MessageStream {
+        Box::pin(stream::unfold((self, None), |(mut source, previous): (Self, Option<i64>)| async move {
+            let frame = source.frames.pop_front()?;
+            if let Some(previous) = previous {
+                let gap = (frame.timestamp - previous).max(0) as f64 / 1000.0;
+                if source.speed > 0.0 && gap > 0.0 {
+                    tokio::time::sleep(Duration::from_secs_f64(gap / source.speed)).await;
+                }
+            }
+            let timestamp = frame.timestamp;
+            Some((Ok(TMessage::Text(frame.text)), (source, Some(timestamp))))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[test]
+    fn frame_lines_round_trip() {
+        // quotes and newlines of the raw message survive being embedded in the line
+        let frame = RecordedFrame::new("bitstamp", "ethbtc", "{\"event\":\"data\",\n\"data\":{}}");
+        assert_eq!(RecordedFrame::from_line(&frame.to_line()).unwrap(), frame);
+        assert!(matches!(RecordedFrame::from_line(r#"{"timestamp":1,"exchange":"binance"}"#), Err(ArbError::Parse(_))));
+    }
+
+    #[tokio::test]
+    async fn recorded_frames_read_back() {
+        let path = std::env::temp_dir().join(format!("recording-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let frames = vec![
+            RecordedFrame::new("binance", "ethbtc", r#"{"e":"depthUpdate","b":[["0.061","1"]],"a":[]}"#),
+            RecordedFrame::new("bitstamp", "ethbtc", r#"{"event":"data","data":{"bids":[],"asks":[]}}"#),
+            RecordedFrame::new("binance", "btcusdt", "{}"),
+        ];
+        let (recorder, writer) = Recorder::spawn(path).await.unwrap();
+        for frame in &frames {
+            recorder.record(frame.clone());
+        }
+        // the writer flushes and ends once the last recorder is gone
+        drop(recorder);
+        writer.await.unwrap().unwrap();
+
+        let recorded = read_recording(path);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(recorded.unwrap(), frames);
+    }
+
+    #[tokio::test]
+    async fn replay_keeps_one_feed_in_order() {
+        let frames = vec![
+            RecordedFrame::new("binance", "ethbtc", "first"),
+            RecordedFrame::new("bitstamp", "ethbtc", "other exchange"),
+            RecordedFrame::new("binance", "btcusdt", "other symbol"),
+            RecordedFrame::new("binance", "ETH-BTC", "second"),
+        ];
+        let messages: Vec<_> = ReplaySource::new(&frames, "binance", "eth/btc", 0.0).into_stream().collect().await;
+        let texts: Vec<_> = messages.into_iter().map(|message| message.unwrap().into_text().unwrap()).collect();
+        assert_eq!(texts, ["first", "second"]);
+    }
+}
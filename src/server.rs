@@ -0,0 +1,867 @@
+// the gRPC service and the websocket endpoint, both fed from the per-symbol summaries
+
+// WebSocket crates
+use tungstenite::Message;
+
+use futures::stream::{self, Stream};
+use futures::StreamExt;
+use futures::SinkExt;
+
+use std::net::SocketAddr;
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::{broadcast, watch, Mutex, Notify};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, trace, warn};
+
+// order book core
+use crate::{level_counts_by_exchange, orderbook, same_book, summary_delta, summary_snapshot, OrderBook};
+use crate::connectors::canonical_symbol;
+use crate::feed::{FeedStatus, SummarySender};
+
+// gRPC crates
+use orderbook::orderbook_aggregator_server::OrderbookAggregator;
+use orderbook::{Summary, SummaryDelta, BookSummaryRequest, PerExchangeBooksResponse, HealthRequest, HealthResponse, Opportunity, OpportunityStreamRequest, Trade, TradeStreamRequest};
+use tonic::{Request, Response, Status};
+
+// where the gRPC server listens unless --grpc-addr is set
+pub const DEFAULT_GRPC_ADDR: &str = "[::1]:50051";
+
+// longest gap between two summaries when the book does not change
+const SUMMARY_HEARTBEAT: Duration = Duration::from_secs(5);
+
+// how often a delta stream sends the whole book again
+const DELTA_RESYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+// summaries a subscriber can fall behind by unless configured otherwise, a slow client only gets the latest
+pub const DEFAULT_SUMMARY_BUFFER: usize = 1;
+
+#[derive(Debug, Clone)]
+pub struct MyOrderbookAggregator {
+    // one merged book per symbol
+    pub books: HashMap<String, Arc<Mutex<OrderBook>>>,
+    // the latest summary of each book, rebuilt once per book update and shared by every subscriber
+    pub summaries: HashMap<String, SummarySender>,
+    // every trade seen by the connectors, for the trade streams
+    pub trades: broadcast::Sender<Trade>,
+    // arbitrage opportunities found on book updates, for the opportunity streams
+    pub opportunities: broadcast::Sender<Opportunity>,
+    // connection state of every feed
+    pub health: Vec<Arc<FeedStatus>>,
+    // shortest gap between two summaries sent to one subscriber, None sends every change
+    pub tick: Option<Duration>,
+    // summaries kept for a subscriber that is behind, the oldest is dropped once it is full
+    pub summary_buffer: usize,
+    // ends the summary streams so the server can shut down
+    pub shutdown: CancellationToken,
+    // the recent summaries of each book, empty unless the server keeps a history
+    pub history: HashMap<String, Arc<SummaryHistory>>,
+}
+
+impl MyOrderbookAggregator {
+    pub fn new(books: HashMap<String, Arc<Mutex<OrderBook>>>, summaries: HashMap<String, SummarySender>, trades: broadcast::Sender<Trade>, opportunities: broadcast::Sender<Opportunity>, health: Vec<Arc<FeedStatus>>, tick: Option<Duration>, shutdown: CancellationToken) -> Self {
+        Self { books, summaries, trades, opportunities, health, tick, summary_buffer: DEFAULT_SUMMARY_BUFFER, shutdown, history: HashMap::new() }
+    }
+
+    // lets a slow subscriber fall behind by up to summary_buffer summaries instead of only getting the latest
+    pub fn with_summary_buffer(mut self, summary_buffer: usize) -> Self {
+        assert!(summary_buffer > 0, "summary buffer must hold at least one summary");
+        self.summary_buffer = summary_buffer;
+        self
+    }
+
+    // replays these recent summaries to subscribers asking for them
+    pub fn with_history(mut self, history: HashMap<String, Arc<SummaryHistory>>) -> Self {
+        self.history = history;
+        self
+    }
+
+    // the served symbol a request refers to, an empty symbol selects it when only one symbol is served
+    fn find_symbol(&self, symbol: &str) -> Option<String> {
+        if symbol.is_empty() && self.books.len() == 1 {
+            return self.books.keys().next().cloned();
+        }
+        let symbol = canonical_symbol(symbol);
+        self.books.contains_key(&symbol).then_some(symbol)
+    }
+
+    fn find_summaries(&self, symbol: &str) -> Option<watch::Receiver<Arc<Summary>>> {
+        Some(self.summaries.get(&self.find_symbol(symbol)?)?.subscribe())
+    }
+}
+
+// the last summaries of one book, for subscribers that want to see how it got where it is
+#[derive(Debug)]
+pub struct SummaryHistory {
+    summaries: std::sync::Mutex<VecDeque<Arc<Summary>>>,
+    capacity: usize,
+}
+
+impl SummaryHistory {
+    // keeps the last capacity summaries published on summaries until shutdown. start it before the
+    // feeds so their first summaries are kept too. changes that come faster than the recorder runs
+    // are kept as their latest summary, the way a live subscriber would have seen them
+    pub fn record(summaries: &SummarySender, capacity: usize, shutdown: CancellationToken) -> Arc<Self> {
+        let history = Arc::new(Self { summaries: std::sync::Mutex::new(VecDeque::with_capacity(capacity)), capacity });
+        let recorder = Arc::clone(&history);
+        let mut summaries = summaries.subscribe();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => return,
+                    changed = summaries.changed() => if changed.is_err() {
+                        return;
+                    },
+                }
+                let summary = Arc::clone(&summaries.borrow_and_update());
+                recorder.push(summary);
+            }
+        });
+        history
+    }
+
+    // drops the oldest summary once full
+    fn push(&self, summary: Arc<Summary>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut summaries = self.summaries.lock().expect("summary history lock poisoned");
+        if summaries.len() == self.capacity {
+            summaries.pop_front();
+        }
+        summaries.push_back(summary);
+    }
+
+    // the kept summaries, oldest first
+    pub fn summaries(&self) -> Vec<Arc<Summary>> {
+        self.summaries.lock().expect("summary history lock poisoned").iter().cloned().collect()
+    }
+}
+
+// the best depth levels of each side, the summary itself when depth is 0 or it has no more levels than that
+fn limit_depth(summary: Arc<Summary>, depth: u32) -> Arc<Summary> {
+    let depth = depth as usize;
+    if depth == 0 || (summary.bids.len() <= depth && summary.asks.len() <= depth) {
+        return summary;
+    }
+    let mut summary = Summary::clone(&summary);
+    summary.bids.truncate(depth);
+    summary.asks.truncate(depth);
+    Arc::new(summary)
+}
+
+// the summaries of one book as they change, shared by the gRPC and WebSocket outputs. an unchanged book
+// is resent every SUMMARY_HEARTBEAT and, with a tick, changes are sent at most once per tick.
+// polled on its own the stream reads the newest summary of the watch channel whenever the client's
+// transport takes the next message, see buffered for what a slow client gets
+fn summary_stream(summaries: watch::Receiver<Arc<Summary>>, tick: Option<Duration>, shutdown: CancellationToken, guard: StreamGuard) -> impl Stream<Item = Arc<Summary>> + Send + Sync + 'static {
+    stream::unfold((summaries, shutdown, None::<Arc<Summary>>, tokio::time::Instant::now(), guard), move |(mut summaries, shutdown, last_sent, last_sent_at, guard)| async move {
+        loop {
+            let mut heartbeat = false;
+            if last_sent.is_some() {
+                // Wait for the next book change, resending the last summary if none arrives in time
+                let remaining = SUMMARY_HEARTBEAT.saturating_sub(last_sent_at.elapsed());
+                tokio::select! {
+                    _ = shutdown.cancelled() => return None,
+                    changed = tokio::time::timeout(remaining, summaries.changed()) => match changed {
+                        Ok(Ok(())) => (),
+                        Ok(Err(_)) => return None,
+                        Err(_) => heartbeat = true,
+                    },
+                }
+
+                // Hold back bursts of changes so a subscriber gets at most one summary per tick
+                let wait = tick.map_or(Duration::ZERO, |tick| tick.saturating_sub(last_sent_at.elapsed()));
+                if !wait.is_zero() {
+                    tokio::select! {
+                        _ = shutdown.cancelled() => return None,
+                        _ = tokio::time::sleep(wait) => (),
+                    }
+                }
+            }
+
+            // Only the pointer is cloned here, the summary was built once by the feed that changed the book
+            let update = Arc::clone(&summaries.borrow_and_update());
+
+            // Unchanged books are only resent as a heartbeat, every summary carries a new generated_at_ms
+            if heartbeat || !last_sent.as_deref().is_some_and(|last_sent| same_book(last_sent, &update)) {
+                trace!(spread = update.spread, bids = update.bids.len(), asks = update.asks.len(), "sending summary");
+                debug!(
+                    best_bid = ?update.bids.first().map(|level| &level.exchange),
+                    best_ask = ?update.asks.first().map(|level| &level.exchange),
+                    "best venues"
+                );
+                return Some((Arc::clone(&update), (summaries, shutdown, Some(update), tokio::time::Instant::now(), guard)));
+            }
+        }
+    })
+}
+
+// summaries taken off the book as they come and queued for one subscriber, capacity of them at most.
+// a slow subscriber never holds up the feeds or other clients: once it is capacity summaries behind
+// the oldest queued one is dropped, so it gets the newest capacity summaries and at 1 only the latest
+fn buffered(summaries: impl Stream<Item = Arc<Summary>> + Send + 'static, capacity: usize) -> impl Stream<Item = Arc<Summary>> + Send + Sync + 'static {
+    let buffer = Arc::new(SummaryBuffer { queue: std::sync::Mutex::new((VecDeque::with_capacity(capacity), false)), capacity, ready: Notify::new() });
+    let forwarded = Arc::clone(&buffer);
+    // ends the forwarding as soon as the subscriber goes away
+    let subscribed = CancellationToken::new();
+    let unsubscribed = subscribed.clone();
+    tokio::spawn(async move {
+        let mut summaries = std::pin::pin!(summaries);
+        loop {
+            tokio::select! {
+                _ = unsubscribed.cancelled() => return,
+                summary = summaries.next() => match summary {
+                    Some(summary) => forwarded.push(summary),
+                    None => return forwarded.finish(),
+                },
+            }
+        }
+    });
+    stream::unfold((buffer, subscribed.drop_guard()), |(buffer, unsubscribe)| async move {
+        loop {
+            match buffer.pop() {
+                Some(Some(summary)) => return Some((summary, (buffer, unsubscribe))),
+                Some(None) => buffer.ready.notified().await,
+                None => return None,
+            }
+        }
+    })
+}
+
+// the queue between a summary stream and its subscriber
+struct SummaryBuffer {
+    // the queued summaries, oldest first, and whether the stream ended
+    queue: std::sync::Mutex<(VecDeque<Arc<Summary>>, bool)>,
+    capacity: usize,
+    // wakes the subscriber, a wake-up while it isn't waiting is kept for its next wait
+    ready: Notify,
+}
+
+impl SummaryBuffer {
+    fn push(&self, summary: Arc<Summary>) {
+        let mut queue = self.queue.lock().expect("summary buffer lock poisoned");
+        if queue.0.len() == self.capacity {
+            queue.0.pop_front();
+        }
+        queue.0.push_back(summary);
+        drop(queue);
+        self.ready.notify_one();
+    }
+
+    fn finish(&self) {
+        self.queue.lock().expect("summary buffer lock poisoned").1 = true;
+        self.ready.notify_one();
+    }
+
+    // the oldest queued summary, Some(None) while there is none yet and None once the stream ended
+    fn pop(&self) -> Option<Option<Arc<Summary>>> {
+        let mut queue = self.queue.lock().expect("summary buffer lock poisoned");
+        match queue.0.pop_front() {
+            Some(summary) => Some(Some(summary)),
+            None if queue.1 => None,
+            None => Some(None),
+        }
+    }
+}
+
+// carried in a stream's state so the end of a subscription is logged. tonic drops the stream
+// as soon as the client goes away, so there is no send that can fail or task left behind
+struct StreamGuard {
+    stream: &'static str,
+    symbol: String,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        debug!("{} stream for {} closed, client disconnected or server stopping", self.stream, self.symbol);
+    }
+}
+
+// implementation of the gRPC server-side functions 
+#[tonic::async_trait]
+impl OrderbookAggregator for MyOrderbookAggregator {
+    type BookSummaryStream = Pin<Box<dyn Stream<Item = Result<Summary, Status>> + Send + Sync + 'static>>;
+
+    async fn book_summary(
+        &self,
+        request: Request<BookSummaryRequest>,
+    ) -> Result<Response<Self::BookSummaryStream>, Status> {
+        info!("Received request: {:?}", request);
+
+        let BookSummaryRequest { symbol, depth, replay_history } = request.get_ref();
+        let depth = *depth;
+        let summaries = self
+            .find_summaries(symbol)
+            .ok_or_else(|| Status::not_found(format!("symbol {:?} is not streamed by this server", symbol)))?;
+        let shutdown = self.shutdown.clone();
+        let tick = self.tick;
+        let guard = StreamGuard { stream: "summary", symbol: symbol.clone() };
+
+        // the kept summaries go first. the live stream starts with the current summary, which is
+        // usually the newest kept one and then isn't sent twice
+        let history = match self.find_symbol(symbol).and_then(|symbol| self.history.get(&symbol)) {
+            Some(history) if *replay_history => history.summaries(),
+            _ => Vec::new(),
+        };
+        let mut newest_kept = history.last().cloned();
+        let live = buffered(summary_stream(summaries, tick, shutdown, guard), self.summary_buffer).filter(move |summary| {
+            let repeated = newest_kept.take().is_some_and(|newest_kept| Arc::ptr_eq(&newest_kept, summary));
+            futures::future::ready(!repeated)
+        });
+
+        // tonic encodes owned messages, so the one copy per subscriber happens here
+        let output_stream = stream::iter(history).chain(live).map(move |summary| Summary::clone(&limit_depth(summary, depth))).map(Ok);
+
+        Ok(Response::new(Box::pin(output_stream)))
+    }
+
+    type BookSummaryDeltaStream = Pin<Box<dyn Stream<Item = Result<SummaryDelta, Status>> + Send + Sync + 'static>>;
+
+    async fn book_summary_delta(&self, request: Request<BookSummaryRequest>) -> Result<Response<Self::BookSummaryDeltaStream>, Status> {
+        info!("Received request: {:?}", request);
+
+        let BookSummaryRequest { symbol, depth, .. } = request.get_ref();
+        let depth = *depth;
+        let summaries = self
+            .find_summaries(symbol)
+            .ok_or_else(|| Status::not_found(format!("symbol {:?} is not streamed by this server", symbol)))?;
+        let guard = StreamGuard { stream: "summary delta", symbol: symbol.clone() };
+        // cut before diffing so a level pushed out of the requested depth arrives as a removal
+        let summaries = buffered(summary_stream(summaries, self.tick, self.shutdown.clone(), guard), self.summary_buffer).map(move |summary| limit_depth(summary, depth));
+
+        // the first message is a snapshot, then deltas against the previous summary with a snapshot
+        // every DELTA_RESYNC_INTERVAL so a client that went wrong recovers
+        let mut previous: Option<(Arc<Summary>, Instant)> = None;
+        let output_stream = summaries.map(move |summary| {
+            let delta = match &previous {
+                Some((last, snapshot_at)) if snapshot_at.elapsed() < DELTA_RESYNC_INTERVAL => summary_delta(last, &summary),
+                _ => summary_snapshot(&summary),
+            };
+            let snapshot_at = match &previous {
+                Some((_, snapshot_at)) if !delta.snapshot => *snapshot_at,
+                _ => Instant::now(),
+            };
+            previous = Some((summary, snapshot_at));
+            delta
+        }).map(Ok);
+
+        Ok(Response::new(Box::pin(output_stream)))
+    }
+
+    type TradeStreamStream = Pin<Box<dyn Stream<Item = Result<Trade, Status>> + Send + Sync + 'static>>;
+
+    async fn trade_stream(&self, request: Request<TradeStreamRequest>) -> Result<Response<Self::TradeStreamStream>, Status> {
+        info!("Received request: {:?}", request);
+
+        let requested = &request.get_ref().symbol;
+        let symbol = self
+            .find_symbol(requested)
+            .ok_or_else(|| Status::not_found(format!("symbol {:?} is not streamed by this server", requested)))?;
+        let trades = self.trades.subscribe();
+        let shutdown = self.shutdown.clone();
+        let guard = StreamGuard { stream: "trade", symbol: symbol.clone() };
+
+        let output_stream = stream::unfold((trades, shutdown, symbol, guard), |(mut trades, shutdown, symbol, guard)| async move {
+            loop {
+                let trade = tokio::select! {
+                    _ = shutdown.cancelled() => return None,
+                    trade = trades.recv() => trade,
+                };
+                match trade {
+                    Ok(trade) if trade.symbol == symbol => return Some((Ok(trade), (trades, shutdown, symbol, guard))),
+                    Ok(_) => (),
+                    // A slow subscriber misses trades rather than holding up the feeds
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => warn!("Trade stream for {} skipped {} trades", symbol, skipped),
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(output_stream)))
+    }
+
+    type OpportunityStreamStream = Pin<Box<dyn Stream<Item = Result<Opportunity, Status>> + Send + Sync + 'static>>;
+
+    async fn opportunity_stream(&self, request: Request<OpportunityStreamRequest>) -> Result<Response<Self::OpportunityStreamStream>, Status> {
+        info!("Received request: {:?}", request);
+
+        let requested = &request.get_ref().symbol;
+        let symbol = self
+            .find_symbol(requested)
+            .ok_or_else(|| Status::not_found(format!("symbol {:?} is not streamed by this server", requested)))?;
+        let opportunities = self.opportunities.subscribe();
+        let shutdown = self.shutdown.clone();
+        let guard = StreamGuard { stream: "opportunity", symbol: symbol.clone() };
+
+        let output_stream = stream::unfold((opportunities, shutdown, symbol, guard), |(mut opportunities, shutdown, symbol, guard)| async move {
+            loop {
+                let opportunity = tokio::select! {
+                    _ = shutdown.cancelled() => return None,
+                    opportunity = opportunities.recv() => opportunity,
+                };
+                match opportunity {
+                    Ok(opportunity) if opportunity.symbol == symbol => return Some((Ok(opportunity), (opportunities, shutdown, symbol, guard))),
+                    Ok(_) => (),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => warn!("Opportunity stream for {} skipped {} opportunities", symbol, skipped),
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(output_stream)))
+    }
+
+    async fn per_exchange_books(&self, request: Request<BookSummaryRequest>) -> Result<Response<PerExchangeBooksResponse>, Status> {
+        let BookSummaryRequest { symbol: requested, depth, .. } = request.get_ref();
+        let book = self
+            .find_symbol(requested)
+            .and_then(|symbol| self.books.get(&symbol))
+            .ok_or_else(|| Status::not_found(format!("symbol {:?} is not streamed by this server", requested)))?;
+        let mut books = book.lock().await.exchange_books();
+        if *depth > 0 {
+            for book in &mut books {
+                book.bids.truncate(*depth as usize);
+                book.asks.truncate(*depth as usize);
+            }
+        }
+        Ok(Response::new(PerExchangeBooksResponse { books }))
+    }
+
+    async fn health(&self, _request: Request<HealthRequest>) -> Result<Response<HealthResponse>, Status> {
+        let mut feeds = Vec::with_capacity(self.health.len());
+        for status in &self.health {
+            let mut feed = status.to_proto();
+            if let Some(book) = self.books.get(&status.symbol) {
+                let counts = level_counts_by_exchange(&*book.lock().await);
+                feed.merged_levels = counts.get(&status.exchange).copied().unwrap_or_default() as u32;
+            }
+            feeds.push(feed);
+        }
+        Ok(Response::new(HealthResponse { feeds }))
+    }
+}
+
+// resolves once every feed has written to its book at least once or given up
+pub async fn wait_for_first_updates(health: &[Arc<FeedStatus>], summaries: &HashMap<String, SummarySender>) {
+    // subscribe before checking so an update in between still wakes us up
+    let mut receivers: Vec<_> = summaries.values().map(|sender| sender.subscribe()).collect();
+    // a feed that gave up will never deliver, the books come from the others
+    while !health.iter().all(|status| status.last_update_ms.load(Ordering::Relaxed) > 0 || status.failed.load(Ordering::Relaxed)) {
+        let changes = receivers.iter_mut().map(|receiver| Box::pin(receiver.changed()));
+        let _ = futures::future::select_all(changes).await;
+    }
+}
+
+// accepts WebSocket clients until shutdown, each one streams the book named by its path as JSON summaries
+pub async fn serve_websocket(addr: SocketAddr, aggregator: MyOrderbookAggregator) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    loop {
+        let (socket, peer) = tokio::select! {
+            _ = aggregator.shutdown.cancelled() => return Ok(()),
+            accepted = listener.accept() => accepted?,
+        };
+        let aggregator = aggregator.clone();
+        tokio::spawn(async move {
+            if let Err(e) = stream_websocket(socket, &aggregator).await {
+                warn!("WebSocket client {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+// keeps the path of the WebSocket upgrade request
+struct RequestPath<'a>(&'a mut String);
+
+impl tungstenite::handshake::server::Callback for RequestPath<'_> {
+    fn on_request(self, request: &tungstenite::handshake::server::Request, response: tungstenite::handshake::server::Response) -> Result<tungstenite::handshake::server::Response, tungstenite::handshake::server::ErrorResponse> {
+        *self.0 = request.uri().path().trim_matches('/').to_string();
+        Ok(response)
+    }
+}
+
+async fn stream_websocket(socket: tokio::net::TcpStream, aggregator: &MyOrderbookAggregator) -> Result<(), tungstenite::Error> {
+    // the symbol is the request path, ws://host:port/ethbtc, empty when only one symbol is served
+    let mut path = String::new();
+    let ws_stream = tokio_tungstenite::accept_hdr_async(socket, RequestPath(&mut path)).await?;
+    let (mut ws_sink, mut ws_stream) = ws_stream.split();
+
+    let symbol = aggregator.find_symbol(&path);
+    let Some(summaries) = aggregator.find_summaries(&path) else {
+        let reason = format!("symbol {:?} is not streamed by this server", path);
+        let frame = tungstenite::protocol::CloseFrame { code: tungstenite::protocol::frame::coding::CloseCode::Policy, reason: reason.into() };
+        return ws_sink.send(Message::Close(Some(frame))).await;
+    };
+    let guard = StreamGuard { stream: "websocket", symbol: symbol.unwrap_or(path) };
+    let mut summaries = Box::pin(buffered(summary_stream(summaries, aggregator.tick, aggregator.shutdown.clone(), guard), aggregator.summary_buffer));
+
+    loop {
+        tokio::select! {
+            summary = summaries.next() => match summary {
+                Some(summary) => {
+                    let text = serde_json::to_string(&*summary).expect("summary serializes");
+                    ws_sink.send(Message::Text(text)).await?;
+                }
+                None => return ws_sink.send(Message::Close(None)).await,
+            },
+            // clients only ever close, pings are answered by tungstenite on the next send
+            message = ws_stream.next() => match message {
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Err(e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use orderbook::Level;
+    use orderbook::orderbook_aggregator_client::OrderbookAggregatorClient;
+    use orderbook::orderbook_aggregator_server::OrderbookAggregatorServer;
+    use tonic::codec::CompressionEncoding;
+
+    fn level(exchange: &str, price: f64, amount: f64, timestamp_ms: i64) -> Level {
+        Level { exchange: exchange.to_string(), price, amount, timestamp_ms }
+    }
+
+    fn summary(bids: Vec<Level>, asks: Vec<Level>, generated_at_ms: i64) -> Arc<Summary> {
+        Arc::new(Summary { bids, asks, generated_at_ms, ..Summary::default() })
+    }
+
+    fn guard() -> StreamGuard {
+        StreamGuard { stream: "test", symbol: "ETH-BTC".to_string() }
+    }
+
+    // the next summary of the stream, None if none comes within a while
+    async fn next_within<S: Stream<Item = Arc<Summary>> + Unpin>(stream: &mut S, wait: Duration) -> Option<Arc<Summary>> {
+        tokio::time::timeout(wait, stream.next()).await.ok().flatten()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn changes_are_sent_a_tick_apart() {
+        let tick = Duration::from_millis(250);
+        let (sender, receiver) = watch::channel(summary(vec![level("binance", 100.0, 1.0, 1_000)], vec![], 1_000));
+        let mut stream = Box::pin(summary_stream(receiver, Some(tick), CancellationToken::new(), guard()));
+        assert_eq!(next_within(&mut stream, Duration::from_secs(1)).await.unwrap().generated_at_ms, 1_000);
+        let first_sent_at = tokio::time::Instant::now();
+
+        // both published right away, the second replaces the first before the tick is up
+        sender.send(summary(vec![level("binance", 100.5, 1.0, 2_000)], vec![], 2_000)).unwrap();
+        sender.send(summary(vec![level("binance", 100.6, 1.0, 3_000)], vec![], 3_000)).unwrap();
+        assert_eq!(next_within(&mut stream, Duration::from_secs(1)).await.unwrap().generated_at_ms, 3_000);
+        assert_eq!(first_sent_at.elapsed(), tick);
+
+        // a change arriving after a quiet tick goes out at once
+        tokio::time::advance(tick * 2).await;
+        let published_at = tokio::time::Instant::now();
+        sender.send(summary(vec![level("binance", 100.7, 1.0, 4_000)], vec![], 4_000)).unwrap();
+        assert_eq!(next_within(&mut stream, Duration::from_secs(1)).await.unwrap().generated_at_ms, 4_000);
+        assert_eq!(published_at.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_gets_only_the_newest_summary() {
+        let (sender, receiver) = watch::channel(summary(vec![level("binance", 100.0, 1.0, 0)], Vec::new(), 0));
+        let mut stream = Box::pin(summary_stream(receiver, None, CancellationToken::new(), guard()));
+        assert!(next_within(&mut stream, Duration::from_secs(1)).await.is_some());
+
+        // the subscriber doesn't read while the book changes ten times
+        for amount in 2..=11 {
+            sender.send_replace(summary(vec![level("binance", 100.0, f64::from(amount), 0)], Vec::new(), i64::from(amount)));
+        }
+        let latest = next_within(&mut stream, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(latest.bids[0].amount, 11.0);
+        assert_eq!(latest.generated_at_ms, 11);
+        // the ones in between are gone rather than queued
+        assert_eq!(next_within(&mut stream, Duration::from_millis(100)).await, None);
+    }
+
+    #[tokio::test]
+    async fn restamped_book_is_not_resent() {
+        let (sender, receiver) = watch::channel(summary(vec![level("binance", 100.0, 1.0, 1_000)], vec![level("binance", 101.0, 1.0, 1_000)], 1_000));
+        let mut stream = Box::pin(summary_stream(receiver, None, CancellationToken::new(), guard()));
+        assert_eq!(next_within(&mut stream, Duration::from_secs(1)).await.unwrap().generated_at_ms, 1_000);
+
+        // only the time fields moved
+        sender.send_replace(summary(vec![level("binance", 100.0, 1.0, 2_000)], vec![level("binance", 101.0, 1.0, 2_000)], 2_000));
+        assert_eq!(next_within(&mut stream, Duration::from_millis(100)).await, None);
+
+        sender.send_replace(summary(vec![level("binance", 100.0, 2.0, 3_000)], vec![level("binance", 101.0, 1.0, 3_000)], 3_000));
+        assert_eq!(next_within(&mut stream, Duration::from_secs(1)).await.unwrap().generated_at_ms, 3_000);
+    }
+
+    #[tokio::test]
+    async fn identical_books_are_sent_once() {
+        let book = || summary(vec![level("binance", 100.0, 1.0, 1_000)], vec![level("bitstamp", 101.0, 2.0, 1_000)], 1_000);
+        let (sender, receiver) = watch::channel(book());
+        let mut stream = Box::pin(summary_stream(receiver, None, CancellationToken::new(), guard()));
+        assert!(next_within(&mut stream, Duration::from_secs(1)).await.is_some());
+        for _ in 0..5 {
+            sender.send_replace(book());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // well inside the heartbeat
+        assert_eq!(next_within(&mut stream, Duration::from_millis(200)).await, None);
+    }
+
+    #[tokio::test]
+    async fn full_summary_buffer_drops_the_oldest() {
+        for (capacity, expected) in [(1, vec![11]), (3, vec![9, 10, 11])] {
+            let (sender, receiver) = watch::channel(summary(vec![level("binance", 100.0, 1.0, 0)], Vec::new(), 1));
+            let mut stream = Box::pin(buffered(summary_stream(receiver, None, CancellationToken::new(), guard()), capacity));
+            assert_eq!(next_within(&mut stream, Duration::from_secs(1)).await.unwrap().generated_at_ms, 1);
+
+            // each change is taken off the book while the subscriber isn't reading
+            for amount in 2..=11 {
+                sender.send_replace(summary(vec![level("binance", 100.0, f64::from(amount), 0)], Vec::new(), i64::from(amount)));
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            let mut received = Vec::new();
+            while let Some(summary) = next_within(&mut stream, Duration::from_millis(100)).await {
+                received.push(summary.generated_at_ms);
+            }
+            assert_eq!(received, expected, "buffer of {}", capacity);
+        }
+    }
+
+    // an aggregator serving one symbol, with the sender its feeds would publish on
+    fn aggregator(symbol: &str) -> (MyOrderbookAggregator, SummarySender) {
+        let order_book = OrderBook::new(10).unwrap();
+        let (summaries, _) = watch::channel(Arc::new(order_book.to_summary()));
+        let summaries = Arc::new(summaries);
+        let (trades, _) = broadcast::channel(16);
+        let (opportunities, _) = broadcast::channel(16);
+        let aggregator = MyOrderbookAggregator::new(
+            HashMap::from([(symbol.to_string(), Arc::new(Mutex::new(order_book)))]),
+            HashMap::from([(symbol.to_string(), Arc::clone(&summaries))]),
+            trades,
+            opportunities,
+            Vec::new(),
+            None,
+            CancellationToken::new(),
+        );
+        (aggregator, summaries)
+    }
+
+    fn summary_request(symbol: &str) -> Request<BookSummaryRequest> {
+        Request::new(BookSummaryRequest { symbol: symbol.to_string(), depth: 0, replay_history: false })
+    }
+
+    #[tokio::test]
+    async fn gzipped_summaries_arrive_intact() {
+        let (aggregator, summaries) = aggregator("ETH-BTC");
+        let service = OrderbookAggregatorServer::new(aggregator).send_compressed(CompressionEncoding::Gzip).accept_compressed(CompressionEncoding::Gzip);
+        let (client, server) = serve_service(service).await;
+        let mut client = client.accept_compressed(CompressionEncoding::Gzip);
+        let published = summary(vec![level("binance", 0.061, 1.5, 1_000), level("bitstamp", 0.0609, 2.0, 900)], vec![level("bitstamp", 0.062, 0.25, 900)], 1_000);
+        summaries.send_replace(Arc::clone(&published));
+
+        let response = client.book_summary(summary_request("ETH-BTC")).await.unwrap();
+        assert_eq!(response.metadata().get("grpc-encoding").unwrap(), "gzip");
+        let received = response.into_inner().message().await.unwrap().unwrap();
+        assert_eq!(received, *published);
+
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn summary_follows_an_update_within_milliseconds() {
+        let (aggregator, summaries) = aggregator("ETH-BTC");
+        let mut stream = aggregator.book_summary(summary_request("ETH-BTC")).await.unwrap().into_inner();
+        // the current book first
+        assert!(stream.next().await.unwrap().is_ok());
+
+        for amount in [1.0, 2.0, 3.0] {
+            let updated_at = Instant::now();
+            summaries.send_replace(summary(vec![level("binance", 100.0, amount, 0)], Vec::new(), 0));
+            let received = tokio::time::timeout(Duration::from_secs(1), stream.next()).await.unwrap().unwrap().unwrap();
+            assert_eq!(received.bids[0].amount, amount);
+            assert!(updated_at.elapsed() < Duration::from_millis(20), "took {:?}", updated_at.elapsed());
+        }
+    }
+
+    #[tokio::test]
+    async fn book_summary_validates_the_symbol_and_clamps_the_depth() {
+        let (aggregator, summaries) = aggregator("ETH-BTC");
+        let bids = vec![level("binance", 0.0613, 1.0, 0), level("bitstamp", 0.0612, 1.0, 0), level("binance", 0.0611, 1.0, 0)];
+        summaries.send_replace(summary(bids, vec![level("bitstamp", 0.0614, 1.0, 0)], 0));
+        let first_summary = |symbol: &str, depth: u32| {
+            let request = Request::new(BookSummaryRequest { symbol: symbol.to_string(), depth, replay_history: false });
+            let aggregator = &aggregator;
+            async move {
+                let mut stream = aggregator.book_summary(request).await?.into_inner();
+                stream.next().await.expect("the current summary")
+            }
+        };
+
+        // any spelling of a served pair
+        assert_eq!(first_summary("ethbtc", 0).await.unwrap().bids.len(), 3);
+        assert_eq!(first_summary("ETH/BTC", 2).await.unwrap().bids.len(), 2);
+        // anything above the server's depth sends what it has
+        let clamped = first_summary("ETH-BTC", 1_000).await.unwrap();
+        assert_eq!((clamped.bids.len(), clamped.asks.len()), (3, 1));
+
+        let unknown = first_summary("DOGE-USD", 0).await.unwrap_err();
+        assert_eq!(unknown.code(), tonic::Code::NotFound);
+        assert!(unknown.message().contains("DOGE-USD"));
+    }
+
+    #[tokio::test]
+    async fn late_subscriber_receives_the_history_first() {
+        let (aggregator, summaries) = aggregator("ETH-BTC");
+        let history = SummaryHistory::record(&summaries, 3, CancellationToken::new());
+        let aggregator = aggregator.with_history(HashMap::from([("ETH-BTC".to_string(), Arc::clone(&history))]));
+        for at_ms in 1..=5 {
+            summaries.send_replace(summary(vec![level("binance", 100.0, at_ms as f64, 0)], Vec::new(), at_ms));
+            // lets the recorder see every change
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let request = Request::new(BookSummaryRequest { symbol: "ETH-BTC".to_string(), depth: 0, replay_history: true });
+        let mut stream = aggregator.book_summary(request).await.unwrap().into_inner();
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(stream.next().await.unwrap().unwrap().generated_at_ms);
+        }
+        // the oldest two fell out, and the newest kept one is the current book so it isn't sent again
+        assert_eq!(received, [3, 4, 5]);
+        summaries.send_replace(summary(vec![level("binance", 100.0, 6.0, 0)], Vec::new(), 6));
+        let live = tokio::time::timeout(Duration::from_secs(1), stream.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(live.generated_at_ms, 6);
+
+        // without asking for it, only the current book
+        let mut stream = aggregator.book_summary(summary_request("ETH-BTC")).await.unwrap().into_inner();
+        assert_eq!(stream.next().await.unwrap().unwrap().generated_at_ms, 6);
+    }
+
+    // a gRPC server for aggregator on a local port, and a client connected to it
+    async fn serve(aggregator: MyOrderbookAggregator) -> (OrderbookAggregatorClient<tonic::transport::Channel>, TestServer) {
+        serve_service(OrderbookAggregatorServer::new(aggregator)).await
+    }
+
+    // the same for a service configured by the test, e.g. with compression
+    async fn serve_service(service: OrderbookAggregatorServer<MyOrderbookAggregator>) -> (OrderbookAggregatorClient<tonic::transport::Channel>, TestServer) {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_shutdown(addr, shutdown.clone().cancelled_owned()),
+        );
+        for _ in 0..50 {
+            if let Ok(client) = OrderbookAggregatorClient::connect(format!("http://{}", addr)).await {
+                return (client, TestServer { shutdown, task });
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("server did not start on {}", addr);
+    }
+
+    struct TestServer {
+        shutdown: CancellationToken,
+        task: tokio::task::JoinHandle<Result<(), tonic::transport::Error>>,
+    }
+
+    impl TestServer {
+        // shuts the server down, failing the test if it panicked or errored
+        async fn stop(self) {
+            self.shutdown.cancel();
+            self.task.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn health_reports_the_connected_exchange() {
+        let (mut aggregator, _summaries) = aggregator("ETH-BTC");
+        let binance = Arc::new(FeedStatus::new("binance", "ETH-BTC"));
+        binance.connected.store(true, Ordering::Relaxed);
+        binance.last_update_ms.store(1_700_000_000_000, Ordering::Relaxed);
+        binance.message_count.store(42, Ordering::Relaxed);
+        aggregator.health = vec![Arc::clone(&binance), Arc::new(FeedStatus::new("bitstamp", "ETH-BTC"))];
+        let levels = |price: &str| vec![crate::PriceLevel { exchange: "binance".to_string(), price: price.parse().unwrap(), amount: 1.into() }];
+        aggregator.books["ETH-BTC"].lock().await.replace_exchange_levels("binance", levels("0.061"), levels("0.062"));
+
+        let (mut client, server) = serve(aggregator).await;
+        let feeds = client.health(HealthRequest {}).await.unwrap().into_inner().feeds;
+
+        assert_eq!(feeds.len(), 2);
+        let binance = feeds.iter().find(|feed| feed.exchange == "binance").unwrap();
+        assert!(binance.connected && !binance.failed);
+        assert_eq!(binance.last_update_ms, 1_700_000_000_000);
+        assert_eq!(binance.message_count, 42);
+        assert_eq!(binance.merged_levels, 2);
+        let bitstamp = feeds.iter().find(|feed| feed.exchange == "bitstamp").unwrap();
+        assert!(!bitstamp.connected);
+        assert_eq!((bitstamp.last_update_ms, bitstamp.merged_levels), (0, 0));
+
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn dropped_client_stream_ends_the_subscription() {
+        let (aggregator, summaries) = aggregator("ETH-BTC");
+        let (mut client, server) = serve(aggregator).await;
+        let mut stream = client.book_summary(BookSummaryRequest { symbol: "ETH-BTC".to_string(), ..Default::default() }).await.unwrap().into_inner();
+        assert!(stream.message().await.unwrap().is_some());
+        assert_eq!(summaries.receiver_count(), 1);
+
+        drop(stream);
+        // the server drops the subscription's stream, and with it the watch receiver
+        for _ in 0..100 {
+            if summaries.receiver_count() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(summaries.receiver_count(), 0);
+        summaries.send_replace(summary(vec![level("binance", 100.0, 1.0, 0)], Vec::new(), 0));
+
+        // and keeps serving everyone else
+        let mut stream = client.book_summary(BookSummaryRequest { symbol: "ETH-BTC".to_string(), ..Default::default() }).await.unwrap().into_inner();
+        assert_eq!(stream.message().await.unwrap().unwrap().bids.len(), 1);
+        drop(stream);
+        server.stop().await;
+    }
+
+    // a websocket client of the server at addr for path, retried while the server starts
+    async fn websocket_client(addr: SocketAddr, path: &str) -> tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>> {
+        for _ in 0..50 {
+            if let Ok((client, _)) = tokio_tungstenite::connect_async(format!("ws://{}/{}", addr, path)).await {
+                return client;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("websocket server did not start on {}", addr);
+    }
+
+    #[tokio::test]
+    async fn websocket_client_receives_a_json_summary() {
+        let (aggregator, summaries) = aggregator("ETH-BTC");
+        summaries.send_replace(summary(vec![level("binance", 0.061, 1.5, 1_000)], vec![level("bitstamp", 0.062, 2.0, 1_000)], 1_000));
+        let shutdown = aggregator.shutdown.clone();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let server = tokio::spawn(serve_websocket(addr, aggregator));
+
+        let mut client = websocket_client(addr, "ethbtc").await;
+        let Some(Ok(Message::Text(text))) = client.next().await else {
+            panic!("no summary frame");
+        };
+        let summary: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(summary["bids"][0]["exchange"], "binance");
+        assert_eq!(summary["bids"][0]["price"], 0.061);
+        assert_eq!(summary["asks"][0]["amount"], 2.0);
+        assert_eq!(summary["generated_at_ms"], 1_000);
+
+        // a symbol that isn't served is refused with a close frame
+        let mut refused = websocket_client(addr, "dogeusd").await;
+        assert!(matches!(refused.next().await, Some(Ok(Message::Close(Some(frame)))) if frame.reason.contains("dogeusd")));
+
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+// the TLS backend of the exchange connections, native-tls by default or rustls with the rustls feature
+
+use std::env;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("enable the native-tls or the rustls feature");
+
+// how exchange certificates are verified, the trusted roots plus an optional extra CA bundle.
+// native-tls trusts the system roots, rustls the bundled webpki roots
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    // PEM file with one or more extra root certificates, e.g. a corporate proxy CA
+    pub ca_bundle: Option<PathBuf>,
+    // skips certificate verification entirely, only meant for debugging behind intercepting proxies
+    pub danger_accept_invalid_certs: bool,
+}
+
+impl TlsConfig {
+    // reads TLS_CA_BUNDLE and TLS_DANGER_ACCEPT_INVALID_CERTS
+    pub fn from_env() -> anyhow::Result<Self> {
+        let danger_accept_invalid_certs = match env::var("TLS_DANGER_ACCEPT_INVALID_CERTS") {
+            Ok(value) => value
+                .parse::<bool>()
+                .map_err(|_| anyhow::anyhow!("TLS_DANGER_ACCEPT_INVALID_CERTS must be true or false, got {:?}", value))?,
+            Err(_) => false,
+        };
+        Ok(Self {
+            ca_bundle: env::var_os("TLS_CA_BUNDLE").map(Into::into),
+            danger_accept_invalid_certs,
+        })
+    }
+
+    // the CA bundle's path and PEM contents, if one is configured
+    fn read_ca_bundle(&self) -> anyhow::Result<Option<(&Path, Vec<u8>)>> {
+        let Some(path) = &self.ca_bundle else {
+            return Ok(None);
+        };
+        let pem = std::fs::read(path).map_err(|e| anyhow::anyhow!("cannot read CA bundle {}: {}", path.display(), e))?;
+        Ok(Some((path, pem)))
+    }
+
+    #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+    pub fn connector(&self) -> anyhow::Result<TlsConnector> {
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some((path, pem)) = self.read_ca_bundle()? {
+            let path = path.display();
+            // from_pem only reads the first certificate of a bundle, so each one is added on its own
+            let certificates = pem_certificates(&pem);
+            if certificates.is_empty() {
+                return Err(anyhow::anyhow!("invalid CA bundle {}: no certificates found", path));
+            }
+            for pem in certificates {
+                let certificate = native_tls::Certificate::from_pem(pem)
+                    .map_err(|e| anyhow::anyhow!("invalid CA bundle {}: {}", path, e))?;
+                builder.add_root_certificate(certificate);
+            }
+        }
+        if self.danger_accept_invalid_certs {
+            warn!("TLS certificate verification is disabled");
+            builder.danger_accept_invalid_certs(true);
+        }
+        Ok(TlsConnector(builder.build()?))
+    }
+
+    #[cfg(feature = "rustls")]
+    pub fn connector(&self) -> anyhow::Result<TlsConnector> {
+        use tokio_rustls::rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore};
+
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+        }));
+        if let Some((path, pem)) = self.read_ca_bundle()? {
+            let path = path.display();
+            let certificates = rustls_pemfile::certs(&mut pem.as_slice()).map_err(|e| anyhow::anyhow!("invalid CA bundle {}: {}", path, e))?;
+            if certificates.is_empty() {
+                return Err(anyhow::anyhow!("invalid CA bundle {}: no certificates found", path));
+            }
+            for certificate in certificates {
+                roots.add(&Certificate(certificate)).map_err(|e| anyhow::anyhow!("invalid CA bundle {}: {}", path, e))?;
+            }
+        }
+        let mut config = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth();
+        if self.danger_accept_invalid_certs {
+            warn!("TLS certificate verification is disabled");
+            config.dangerous().set_certificate_verifier(std::sync::Arc::new(AcceptAnyCertificate));
+        }
+        Ok(TlsConnector(std::sync::Arc::new(config)))
+    }
+}
+
+// the PEM blocks of a bundle, one per certificate and each ending with its END line
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+fn pem_certificates(pem: &[u8]) -> Vec<&[u8]> {
+    const BEGIN: &[u8] = b"-----BEGIN CERTIFICATE-----";
+    const END: &[u8] = b"-----END CERTIFICATE-----";
+    let mut certificates = Vec::new();
+    let mut rest = pem;
+    while let Some(start) = rest.windows(BEGIN.len()).position(|window| window == BEGIN) {
+        // a truncated last certificate is kept so parsing it reports the bundle as invalid
+        let Some(length) = rest[start..].windows(END.len()).position(|window| window == END) else {
+            certificates.push(&rest[start..]);
+            break;
+        };
+        let end = start + length + END.len();
+        certificates.push(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    certificates
+}
+
+// a configured client side of the TLS backend, cheap to clone for every connection attempt
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+#[derive(Clone)]
+pub struct TlsConnector(native_tls::TlsConnector);
+
+#[cfg(feature = "rustls")]
+#[derive(Clone)]
+pub struct TlsConnector(std::sync::Arc<tokio_rustls::rustls::ClientConfig>);
+
+impl TlsConnector {
+    // the same connector in the form tokio-tungstenite expects for wss:// urls
+    pub fn websocket_connector(&self) -> tokio_tungstenite::Connector {
+        #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+        return tokio_tungstenite::Connector::NativeTls(self.0.clone());
+        #[cfg(feature = "rustls")]
+        return tokio_tungstenite::Connector::Rustls(std::sync::Arc::clone(&self.0));
+    }
+}
+
+// TLS_DANGER_ACCEPT_INVALID_CERTS for rustls, which has no switch for it
+#[cfg(feature = "rustls")]
+struct AcceptAnyCertificate;
+
+#[cfg(feature = "rustls")]
+impl tokio_rustls::rustls::client::ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &tokio_rustls::rustls::Certificate,
+        _intermediates: &[tokio_rustls::rustls::Certificate],
+        _server_name: &tokio_rustls::rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<tokio_rustls::rustls::client::ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(tokio_rustls::rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CA_BUNDLE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/ca_bundle.pem");
+
+    #[test]
+    fn loads_a_custom_ca_bundle() {
+        let config = TlsConfig { ca_bundle: Some(CA_BUNDLE.into()), danger_accept_invalid_certs: false };
+        assert!(config.connector().is_ok());
+    }
+
+    #[test]
+    fn missing_ca_bundle_is_an_error() {
+        let config = TlsConfig { ca_bundle: Some("/nonexistent/ca.pem".into()), danger_accept_invalid_certs: false };
+        let error = config.connector().err().expect("a missing bundle fails").to_string();
+        assert!(error.contains("cannot read CA bundle"), "{}", error);
+    }
+
+    #[test]
+    fn bundle_without_certificates_is_an_error() {
+        let path = std::env::temp_dir().join(format!("empty-ca-{}.pem", std::process::id()));
+        std::fs::write(&path, "not a certificate\n").unwrap();
+        let config = TlsConfig { ca_bundle: Some(path.clone()), danger_accept_invalid_certs: false };
+        let result = config.connector();
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+    #[test]
+    fn splits_every_certificate_of_a_bundle() {
+        let pem = std::fs::read(CA_BUNDLE).unwrap();
+        let certificates = pem_certificates(&pem);
+        assert_eq!(certificates.len(), 2);
+        for certificate in certificates {
+            assert!(native_tls::Certificate::from_pem(certificate).is_ok());
+        }
+    }
+
+    // a wss:// connection to a local server presenting a localhost certificate signed by
+    // tests/data/tls_ca.pem, which only verifies with that CA added as a bundle
+    #[cfg(feature = "rustls")]
+    #[tokio::test]
+    async fn websocket_handshake_trusts_the_custom_ca_bundle() {
+        use crate::feed::{connect_websocket, WebSocketOptions, DEFAULT_MAX_MESSAGE_SIZE};
+        use futures::{SinkExt, StreamExt};
+        use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+
+        let data = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+        let certificates = rustls_pemfile::certs(&mut std::fs::read(format!("{}/localhost.pem", data)).unwrap().as_slice()).unwrap();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut std::fs::read(format!("{}/localhost.key", data)).unwrap().as_slice()).unwrap().remove(0);
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certificates.into_iter().map(Certificate).collect(), PrivateKey(key))
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(server_config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let Ok(mut websocket) = tokio_tungstenite::accept_async(stream).await else {
+                        return;
+                    };
+                    // echoes the first message back
+                    if let Some(Ok(message)) = websocket.next().await {
+                        let _ = websocket.send(message).await;
+                    }
+                });
+            }
+        });
+
+        let url = format!("wss://localhost:{}/ws", port);
+        let options = |tls: TlsConfig| WebSocketOptions { tls: tls.connector().unwrap(), max_message_size: DEFAULT_MAX_MESSAGE_SIZE };
+        let trusted = options(TlsConfig { ca_bundle: Some(format!("{}/tls_ca.pem", data).into()), danger_accept_invalid_certs: false });
+        let (mut sink, mut stream) = connect_websocket(&url, "local", &trusted).await.unwrap();
+        sink.send(tungstenite::Message::Text("ping".to_string())).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), tungstenite::Message::Text("ping".to_string()));
+
+        // the bundled webpki roots alone don't know the test CA
+        assert!(connect_websocket(&url, "local", &options(TlsConfig::default())).await.is_err());
+    }
+}
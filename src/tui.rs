@@ -0,0 +1,88 @@
+// a live terminal view of the merged books for local monitoring, redrawn in place with ANSI escapes
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::feed::SummarySender;
+use crate::orderbook::{Level, Summary};
+
+// how often the screen is redrawn at most, changes in between are drawn together
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+
+// the color an exchange's name is drawn in, so its levels stand out across both sides
+fn exchange_color(exchange: &str) -> &'static str {
+    match exchange {
+        "binance" => "\x1b[33m",
+        "bitstamp" => "\x1b[36m",
+        "coinbase" => "\x1b[34m",
+        "kraken" => "\x1b[35m",
+        _ => RESET,
+    }
+}
+
+// draws the books of every symbol to stdout until shutdown, reading the same summaries as the gRPC
+// streams. logs keep going to stderr, redirect it so they don't scroll the view
+pub async fn run_tui(summaries: &HashMap<String, SummarySender>, shutdown: CancellationToken) -> std::io::Result<()> {
+    let mut receivers: Vec<(String, watch::Receiver<Arc<Summary>>)> = summaries.iter().map(|(symbol, sender)| (symbol.clone(), sender.subscribe())).collect();
+    receivers.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut stdout = std::io::stdout();
+    // hide the cursor while drawing
+    write!(stdout, "\x1b[?25l")?;
+    let mut redraw = tokio::time::interval(REDRAW_INTERVAL);
+    let mut drawn = false;
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = redraw.tick() => (),
+        }
+        if drawn && !receivers.iter().any(|(_, receiver)| receiver.has_changed().unwrap_or(false)) {
+            continue;
+        }
+        let mut frame = String::from("\x1b[H\x1b[2J");
+        for (symbol, receiver) in &mut receivers {
+            let summary = Arc::clone(&receiver.borrow_and_update());
+            render_book(&mut frame, symbol, &summary);
+        }
+        stdout.write_all(frame.as_bytes())?;
+        stdout.flush()?;
+        drawn = true;
+    }
+    write!(stdout, "\x1b[?25h")?;
+    stdout.flush()
+}
+
+// one book: the asks from the worst down to the best in red, the spread, then the bids from the best in green
+fn render_book(frame: &mut String, symbol: &str, summary: &Summary) {
+    let _ = writeln!(frame, "{BOLD}{symbol}{RESET}");
+    for level in summary.asks.iter().rev() {
+        render_level(frame, level, RED);
+    }
+    if summary.has_spread {
+        let crossed = if summary.crossed { " crossed" } else { "" };
+        let _ = writeln!(frame, "{BOLD}  spread {} ({:.2} bps) mid {}{crossed}{RESET}", summary.spread, summary.spread_bps, summary.mid_price);
+    } else {
+        let _ = writeln!(frame, "{BOLD}  no spread, a side of the book is empty{RESET}");
+    }
+    for level in &summary.bids {
+        render_level(frame, level, GREEN);
+    }
+    if !summary.stale_exchanges.is_empty() {
+        let _ = writeln!(frame, "  stale: {}", summary.stale_exchanges.join(", "));
+    }
+    let _ = writeln!(frame);
+}
+
+fn render_level(frame: &mut String, level: &Level, side_color: &str) {
+    let _ = writeln!(frame, "  {}{:<10}{RESET} {side_color}{:>18} @ {:<18}{RESET}", exchange_color(&level.exchange), level.exchange, level.amount, level.price);
+}
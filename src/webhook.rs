@@ -0,0 +1,189 @@
+// posts arbitrage opportunities to a chat webhook for alerting
+
+use std::time::{Duration, Instant};
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+// gRPC crates
+use crate::orderbook::Opportunity;
+
+// how long one post may take before it is given up
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+// alerts waiting for the poster, more are dropped while the endpoint is slow
+const WEBHOOK_QUEUE_CAPACITY: usize = 16;
+
+// the body of one alert. Slack shows `text` and Discord `content`, other consumers get the numbers
+pub fn webhook_payload(opportunity: &Opportunity) -> Value {
+    let text = format!(
+        "{}: buy on {} @ {}, sell on {} @ {}, {} per unit after fees, {} executable for {}",
+        opportunity.symbol,
+        opportunity.buy_exchange,
+        opportunity.buy_price,
+        opportunity.sell_exchange,
+        opportunity.sell_price,
+        opportunity.profit_per_unit,
+        opportunity.executable_amount,
+        opportunity.executable_profit,
+    );
+    json!({
+        "text": text,
+        "content": text,
+        "opportunity": {
+            "symbol": opportunity.symbol,
+            "buy_exchange": opportunity.buy_exchange,
+            "buy_price": opportunity.buy_price,
+            "sell_exchange": opportunity.sell_exchange,
+            "sell_price": opportunity.sell_price,
+            "profit_per_unit": opportunity.profit_per_unit,
+            "gross_profit_per_unit": opportunity.gross_profit_per_unit,
+            "amount": opportunity.amount,
+            "executable_amount": opportunity.executable_amount,
+            "executable_profit": opportunity.executable_profit,
+            "timestamp_ms": opportunity.timestamp_ms,
+        },
+    })
+}
+
+// posts the opportunities as they are detected, at most one per interval with the ones in between
+// dropped. reads its own subscription of the opportunity channel and hands the payloads to a poster
+// task through a bounded queue, so a slow or failing endpoint never holds up the feeds
+pub async fn run_webhook(url: String, mut opportunities: broadcast::Receiver<Opportunity>, interval: Duration, shutdown: CancellationToken) -> anyhow::Result<()> {
+    let client = reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build()?;
+    let (queue_tx, queue_rx) = mpsc::channel(WEBHOOK_QUEUE_CAPACITY);
+    tokio::spawn(post_payloads(client, url, queue_rx));
+
+    let mut last_sent: Option<Instant> = None;
+    loop {
+        let opportunity = tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            received = opportunities.recv() => match received {
+                Ok(opportunity) => opportunity,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    debug!("Webhook skipped {} opportunities", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        };
+        if last_sent.is_some_and(|sent_at| sent_at.elapsed() < interval) {
+            continue;
+        }
+        last_sent = Some(Instant::now());
+        if queue_tx.try_send(webhook_payload(&opportunity)).is_err() {
+            warn!("Webhook queue is full, dropping the {} opportunity", opportunity.symbol);
+        }
+    }
+}
+
+// posts the queued payloads one at a time until the queue is dropped
+async fn post_payloads(client: reqwest::Client, url: String, mut queue: mpsc::Receiver<Value>) {
+    while let Some(payload) = queue.recv().await {
+        let sent = client
+            .post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(payload.to_string())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match sent {
+            Ok(_) => debug!("Posted an opportunity to the webhook"),
+            Err(e) => warn!("Webhook post failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    // the content type and body of every post received
+    type Posts = Arc<Mutex<Vec<(String, Value)>>>;
+
+    // a webhook endpoint on a local port
+    fn mock_webhook() -> (std::net::SocketAddr, Posts) {
+        let posts: Posts = Arc::default();
+        let received = Arc::clone(&posts);
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let received = Arc::clone(&received);
+            async move {
+                Ok::<_, Infallible>(hyper::service::service_fn(move |request: hyper::Request<hyper::Body>| {
+                    let received = Arc::clone(&received);
+                    async move {
+                        assert_eq!(request.method(), hyper::Method::POST);
+                        let content_type = request.headers()[hyper::header::CONTENT_TYPE].to_str().unwrap().to_string();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        received.lock().unwrap().push((content_type, serde_json::from_slice(&body).unwrap()));
+                        Ok::<_, Infallible>(hyper::Response::new(hyper::Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, posts)
+    }
+
+    fn opportunity(buy_price: f64) -> Opportunity {
+        Opportunity {
+            symbol: "ETH-BTC".to_string(),
+            buy_exchange: "bitstamp".to_string(),
+            buy_price,
+            sell_exchange: "binance".to_string(),
+            sell_price: 0.0625,
+            profit_per_unit: 0.0003,
+            amount: 1.5,
+            timestamp_ms: 1_700_000_000_000,
+            gross_profit_per_unit: 0.0005,
+            executable_amount: 2.0,
+            executable_profit: 0.0005,
+            executable_profit_per_unit: 0.00025,
+        }
+    }
+
+    #[tokio::test]
+    async fn posts_the_opportunity_payload_at_most_once_per_interval() {
+        let (addr, posts) = mock_webhook();
+        let (sender, receiver) = broadcast::channel(16);
+        let shutdown = CancellationToken::new();
+        let webhook = tokio::spawn(run_webhook(format!("http://{}/hook", addr), receiver, Duration::from_secs(60), shutdown.clone()));
+
+        sender.send(opportunity(0.062)).unwrap();
+        // inside the interval, dropped
+        sender.send(opportunity(0.0621)).unwrap();
+        for _ in 0..200 {
+            if !posts.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown.cancel();
+        webhook.await.unwrap().unwrap();
+
+        let posts = posts.lock().unwrap();
+        assert_eq!(posts.len(), 1);
+        let (content_type, payload) = &posts[0];
+        assert_eq!(content_type, "application/json");
+        let text = "ETH-BTC: buy on bitstamp @ 0.062, sell on binance @ 0.0625, 0.0003 per unit after fees, 2 executable for 0.0005";
+        assert_eq!(payload["text"], text);
+        assert_eq!(payload["content"], text);
+        assert_eq!(payload["opportunity"], serde_json::json!({
+            "symbol": "ETH-BTC",
+            "buy_exchange": "bitstamp",
+            "buy_price": 0.062,
+            "sell_exchange": "binance",
+            "sell_price": 0.0625,
+            "profit_per_unit": 0.0003,
+            "gross_profit_per_unit": 0.0005,
+            "amount": 1.5,
+            "executable_amount": 2.0,
+            "executable_profit": 0.0005,
+            "timestamp_ms": 1_700_000_000_000i64,
+        }));
+    }
+}
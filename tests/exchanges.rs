@@ -0,0 +1,436 @@
+// end to end runs of the Binance and Bitstamp feeds against local servers standing in for the
+// exchanges, pointed at through the BINANCE_* and BITSTAMP_* endpoint variables
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use rust_decimal::Decimal;
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, watch, Mutex};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+use tonic::transport::Server;
+
+use rust_challenge::connectors::{fetch_rest_snapshot, BinanceConnector, BitstampConnector, ExchangeConnector};
+use rust_challenge::feed::{self, Feed, FeedStatus, Opportunities, ReconnectConfig, WebSocketOptions, DEFAULT_MAX_MESSAGE_SIZE, OPPORTUNITY_CHANNEL_CAPACITY, TRADE_CHANNEL_CAPACITY};
+use rust_challenge::orderbook::orderbook_aggregator_client::OrderbookAggregatorClient;
+use rust_challenge::orderbook::orderbook_aggregator_server::OrderbookAggregatorServer;
+use rust_challenge::orderbook::BookSummaryRequest;
+use rust_challenge::server::MyOrderbookAggregator;
+use rust_challenge::tls::TlsConfig;
+use rust_challenge::{Fees, OrderBook, PriceLevel};
+
+// held by the tests pointing this process' endpoint variables at their mock exchanges, one at a time
+static ENVIRONMENT: Mutex<()> = Mutex::const_new(());
+
+// what a mock exchange was sent, shared with the test
+type Received = Arc<StdMutex<Vec<Value>>>;
+
+// a websocket server answering the first message of every connection, the subscription, with
+// replies(subscription) and then staying open without sending anything else
+async fn mock_websocket(replies: fn(&Value) -> Vec<Value>) -> (SocketAddr, Received) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let received: Received = Arc::default();
+    let subscriptions = Arc::clone(&received);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let subscriptions = Arc::clone(&subscriptions);
+            tokio::spawn(async move {
+                let Ok(mut websocket) = tokio_tungstenite::accept_async(stream).await else {
+                    return;
+                };
+                let Some(Ok(Message::Text(text))) = websocket.next().await else {
+                    return;
+                };
+                let subscription: Value = serde_json::from_str(&text).unwrap();
+                for reply in replies(&subscription) {
+                    if websocket.send(Message::Text(reply.to_string())).await.is_err() {
+                        return;
+                    }
+                }
+                subscriptions.lock().unwrap().push(subscription);
+                while let Some(Ok(_)) = websocket.next().await {}
+            });
+        }
+    });
+    (addr, received)
+}
+
+// an HTTP server answering every request with body, recording the requested paths
+async fn mock_rest(body: Value) -> (SocketAddr, Arc<StdMutex<Vec<String>>>) {
+    mock_rest_with_status(hyper::StatusCode::OK, body).await
+}
+
+// the same answering with status instead of 200
+async fn mock_rest_with_status(status: hyper::StatusCode, body: Value) -> (SocketAddr, Arc<StdMutex<Vec<String>>>) {
+    let requests: Arc<StdMutex<Vec<String>>> = Arc::default();
+    let requested = Arc::clone(&requests);
+    let make_service = hyper::service::make_service_fn(move |_| {
+        let (body, requested) = (body.to_string(), Arc::clone(&requested));
+        async move {
+            Ok::<_, Infallible>(hyper::service::service_fn(move |request: hyper::Request<hyper::Body>| {
+                requested.lock().unwrap().push(request.uri().to_string());
+                let body = body.clone();
+                async move {
+                    let mut response = hyper::Response::new(hyper::Body::from(body));
+                    *response.status_mut() = status;
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+    let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    (addr, requests)
+}
+
+// [price, amount] pairs as the exchanges send them
+fn pairs(levels: &[(&str, &str)]) -> Value {
+    levels.iter().map(|(price, amount)| json!([price, amount])).collect()
+}
+
+// replies to a Binance SUBSCRIBE for ethbtc: the ack, then diffs starting just before the REST
+// snapshot's lastUpdateId of 100. the first one arrives before the snapshot and is buffered
+fn binance_session(subscription: &Value) -> Vec<Value> {
+    assert_eq!(subscription["method"], "SUBSCRIBE");
+    assert!(subscription["params"].as_array().unwrap().contains(&json!("ethbtc@depth")), "{}", subscription);
+    let diff = |first: u64, last: u64, bids: &[(&str, &str)], asks: &[(&str, &str)]| {
+        json!({"e": "depthUpdate", "E": 1_700_000_000_000u64, "s": "ETHBTC", "U": first, "u": last, "b": pairs(bids), "a": pairs(asks)})
+    };
+    vec![
+        json!({"result": null, "id": subscription["id"]}),
+        diff(99, 101, &[("0.06100000", "2")], &[]),
+        diff(102, 102, &[("0.06090000", "0")], &[("0.06190000", "0.5")]),
+    ]
+}
+
+// 12 levels a side, 0.0610 down to 0.0599 and 0.0620 up to 0.0631, one of each
+fn binance_snapshot() -> Value {
+    let prices = |from: i64, step: i64| (0..12).map(|i| Decimal::new(from + step * i, 4).to_string()).collect::<Vec<_>>();
+    let levels = |prices: Vec<String>| prices.into_iter().map(|price| json!([price, "1"])).collect::<Value>();
+    json!({"lastUpdateId": 100, "bids": levels(prices(610, -1)), "asks": levels(prices(620, 1))})
+}
+
+// replies to a Bitstamp subscription to order_book_ethbtc: the confirmation and two full books
+fn bitstamp_session(subscription: &Value) -> Vec<Value> {
+    assert_eq!(subscription, &json!({"event": "bts:subscribe", "data": {"channel": "order_book_ethbtc"}}));
+    let book = |bids: &[(&str, &str)], asks: &[(&str, &str)]| {
+        json!({"event": "data", "channel": "order_book_ethbtc", "data": {"timestamp": "1700000000", "microtimestamp": "1700000000000000", "bids": pairs(bids), "asks": pairs(asks)}})
+    };
+    vec![
+        json!({"event": "bts:subscription_succeeded", "channel": "order_book_ethbtc", "data": {}}),
+        book(&[("0.06105", "3"), ("0.06095", "3"), ("0.06085", "3")], &[("0.06195", "3"), ("0.06205", "3")]),
+        book(&[("0.06105", "4"), ("0.06075", "3")], &[("0.06185", "1"), ("0.06205", "3")]),
+    ]
+}
+
+fn levels(levels: &[(&str, &str, &str)]) -> Vec<PriceLevel> {
+    levels
+        .iter()
+        .map(|(exchange, price, amount)| PriceLevel { exchange: exchange.to_string(), price: price.parse().unwrap(), amount: amount.parse().unwrap() })
+        .collect()
+}
+
+fn feed(symbol: &str, exchange_symbol: &str, connector: Box<dyn ExchangeConnector>, order_book: &Arc<Mutex<OrderBook>>, summaries: &feed::SummarySender) -> Feed {
+    let health = Arc::new(FeedStatus::new(connector.name(), symbol));
+    Feed {
+        symbol: symbol.to_string(),
+        exchange_symbol: exchange_symbol.to_string(),
+        connector,
+        order_book: Arc::clone(order_book),
+        summaries: Arc::clone(summaries),
+        replay: None,
+        recorder: None,
+        health,
+        stale_after: Duration::from_secs(10),
+    }
+}
+
+#[tokio::test]
+async fn merged_book_converges_to_the_top_10() {
+    let (binance_ws, binance_subscriptions) = mock_websocket(binance_session).await;
+    let (binance_rest, binance_requests) = mock_rest(binance_snapshot()).await;
+    let (bitstamp_ws, bitstamp_subscriptions) = mock_websocket(bitstamp_session).await;
+    let _environment = ENVIRONMENT.lock().await;
+    std::env::set_var("BINANCE_WS_URL", format!("ws://{}/ws", binance_ws));
+    std::env::set_var("BINANCE_REST_URL", format!("http://{}", binance_rest));
+    std::env::set_var("BITSTAMP_WS_URL", format!("ws://{}", bitstamp_ws));
+
+    let order_book = OrderBook::new(10).unwrap();
+    let (summaries, mut received) = watch::channel(Arc::new(order_book.to_summary()));
+    let (order_book, summaries) = (Arc::new(Mutex::new(order_book)), Arc::new(summaries));
+    let feeds = vec![
+        feed("ETH-BTC", "ethbtc", Box::new(BinanceConnector::new(10)), &order_book, &summaries),
+        feed("ETH-BTC", "ethbtc", Box::new(BitstampConnector::new(10, false)), &order_book, &summaries),
+    ];
+    let (trades, _) = broadcast::channel(TRADE_CHANNEL_CAPACITY);
+    let (sender, _) = broadcast::channel(OPPORTUNITY_CHANNEL_CAPACITY);
+    let opportunities = Opportunities { sender, min_profit: Decimal::ZERO, fees: Fees::default() };
+    let websocket = WebSocketOptions { tls: TlsConfig::default().connector().unwrap(), max_message_size: DEFAULT_MAX_MESSAGE_SIZE };
+    let shutdown = CancellationToken::new();
+    let run = tokio::spawn(feed::run(feeds, trades, opportunities, ReconnectConfig::default(), websocket, None, shutdown.clone()));
+
+    let expected_bids = levels(&[
+        ("bitstamp", "0.06105", "4"),
+        ("binance", "0.0610", "2"),
+        ("binance", "0.0608", "1"),
+        ("bitstamp", "0.06075", "3"),
+        ("binance", "0.0607", "1"),
+        ("binance", "0.0606", "1"),
+        ("binance", "0.0605", "1"),
+        ("binance", "0.0604", "1"),
+        ("binance", "0.0603", "1"),
+        ("binance", "0.0602", "1"),
+    ]);
+    let expected_asks = levels(&[
+        ("bitstamp", "0.06185", "1"),
+        ("binance", "0.0619", "0.5"),
+        ("binance", "0.0620", "1"),
+        ("bitstamp", "0.06205", "3"),
+        ("binance", "0.0621", "1"),
+        ("binance", "0.0622", "1"),
+        ("binance", "0.0623", "1"),
+        ("binance", "0.0624", "1"),
+        ("binance", "0.0625", "1"),
+        ("binance", "0.0626", "1"),
+    ]);
+    let converged = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            received.changed().await.unwrap();
+            let book = order_book.lock().await;
+            let bids: Vec<_> = book.bids().iter().cloned().collect();
+            let asks: Vec<_> = book.asks().iter().cloned().collect();
+            if bids == expected_bids && asks == expected_asks {
+                break;
+            }
+        }
+    })
+    .await;
+    let (bids, asks) = {
+        let book = order_book.lock().await;
+        (book.bids().iter().cloned().collect::<Vec<_>>(), book.asks().iter().cloned().collect::<Vec<_>>())
+    };
+    assert!(converged.is_ok(), "bids {:?}\nasks {:?}", bids, asks);
+
+    // each exchange was subscribed once and Binance anchored on one REST snapshot
+    assert_eq!(binance_subscriptions.lock().unwrap().len(), 1);
+    assert_eq!(bitstamp_subscriptions.lock().unwrap().len(), 1);
+    assert_eq!(*binance_requests.lock().unwrap(), ["/api/v3/depth?symbol=ETHBTC&limit=1000"]);
+    let summary = Arc::clone(&received.borrow());
+    assert_eq!(summary.bids.len(), 10);
+    assert_eq!(summary.asks.len(), 10);
+    assert_eq!(summary.bids[0].exchange, "bitstamp");
+    assert_eq!(summary.asks[1].exchange, "binance");
+
+    shutdown.cancel();
+    run.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn snapshot_mode_prints_the_merged_book_and_exits() {
+    let (binance_ws, _) = mock_websocket(binance_session).await;
+    let (binance_rest, _) = mock_rest(binance_snapshot()).await;
+    let (bitstamp_ws, _) = mock_websocket(bitstamp_session).await;
+
+    let server = tokio::process::Command::new(env!("CARGO_BIN_EXE_orderbook-server"))
+        .args(["--snapshot", "--symbol", "ethbtc", "--exchanges", "binance,bitstamp", "--depth", "10"])
+        .env("BINANCE_WS_URL", format!("ws://{}/ws", binance_ws))
+        .env("BINANCE_REST_URL", format!("http://{}", binance_rest))
+        .env("BITSTAMP_WS_URL", format!("ws://{}", bitstamp_ws))
+        .env("RUST_LOG", "warn")
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(Duration::from_secs(30), server).await.expect("snapshot mode exits").unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // stdout holds nothing but the books
+    let books: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let book = &books["ETH-BTC"];
+    assert_eq!(book["max_depth"], 10);
+    for side in ["bids", "asks"] {
+        let levels = book[side].as_array().unwrap();
+        assert_eq!(levels.len(), 10, "{}", book);
+        for exchange in ["binance", "bitstamp"] {
+            assert!(levels.iter().any(|level| level["exchange"] == exchange), "no {} {} in {}", exchange, side, book);
+        }
+    }
+}
+
+#[tokio::test]
+async fn cancelling_the_shutdown_token_stops_the_server_and_the_feeds() {
+    let (binance_ws, _) = mock_websocket(binance_session).await;
+    let (binance_rest, _) = mock_rest(binance_snapshot()).await;
+    let (bitstamp_ws, _) = mock_websocket(bitstamp_session).await;
+    let _environment = ENVIRONMENT.lock().await;
+    std::env::set_var("BINANCE_WS_URL", format!("ws://{}/ws", binance_ws));
+    std::env::set_var("BINANCE_REST_URL", format!("http://{}", binance_rest));
+    std::env::set_var("BITSTAMP_WS_URL", format!("ws://{}", bitstamp_ws));
+
+    let order_book = OrderBook::new(10).unwrap();
+    let (summaries, mut received) = watch::channel(Arc::new(order_book.to_summary()));
+    let (order_book, summaries) = (Arc::new(Mutex::new(order_book)), Arc::new(summaries));
+    let feeds = vec![
+        feed("ETH-BTC", "ethbtc", Box::new(BinanceConnector::new(10)), &order_book, &summaries),
+        feed("ETH-BTC", "ethbtc", Box::new(BitstampConnector::new(10, false)), &order_book, &summaries),
+    ];
+    let health: Vec<_> = feeds.iter().map(|feed| Arc::clone(&feed.health)).collect();
+    let (trades, _) = broadcast::channel(TRADE_CHANNEL_CAPACITY);
+    let (sender, _) = broadcast::channel(OPPORTUNITY_CHANNEL_CAPACITY);
+    let opportunities = Opportunities { sender: sender.clone(), min_profit: Decimal::ZERO, fees: Fees::default() };
+    let websocket = WebSocketOptions { tls: TlsConfig::default().connector().unwrap(), max_message_size: DEFAULT_MAX_MESSAGE_SIZE };
+
+    // wired the way main does it, one token shared by the feeds and the gRPC server
+    let shutdown = CancellationToken::new();
+    let feeds = tokio::spawn(feed::run(feeds, trades.clone(), opportunities, ReconnectConfig::default(), websocket, None, shutdown.clone()));
+    let books = HashMap::from([("ETH-BTC".to_string(), Arc::clone(&order_book))]);
+    let aggregator = MyOrderbookAggregator::new(books, HashMap::from([("ETH-BTC".to_string(), summaries)]), trades, sender, health, None, shutdown.clone());
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let server = tokio::spawn(Server::builder().add_service(OrderbookAggregatorServer::new(aggregator)).serve_with_shutdown(addr, shutdown.clone().cancelled_owned()));
+
+    // running for real, the feeds delivered a book
+    tokio::time::timeout(Duration::from_secs(10), received.changed()).await.expect("a summary is published").unwrap();
+
+    shutdown.cancel();
+    tokio::time::timeout(Duration::from_secs(5), server).await.expect("the server stops promptly").unwrap().unwrap();
+    tokio::time::timeout(Duration::from_secs(5), feeds).await.expect("the feeds stop promptly").unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn client_receives_a_summary_from_the_running_server() {
+    let (binance_ws, _) = mock_websocket(binance_session).await;
+    let (binance_rest, _) = mock_rest(binance_snapshot()).await;
+    let (bitstamp_ws, _) = mock_websocket(bitstamp_session).await;
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    let _server = tokio::process::Command::new(env!("CARGO_BIN_EXE_orderbook-server"))
+        .args(["--symbol", "ethbtc", "--exchanges", "binance,bitstamp", "--depth", "10"])
+        .env("GRPC_ADDR", addr.to_string())
+        .env("BINANCE_WS_URL", format!("ws://{}/ws", binance_ws))
+        .env("BINANCE_REST_URL", format!("http://{}", binance_rest))
+        .env("BITSTAMP_WS_URL", format!("ws://{}", bitstamp_ws))
+        .env("RUST_LOG", "warn")
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+
+    // the client connects once, so retry it until the server is listening
+    let client = async {
+        loop {
+            let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_orderbook-client"))
+                .args(["--symbol", "ETH-BTC", "--count", "1"])
+                .env("GRPC_ADDR", addr.to_string())
+                .output()
+                .await
+                .unwrap();
+            if output.status.success() {
+                return output;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    let output = tokio::time::timeout(Duration::from_secs(30), client).await.expect("the client receives a summary");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("spread: "), "{}", stdout);
+    assert!(stdout.contains("generated at: "), "{}", stdout);
+}
+
+#[tokio::test]
+async fn client_rejects_a_bad_server_address() {
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_orderbook-client"))
+        .args(["--count", "1"])
+        .env("GRPC_ADDR", "not an address")
+        .output()
+        .await
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("InvalidUri"), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[tokio::test]
+async fn summaries_stream_with_a_single_exchange_enabled() {
+    let (bitstamp_ws, _) = mock_websocket(bitstamp_session).await;
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    // no Binance endpoint set, it must not be started
+    let _server = tokio::process::Command::new(env!("CARGO_BIN_EXE_orderbook-server"))
+        .args(["--symbol", "ethbtc", "--exchanges", "bitstamp", "--depth", "10"])
+        .env("GRPC_ADDR", addr.to_string())
+        .env("BITSTAMP_WS_URL", format!("ws://{}", bitstamp_ws))
+        .env("RUST_LOG", "warn")
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+
+    let summary = tokio::time::timeout(Duration::from_secs(30), async {
+        let mut client = loop {
+            match OrderbookAggregatorClient::connect(format!("http://{}", addr)).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        };
+        let request = BookSummaryRequest { symbol: "ETH-BTC".to_string(), ..BookSummaryRequest::default() };
+        let mut stream = client.book_summary(request).await.unwrap().into_inner();
+        // the first summary may come before the feed delivered a book
+        loop {
+            let summary = stream.message().await.unwrap().expect("the stream stays open");
+            if summary.bids.len() == 2 && summary.asks.len() == 2 {
+                break summary;
+            }
+        }
+    })
+    .await
+    .expect("a summary of the Bitstamp book streams");
+
+    assert!(summary.bids.iter().chain(&summary.asks).all(|level| level.exchange == "bitstamp"), "{:?}", summary);
+    assert_eq!(summary.bids[0].price, 0.06105);
+    assert_eq!(summary.asks[0].price, 0.06185);
+    assert!(summary.has_spread);
+}
+
+#[tokio::test]
+async fn rest_snapshots_are_fetched_and_cut_to_depth() {
+    let (binance_rest, binance_requests) = mock_rest(binance_snapshot()).await;
+    let bitstamp_book = json!({
+        "timestamp": "1700000000",
+        "microtimestamp": "1700000000000000",
+        "bids": pairs(&[("0.06105", "4"), ("0.06095", "3"), ("0.06085", "2")]),
+        "asks": pairs(&[("0.06185", "1"), ("0.06205", "3"), ("0.06215", "5")]),
+    });
+    let (bitstamp_rest, bitstamp_requests) = mock_rest(bitstamp_book).await;
+    let _environment = ENVIRONMENT.lock().await;
+    std::env::set_var("BINANCE_REST_URL", format!("http://{}", binance_rest));
+    std::env::set_var("BITSTAMP_REST_URL", format!("http://{}", bitstamp_rest));
+
+    let (bids, asks) = fetch_rest_snapshot("binance", "ethbtc", 3).await.unwrap().into_levels();
+    assert_eq!(bids, levels(&[("binance", "0.0610", "1"), ("binance", "0.0609", "1"), ("binance", "0.0608", "1")]));
+    assert_eq!(asks, levels(&[("binance", "0.0620", "1"), ("binance", "0.0621", "1"), ("binance", "0.0622", "1")]));
+    // the full book is asked for and cut here
+    assert_eq!(*binance_requests.lock().unwrap(), ["/api/v3/depth?symbol=ETHBTC&limit=1000"]);
+
+    let (bids, asks) = fetch_rest_snapshot("bitstamp", "ethbtc", 2).await.unwrap().into_levels();
+    assert_eq!(bids, levels(&[("bitstamp", "0.06105", "4"), ("bitstamp", "0.06095", "3")]));
+    assert_eq!(asks, levels(&[("bitstamp", "0.06185", "1"), ("bitstamp", "0.06205", "3")]));
+    assert_eq!(*bitstamp_requests.lock().unwrap(), ["/api/v2/order_book/ethbtc/"]);
+
+    assert!(fetch_rest_snapshot("kraken", "XBT/USD", 10).await.is_err());
+}
+
+#[tokio::test]
+async fn rest_snapshot_error_status_is_an_error() {
+    let (binance_rest, _) = mock_rest_with_status(hyper::StatusCode::TOO_MANY_REQUESTS, json!({"code": -1003, "msg": "Too many requests"})).await;
+    let (bitstamp_rest, _) = mock_rest_with_status(hyper::StatusCode::NOT_FOUND, json!({})).await;
+    let _environment = ENVIRONMENT.lock().await;
+    std::env::set_var("BINANCE_REST_URL", format!("http://{}", binance_rest));
+    std::env::set_var("BITSTAMP_REST_URL", format!("http://{}", bitstamp_rest));
+
+    let error = fetch_rest_snapshot("binance", "ethbtc", 10).await.unwrap_err().to_string();
+    assert!(error.contains("429"), "{}", error);
+    let error = fetch_rest_snapshot("bitstamp", "ethbtc", 10).await.unwrap_err().to_string();
+    assert!(error.contains("404"), "{}", error);
+}